@@ -1,6 +1,5 @@
 // Auto-generated by cbindgen. Do not edit.
 
-
 #ifndef SYNC_ENGINE_FFI_H
 #define SYNC_ENGINE_FFI_H
 
@@ -13,8 +12,82 @@
 #include <stdint.h>
 #include <stdbool.h>
 
+#define SE_ABI_VERSION 2
+
+#define SE_OK 0
+
+#define SE_ERR_GENERIC 1
+
+#define SE_ERR_SERDE 2
+
+#define SE_ERR_CALLBACK 3
+
+#define SE_ERR_INVALID_ARG 4
+
+#define SE_ERR_TX_CONTROL 5
+
+#define SE_ERR_BUSY 6
+
+#define SE_ERR_CONSTRAINT 7
+
+#define SE_ERR_CONFLICT 8
+
+#define SE_ERR_NESTED_APPLY 9
+
+#define SE_OP_INSERT 0
+
+#define SE_OP_UPDATE 1
+
+#define SE_OP_DELETE 2
+
+#define SE_APPLY_OK 0
+
+#define SE_APPLY_SKIP 2
+
+#define SE_WINNER_LOCAL 0
+
+#define SE_WINNER_REMOTE 1
+
+#define SE_CONFLICT_LOCAL_CHANGE 0
+
+#define SE_CONFLICT_LOCAL_DELETE 1
+
+#define SE_OUTCOME_APPLIED 0
+
+#define SE_OUTCOME_DUPLICATE 1
+
+#define SE_OUTCOME_CONFLICT_LOCAL 2
+
+#define SE_OUTCOME_CONFLICT_REMOTE 3
+
+#define SE_OUTCOME_TOMBSTONED 4
+
+#define SE_OUTCOME_UNKNOWN_TABLE 5
+
+#define SE_OUTCOME_QUARANTINED 6
+
+#define SE_CHECKPOINT_PASSIVE 0
+
+#define SE_CHECKPOINT_FULL 1
+
+#define SE_CHECKPOINT_RESTART 2
+
+#define SE_CHECKPOINT_TRUNCATE 3
+
 typedef struct SyncConnHandle SyncConnHandle;
 
+typedef struct SE_OpenOptions {
+  uintptr_t max_op_bytes;
+  uint32_t busy_timeout_ms;
+  uint32_t commit_retries;
+  uint32_t retry_backoff_ms;
+} SE_OpenOptions;
+
+typedef struct SE_Error {
+  int code;
+  char *message;
+} SE_Error;
+
 typedef struct SE_Op {
   const char *remote_id;
   const char *table_name;
@@ -29,10 +102,41 @@ typedef struct SE_Op {
 
 typedef int (*SE_ApplyCallback)(void *user_data, const struct SE_Op *op);
 
+typedef int (*SE_PushCallback)(void *user_data,
+                               const char *ops_json,
+                               int64_t *out_acked,
+                               uintptr_t out_acked_cap,
+                               uintptr_t *out_acked_len);
+
+typedef int (*SE_PullCallback)(void *user_data, const char *cursor, const char **out_page_json);
+
+typedef void (*SE_CursorCallback)(void *user_data, const char *cursor);
+
+typedef void (*SE_ConflictCallback)(void *user_data, const struct SE_Op *op, int reason);
+
+typedef struct SE_CheckpointResult {
+  int busy;
+  int64_t log_frames;
+  int64_t checkpointed_frames;
+} SE_CheckpointResult;
+
+typedef struct SE_Conflict {
+  const char *remote_id;
+  const char *table_name;
+  const char *row_id;
+  const char *local_hlc;
+  const char *remote_hlc;
+  int winner;
+} SE_Conflict;
+
 void sync_string_free(char *s);
 
+void sync_bytes_free(uint8_t *ptr, uintptr_t len);
+
 struct SyncConnHandle *sync_open(const char *path);
 
+struct SyncConnHandle *sync_open_with_opts(const char *path, const struct SE_OpenOptions *opts);
+
 void sync_close(struct SyncConnHandle *handle);
 
 int sync_init_schema(struct SyncConnHandle *handle);
@@ -60,6 +164,15 @@ int64_t sync_log_delete(struct SyncConnHandle *handle,
 
 char *sync_get_pending_ops_json(struct SyncConnHandle *handle, int64_t limit);
 
+char *sync_get_pending_ops_for_table_json(struct SyncConnHandle *handle,
+                                          const char *table_name,
+                                          int64_t limit);
+
+int sync_get_pending_ops_compressed(struct SyncConnHandle *handle,
+                                    int64_t limit,
+                                    uint8_t **out_ptr,
+                                    uintptr_t *out_len);
+
 int sync_mark_ops_acked(struct SyncConnHandle *handle, const int64_t *ids, uintptr_t len);
 
 char *sync_get_remote_cursor(struct SyncConnHandle *handle);
@@ -70,6 +183,10 @@ int sync_last_error_code(void);
 
 char *sync_last_error_message(void);
 
+int sync_last_error(struct SE_Error *out);
+
+uint32_t sync_abi_version(void);
+
 int sync_mark_ops_pushed(struct SyncConnHandle *handle, const int64_t *ids, uintptr_t len);
 
 int sync_get_schema_version(struct SyncConnHandle *handle, int32_t *out_version);
@@ -84,4 +201,75 @@ int sync_apply_remote_ops(struct SyncConnHandle *handle,
                           SE_ApplyCallback cb,
                           void *user_data);
 
+char *sync_apply_remote_ops_applied_json(struct SyncConnHandle *handle,
+                                         const struct SE_Op *ops,
+                                         uintptr_t len,
+                                         SE_ApplyCallback cb,
+                                         void *user_data);
+
+int sync_apply_remote_ops_json(struct SyncConnHandle *handle,
+                               const char *ops_json,
+                               SE_ApplyCallback cb,
+                               void *user_data);
+
+char *sync_applied_ops_since_json(struct SyncConnHandle *handle, int64_t since_ms, int64_t limit);
+
+int sync_push_only(struct SyncConnHandle *handle,
+                   int64_t limit,
+                   SE_PushCallback push_cb,
+                   void *user_data,
+                   uintptr_t *out_count);
+
+int sync_pull_only(struct SyncConnHandle *handle,
+                   uintptr_t max_pages,
+                   SE_PullCallback pull_cb,
+                   SE_ApplyCallback apply_cb,
+                   void *user_data,
+                   uintptr_t *out_count);
+
+int sync_set_own_origin(struct SyncConnHandle *handle, const char *origin);
+
+char *sync_get_own_origin(struct SyncConnHandle *handle);
+
+int sync_init_schema_with_journal_mode(struct SyncConnHandle *handle, const char *mode);
+
+int sync_register_migration(struct SyncConnHandle *handle, int32_t version, const char *sql);
+
+char *sync_hlc_frontier_json(struct SyncConnHandle *handle);
+
+int sync_confirm_pushed_by_hlc_json(struct SyncConnHandle *handle,
+                                    const char *hlcs_json,
+                                    uintptr_t *out_count);
+
+int sync_set_cursor_callback(struct SyncConnHandle *handle, SE_CursorCallback cb, void *user_data);
+
+int sync_set_conflict_callback(struct SyncConnHandle *handle,
+                               SE_ConflictCallback cb,
+                               void *user_data);
+
+int sync_set_max_apply_attempts(struct SyncConnHandle *handle, uint32_t attempts);
+
+int sync_is_initialized(struct SyncConnHandle *handle, bool *out_bool);
+
+int sync_handle_last_error(struct SyncConnHandle *handle, struct SE_Error *out);
+
+int sync_apply_remote_ops_outcomes(struct SyncConnHandle *handle,
+                                   const struct SE_Op *ops,
+                                   uintptr_t len,
+                                   SE_ApplyCallback cb,
+                                   void *user_data,
+                                   int *out_outcomes);
+
+char *sync_build_push_envelope_json(struct SyncConnHandle *handle, int64_t limit);
+
+char *sync_tracked_tables_json(struct SyncConnHandle *handle, const char *status);
+
+char *sync_storage_report_json(struct SyncConnHandle *handle);
+
+int sync_checkpoint(struct SyncConnHandle *handle, int mode, struct SE_CheckpointResult *out);
+
+char *sync_count_by_status_json(struct SyncConnHandle *handle);
+
+char *sync_get_change_json(struct SyncConnHandle *handle, int64_t change_id);
+
 #endif  /* SYNC_ENGINE_FFI_H */
@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Generate dist/sync_engine.h from the `ffi` module.
+# Pass --verify to fail (without writing) when the committed header is out of date.
+
+CRATE_NAME=sync-engine
+HDR=$(pwd)/dist/sync_engine.h
+
+if ! command -v cbindgen >/dev/null 2>&1; then
+  echo "cbindgen is required. Install: cargo install cbindgen" >&2
+  exit 1
+fi
+
+if [[ "${1:-}" == "--verify" ]]; then
+  if ! cbindgen --crate ${CRATE_NAME} --config cbindgen.toml --output "${HDR}" --verify; then
+    echo "${HDR} is stale; run scripts/gen_header.sh and commit the result" >&2
+    exit 1
+  fi
+  echo "Header up to date: ${HDR}"
+else
+  mkdir -p "$(dirname "${HDR}")"
+  cbindgen --crate ${CRATE_NAME} --config cbindgen.toml --output "${HDR}"
+  echo "Generated: ${HDR}"
+fi
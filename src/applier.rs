@@ -0,0 +1,118 @@
+use rusqlite::Transaction;
+
+use crate::oplog::{ApplyDomainOp, OpType, RemoteOp, SyncError, is_identifier, json_to_sql};
+
+/// Applier for append-only event tables: every op must be an INSERT, written with
+/// `INSERT OR IGNORE` so a redelivered event is harmless even without dedup.
+/// `new_row` fields map to columns; `pk_col` is filled from `row_id` when missing.
+#[derive(Debug, Clone)]
+pub struct AppendOnlyApplier {
+    pk_col: String,
+    ignore_non_insert: bool,
+}
+
+impl AppendOnlyApplier {
+    pub fn new(pk_col: &str) -> Self {
+        Self { pk_col: pk_col.to_string(), ignore_non_insert: false }
+    }
+
+    /// Skip UPDATE/DELETE ops instead of failing the batch on them.
+    pub fn ignore_non_insert(mut self, on: bool) -> Self {
+        self.ignore_non_insert = on;
+        self
+    }
+}
+
+impl ApplyDomainOp for AppendOnlyApplier {
+    fn apply(&self, tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+        if op.op_type != OpType::Insert {
+            if self.ignore_non_insert {
+                return Ok(());
+            }
+            return Err(SyncError::State("append-only table received an UPDATE or DELETE"));
+        }
+        if !is_identifier(&op.table_name) || !is_identifier(&self.pk_col) {
+            return Err(SyncError::State("table_name and pk_col must be valid identifiers"));
+        }
+        let mut row = match &op.new_row {
+            Some(serde_json::Value::Object(obj)) => obj.clone(),
+            _ => return Err(SyncError::State("insert requires an object new_row")),
+        };
+        row.entry(self.pk_col.clone())
+            .or_insert_with(|| serde_json::Value::String(op.row_id.clone()));
+        if !row.keys().all(|k| is_identifier(k)) {
+            return Err(SyncError::State("column is not a valid identifier"));
+        }
+
+        let cols: Vec<&str> = row.keys().map(String::as_str).collect();
+        let placeholders: Vec<String> = (1..=cols.len()).map(|i| format!("?{}", i)).collect();
+        let sql = format!(
+            "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+            op.table_name,
+            cols.join(","),
+            placeholders.join(",")
+        );
+        let values: Vec<rusqlite::types::Value> = row.values().map(json_to_sql).collect();
+        tx.execute(&sql, rusqlite::params_from_iter(values))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    fn op(row_id: &str, op_type: OpType, new_row: Option<serde_json::Value>) -> RemoteOp {
+        RemoteOp {
+            remote_id: format!("op-{}", row_id),
+            table_name: "events".to_string(),
+            row_id: row_id.to_string(),
+            op_type,
+            columns: None,
+            new_row,
+            old_row: None,
+            hlc: "100-0-server".to_string(),
+            origin: "server".to_string(),
+        }
+    }
+
+    fn events(conn: &Connection) -> Vec<(String, String)> {
+        let mut stmt = conn.prepare("SELECT id, kind FROM events ORDER BY id").unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn inserts_once_and_rejects_updates() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE events(id TEXT PRIMARY KEY, kind TEXT)").unwrap();
+        let applier = AppendOnlyApplier::new("id");
+        let tx = conn.transaction().unwrap();
+
+        applier.apply(&tx, &op("e1", OpType::Insert, Some(json!({"kind": "open"})))).unwrap();
+        applier.apply(&tx, &op("e1", OpType::Insert, Some(json!({"kind": "redelivered"})))).unwrap();
+        let err = applier.apply(&tx, &op("e1", OpType::Update, Some(json!({"kind": "edit"})))).unwrap_err();
+        tx.commit().unwrap();
+
+        assert!(matches!(err, SyncError::State("append-only table received an UPDATE or DELETE")));
+        assert_eq!(events(&conn), [("e1".to_string(), "open".to_string())]);
+    }
+
+    #[test]
+    fn non_inserts_can_be_ignored() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE events(id TEXT PRIMARY KEY, kind TEXT)").unwrap();
+        let applier = AppendOnlyApplier::new("id").ignore_non_insert(true);
+        let tx = conn.transaction().unwrap();
+
+        applier.apply(&tx, &op("e1", OpType::Insert, Some(json!({"kind": "open"})))).unwrap();
+        applier.apply(&tx, &op("e1", OpType::Delete, None)).unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(events(&conn), [("e1".to_string(), "open".to_string())]);
+    }
+}
@@ -1,31 +1,185 @@
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+#![allow(non_camel_case_types)]
+
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Mutex;
 
 use std::mem::transmute;
 
-use crate::oplog::{OpType, RemoteOp, SyncEngine, SyncError};
-use rusqlite::OptionalExtension;
+use crate::oplog::{ApplyDomainOp, CheckpointMode, ContentionPolicy, JournalMode, OpOutcome, OpType, RemoteOp, SyncEngine, SyncError, Winner};
+use crate::sync::SyncClient;
+use serde::Deserialize;
 
 /// Opaque handle that owns a SQLite connection.
 /// Swift/Objective-C hold this as an unsafe pointer and pass it back to Rust APIs.
 pub struct SyncConnHandle {
     conn: rusqlite::Connection,
+    migrations: BTreeMap<i32, String>, // host steps from sync_register_migration
+    max_op_bytes: Option<usize>,       // from SE_OpenOptions
+    contention: ContentionPolicy,      // from SE_OpenOptions
+    max_apply_attempts: Option<u32>,   // from sync_set_max_apply_attempts
+    cursor_cb: SE_CursorCallback,      // from sync_set_cursor_callback
+    cursor_user_data: *mut c_void,
+    conflict_cb: SE_ConflictCallback,  // from sync_set_conflict_callback
+    conflict_user_data: *mut c_void,
+    last_error: Mutex<(i32, String)>,  // per-handle copy of LAST_ERROR, see sync_handle_last_error
+}
+
+impl SyncConnHandle {
+    /// Engine bound to this handle's connection, configured from its open options.
+    fn engine(&self) -> Result<SyncEngine<'_>, SyncError> {
+        let mut engine = SyncEngine::new(&self.conn)?;
+        if let Some(n) = self.max_op_bytes { engine = engine.with_max_op_bytes(n); }
+        if self.contention != ContentionPolicy::default() { engine = engine.with_contention_policy(self.contention)?; }
+        if let Some(n) = self.max_apply_attempts { engine = engine.with_max_apply_attempts(n); }
+        if let Some(func) = self.cursor_cb {
+            let user_data = self.cursor_user_data;
+            engine.set_on_cursor_advance(Box::new(move |cursor| {
+                if let Ok(c) = CString::new(cursor) { func(user_data, c.as_ptr()); }
+            }));
+        }
+        if let Some(func) = self.conflict_cb {
+            let user_data = self.conflict_user_data;
+            engine.set_on_conflict(Box::new(move |op, conflict| {
+                if conflict.winner != Winner::Local { return; }
+                let reason = if conflict.local_op == OpType::Delete { SE_CONFLICT_LOCAL_DELETE } else { SE_CONFLICT_LOCAL_CHANGE };
+                let _ = with_se_op(op, |c_op| func(user_data, c_op, reason));
+            }));
+        }
+        Ok(engine)
+    }
 }
 
 thread_local! {
-    static LAST_ERROR: RefCell<(i32, String)> = RefCell::new((0, String::new()));
+    static LAST_ERROR: RefCell<(i32, String)> = const { RefCell::new((0, String::new())) };
+}
+
+/// Copies the thread's last error into the handle's slot when dropped, i.e. as the exported
+/// function that created it returns. See `sync_handle_last_error`.
+struct ErrorMirror(*mut SyncConnHandle);
+
+impl ErrorMirror {
+    /// Clears the thread's last error first, so the slot only reflects the call being made.
+    fn new(handle: *mut SyncConnHandle) -> Self {
+        clear_last_error();
+        ErrorMirror(handle)
+    }
+}
+
+impl Drop for ErrorMirror {
+    fn drop(&mut self) {
+        if let Some(h) = unsafe { self.0.as_ref() } {
+            let err = LAST_ERROR.with(|le| le.borrow().clone());
+            if let Ok(mut slot) = h.last_error.lock() { *slot = err; }
+        }
+    }
 }
 
 fn set_last_error(code: i32, msg: &str) { LAST_ERROR.with(|le| *le.borrow_mut() = (code, msg.to_string())); }
-fn clear_last_error() { LAST_ERROR.with(|le| *le.borrow_mut() = (0, String::new())); }
+fn clear_last_error() { LAST_ERROR.with(|le| *le.borrow_mut() = (SE_OK, String::new())); }
+
+/// Version of the C ABI described by `sync_engine.h`. Bumped whenever a `#[repr(C)]`
+/// struct, callback signature or exported constant changes incompatibly.
+pub const SE_ABI_VERSION: u32 = 2;
+
+/// Error codes reported by `sync_last_error_code`.
+pub const SE_OK: c_int = 0;
+pub const SE_ERR_GENERIC: c_int = 1;
+pub const SE_ERR_SERDE: c_int = 2;
+pub const SE_ERR_CALLBACK: c_int = 3;
+pub const SE_ERR_INVALID_ARG: c_int = 4;
+/// `sync_tx_exec_current` was handed SQL that would begin, commit or roll back a transaction.
+pub const SE_ERR_TX_CONTROL: c_int = 5;
+/// SQLITE_BUSY / SQLITE_LOCKED: another connection holds the lock; retrying later is reasonable.
+pub const SE_ERR_BUSY: c_int = 6;
+/// A SQLite constraint (UNIQUE, CHECK, NOT NULL, ...) rejected the write.
+pub const SE_ERR_CONSTRAINT: c_int = 7;
+/// A local change reused an `(hlc, origin)` already logged; regenerate the HLC and retry.
+pub const SE_ERR_CONFLICT: c_int = 8;
+/// An apply entry point was called from inside an apply callback; nesting would clobber the
+/// transaction `sync_tx_exec_current` writes through.
+pub const SE_ERR_NESTED_APPLY: c_int = 9;
+
+/// Values of `SE_Op.op_type`.
+pub const SE_OP_INSERT: i32 = 0;
+pub const SE_OP_UPDATE: i32 = 1;
+pub const SE_OP_DELETE: i32 = 2;
+
+/// Return codes of an `SE_ApplyCallback`.
+pub const SE_APPLY_OK: c_int = 0;
+pub const SE_APPLY_SKIP: c_int = 2;
+
+/// Values of `SE_Conflict.winner`.
+pub const SE_WINNER_LOCAL: c_int = 0;
+pub const SE_WINNER_REMOTE: c_int = 1;
+
+/// Reason codes passed to an `SE_ConflictCallback`.
+/// A newer pending local INSERT/UPDATE of the row won.
+pub const SE_CONFLICT_LOCAL_CHANGE: c_int = 0;
+/// The row has a pending local DELETE that won; it stays deleted.
+pub const SE_CONFLICT_LOCAL_DELETE: c_int = 1;
+
+/// Options for `sync_open_with_opts`. Zero-initialize for defaults.
+#[repr(C)]
+pub struct SE_OpenOptions {
+    pub max_op_bytes: usize, // reject logged ops whose JSON payload exceeds this; 0 = no limit
+    pub busy_timeout_ms: u32, // see ContentionPolicy
+    pub commit_retries: u32,
+    pub retry_backoff_ms: u32,
+}
+
+/// Values written by `sync_apply_remote_ops_outcomes`, one per op.
+pub const SE_OUTCOME_APPLIED: c_int = 0;
+pub const SE_OUTCOME_DUPLICATE: c_int = 1;
+pub const SE_OUTCOME_CONFLICT_LOCAL: c_int = 2;
+pub const SE_OUTCOME_CONFLICT_REMOTE: c_int = 3;
+pub const SE_OUTCOME_TOMBSTONED: c_int = 4;
+pub const SE_OUTCOME_UNKNOWN_TABLE: c_int = 5;
+pub const SE_OUTCOME_QUARANTINED: c_int = 6;
+
+/// Modes accepted by `sync_checkpoint`.
+pub const SE_CHECKPOINT_PASSIVE: c_int = 0;
+pub const SE_CHECKPOINT_FULL: c_int = 1;
+pub const SE_CHECKPOINT_RESTART: c_int = 2;
+pub const SE_CHECKPOINT_TRUNCATE: c_int = 3;
+
+/// Frame counts filled by `sync_checkpoint`; see `CheckpointResult`.
+#[repr(C)]
+pub struct SE_CheckpointResult {
+    pub busy: c_int,
+    pub log_frames: i64,
+    pub checkpointed_frames: i64,
+}
+
+/// Snapshot of the last error, filled by `sync_last_error`.
+/// `message` is owned by the caller and must be freed with `sync_string_free`.
+#[repr(C)]
+pub struct SE_Error {
+    pub code: c_int,
+    pub message: *mut c_char,
+}
+
+/// A remote op that contended with a pending local change for the same row.
+/// All strings are borrowed and only valid for the duration of the call that receives them.
+#[repr(C)]
+pub struct SE_Conflict {
+    pub remote_id: *const c_char,
+    pub table_name: *const c_char,
+    pub row_id: *const c_char,
+    pub local_hlc: *const c_char,
+    pub remote_hlc: *const c_char,
+    pub winner: c_int, // SE_WINNER_LOCAL or SE_WINNER_REMOTE
+}
 
 #[repr(C)]
 pub struct SE_Op {
     pub remote_id: *const c_char,
     pub table_name: *const c_char,
     pub row_id: *const c_char,
-    pub op_type: i32, // 0 Insert,1 Update,2 Delete
+    pub op_type: i32, // SE_OP_INSERT, SE_OP_UPDATE or SE_OP_DELETE
     pub columns_json: *const c_char, // nullable
     pub new_row_json: *const c_char, // nullable
     pub old_row_json: *const c_char, // nullable
@@ -33,10 +187,144 @@ pub struct SE_Op {
     pub origin: *const c_char,
 }
 
+/// Apply callback contract:
+/// - `SE_APPLY_OK` (0): the op was applied; it is recorded in `applied_remote_ops`.
+/// - `SE_APPLY_SKIP` (2): the host deliberately ignored the op; it is still recorded as applied
+///   so it won't be redelivered, and the batch continues.
+/// - anything else: hard failure; the whole batch is rolled back and that code is returned. With
+///   `sync_set_max_apply_attempts`, an op that keeps failing is eventually quarantined instead and
+///   the batch continues.
 pub type SE_ApplyCallback = Option<extern "C" fn(user_data: *mut c_void, op: *const SE_Op) -> c_int>;
 
+/// Push callback for `sync_push_only`. Receives the pending ops as a JSON array, writes the acked
+/// change ids into `out_acked` (room for `out_acked_cap` ids) and their count into `out_acked_len`.
+/// Return 0 on success; any other value aborts the push.
+pub type SE_PushCallback = Option<extern "C" fn(user_data: *mut c_void, ops_json: *const c_char, out_acked: *mut i64, out_acked_cap: usize, out_acked_len: *mut usize) -> c_int>;
+
+/// Pull callback for `sync_pull_only`. Receives the current cursor (null if unset) and stores in
+/// `out_page_json` a JSON object `{"ops": [RemoteOp...], "cursor": "..." | null}`. The string stays
+/// owned by the host and must remain valid until the callback is invoked again or `sync_pull_only` returns.
+/// Return 0 on success; any other value aborts the pull.
+pub type SE_PullCallback = Option<extern "C" fn(user_data: *mut c_void, cursor: *const c_char, out_page_json: *mut *const c_char) -> c_int>;
+
+/// Cursor callback for `sync_set_cursor_callback`. Receives the cursor just committed; the string
+/// is borrowed and only valid for the duration of the call.
+pub type SE_CursorCallback = Option<extern "C" fn(user_data: *mut c_void, cursor: *const c_char)>;
+
+/// Conflict callback for `sync_set_conflict_callback`. Receives a remote op that lost to a pending
+/// local change and an `SE_CONFLICT_*` reason. The op is borrowed and only valid for the duration of the call.
+pub type SE_ConflictCallback = Option<extern "C" fn(user_data: *mut c_void, op: *const SE_Op, reason: c_int)>;
+
 thread_local! {
-    static TLS_TX_PTR: RefCell<*mut rusqlite::Transaction<'static>> = RefCell::new(std::ptr::null_mut());
+    static TLS_TX_PTR: RefCell<*mut rusqlite::Transaction<'static>> = const { RefCell::new(std::ptr::null_mut()) };
+}
+
+/// Publishes a transaction in `TLS_TX_PTR` for `sync_tx_exec_current`; dropping it clears the
+/// pointer again, so every return path (and unwinding) leaves no dangling transaction behind.
+struct PublishedTx;
+
+impl PublishedTx {
+    /// None if another transaction is already published on this thread.
+    fn publish(tx: &rusqlite::Transaction<'_>) -> Option<PublishedTx> {
+        TLS_TX_PTR.with(|cell| {
+            let mut ptr = cell.borrow_mut();
+            if !ptr.is_null() { return None; }
+            *ptr = unsafe { transmute::<*const rusqlite::Transaction<'_>, *mut rusqlite::Transaction<'static>>(tx) };
+            Some(PublishedTx)
+        })
+    }
+}
+
+impl Drop for PublishedTx {
+    fn drop(&mut self) { TLS_TX_PTR.with(|cell| *cell.borrow_mut() = std::ptr::null_mut()); }
+}
+
+/// True (with `SE_ERR_NESTED_APPLY` set) when called from inside an apply callback.
+fn reject_nested_apply() -> bool {
+    let nested = TLS_TX_PTR.with(|cell| !cell.borrow().is_null());
+    if nested { set_last_error(SE_ERR_NESTED_APPLY, "nested apply not allowed"); }
+    nested
+}
+
+/// Map an error to the code reported by `sync_last_error_code`.
+trait FfiErrorCode {
+    fn ffi_code(&self) -> c_int;
+}
+
+impl FfiErrorCode for rusqlite::Error {
+    fn ffi_code(&self) -> c_int {
+        match self.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => SE_ERR_BUSY,
+            Some(rusqlite::ErrorCode::ConstraintViolation) => SE_ERR_CONSTRAINT,
+            _ => SE_ERR_GENERIC,
+        }
+    }
+}
+
+impl FfiErrorCode for SyncError {
+    fn ffi_code(&self) -> c_int {
+        match self {
+            SyncError::Sqlite(e) => e.ffi_code(),
+            SyncError::Serde(_) => SE_ERR_SERDE,
+            SyncError::Callback(_) => SE_ERR_CALLBACK,
+            SyncError::Validation(_) => SE_ERR_INVALID_ARG,
+            SyncError::Conflict(_) => SE_ERR_CONFLICT,
+            SyncError::NestedApply => SE_ERR_NESTED_APPLY,
+            _ => SE_ERR_GENERIC,
+        }
+    }
+}
+
+/// Adapts an `SE_ApplyCallback` to `ApplyDomainOp` so engine-driven apply paths can call back into
+/// the host. The transaction is published for `sync_tx_exec_current` while the callback runs.
+struct CallbackApplier {
+    cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+}
+
+impl ApplyDomainOp for CallbackApplier {
+    fn apply(&self, tx: &rusqlite::Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+        let Some(func) = self.cb else { return Ok(()) };
+        let Some(_published) = PublishedTx::publish(tx) else { return Err(SyncError::NestedApply) };
+        let rc = with_se_op(op, |c_op| func(self.user_data, c_op))?;
+        if rc != SE_APPLY_OK && rc != SE_APPLY_SKIP { return Err(SyncError::Callback(rc)); }
+        Ok(())
+    }
+}
+
+/// Call `f` with `op` as an `SE_Op` whose strings live until `f` returns.
+fn with_se_op<R>(op: &RemoteOp, f: impl FnOnce(&SE_Op) -> R) -> Result<R, SyncError> {
+    let cstr = |s: &str| CString::new(s).map_err(|_| SyncError::State("nul byte in op field"));
+    let json = |v: &Option<serde_json::Value>| v.as_ref().map(|v| cstr(&v.to_string())).transpose();
+    let opt_ptr = |c: &Option<CString>| c.as_ref().map_or(std::ptr::null(), |c| c.as_ptr());
+
+    let remote_id = cstr(&op.remote_id)?;
+    let table_name = cstr(&op.table_name)?;
+    let row_id = cstr(&op.row_id)?;
+    let columns = json(&op.columns)?;
+    let new_row = json(&op.new_row)?;
+    let old_row = json(&op.old_row)?;
+    let hlc = cstr(&op.hlc)?;
+    let origin = cstr(&op.origin)?;
+    let c_op = SE_Op {
+        remote_id: remote_id.as_ptr(),
+        table_name: table_name.as_ptr(),
+        row_id: row_id.as_ptr(),
+        op_type: match op.op_type { OpType::Insert => SE_OP_INSERT, OpType::Update => SE_OP_UPDATE, OpType::Delete => SE_OP_DELETE },
+        columns_json: opt_ptr(&columns),
+        new_row_json: opt_ptr(&new_row),
+        old_row_json: opt_ptr(&old_row),
+        hlc: hlc.as_ptr(),
+        origin: origin.as_ptr(),
+    };
+    Ok(f(&c_op))
+}
+
+/// One page handed back by an `SE_PullCallback`.
+#[derive(Deserialize)]
+struct PullPage {
+    ops: Vec<RemoteOp>,
+    cursor: Option<String>,
 }
 
 fn ptr_to_str<'a>(ptr: *const c_char) -> Result<&'a str, ()> {
@@ -53,6 +341,23 @@ fn opt_ptr_to_str<'a>(ptr: *const c_char) -> Result<Option<&'a str>, ()> {
     Ok(Some(ptr_to_str(ptr)?))
 }
 
+/// The `(table_name, row_id, origin)` strings every `sync_log_*` call takes; sets the last error if one is invalid.
+fn row_args<'a>(table_name: *const c_char, row_id: *const c_char, origin: *const c_char) -> Result<(&'a str, &'a str, &'a str), ()> {
+    match (ptr_to_str(table_name), ptr_to_str(row_id), ptr_to_str(origin)) {
+        (Ok(a), Ok(b), Ok(c)) => Ok((a, b, c)),
+        _ => { set_last_error(SE_ERR_INVALID_ARG, "invalid table_name, row_id or origin"); Err(()) }
+    }
+}
+
+/// Parse a nullable JSON argument; sets the last error (naming `name`) if it is invalid.
+fn parse_opt_json(ptr: *const c_char, name: &str) -> Result<Option<serde_json::Value>, ()> {
+    match opt_ptr_to_str(ptr) {
+        Ok(Some(s)) => serde_json::from_str(s).map(Some).map_err(|e| set_last_error(SE_ERR_SERDE, &format!("{}: {}", name, e))),
+        Ok(None) => Ok(None),
+        Err(_) => { set_last_error(SE_ERR_INVALID_ARG, &format!("invalid {}", name)); Err(()) }
+    }
+}
+
 fn to_cstring_ptr(s: &str) -> *mut c_char {
     CString::new(s).map(|cs| cs.into_raw()).unwrap_or(std::ptr::null_mut())
 }
@@ -66,6 +371,31 @@ pub extern "C" fn sync_string_free(s: *mut c_char) {
     unsafe { let _ = CString::from_raw(s); }
 }
 
+/// Hand `bytes` to the host through the `(out_ptr, out_len)` convention used by every
+/// byte-returning function: the buffer's capacity equals its length (`into_boxed_slice`),
+/// so `sync_bytes_free(ptr, len)` can rebuild and drop it.
+#[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+fn bytes_to_out(bytes: Vec<u8>, out_ptr: *mut *mut u8, out_len: *mut usize) {
+    let boxed = bytes.into_boxed_slice();
+    let len = boxed.len();
+    let ptr = Box::into_raw(boxed) as *mut u8;
+    unsafe {
+        *out_ptr = ptr;
+        *out_len = len;
+    }
+}
+
+/// Free a byte buffer returned through an `(out_ptr, out_len)` pair, passing back the same length.
+/// Buffers and strings come from different constructors: freeing a buffer with `sync_string_free`,
+/// or a string with this function, is undefined behavior.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_bytes_free(ptr: *mut u8, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe { drop(Vec::from_raw_parts(ptr, len, len)); }
+}
+
 /// Open a SQLite connection. Path can be file path or ":memory:".
 /// Returns null on failure.
 #[unsafe(no_mangle)]
@@ -77,10 +407,29 @@ pub extern "C" fn sync_open(path: *const c_char) -> *mut SyncConnHandle {
     match rusqlite::Connection::open(path) {
         Ok(conn) => {
             clear_last_error();
-            Box::into_raw(Box::new(SyncConnHandle { conn }))
+            Box::into_raw(Box::new(SyncConnHandle { conn, migrations: BTreeMap::new(), max_op_bytes: None, contention: ContentionPolicy::default(), max_apply_attempts: None, cursor_cb: None, cursor_user_data: std::ptr::null_mut(), conflict_cb: None, conflict_user_data: std::ptr::null_mut(), last_error: Mutex::new((SE_OK, String::new())) }))
         },
-        Err(e) => { set_last_error(1, &format!("sqlite: {}", e)); std::ptr::null_mut() },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("sqlite: {}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// Like `sync_open`, applying `opts` (nullable; null means defaults) to every engine call made
+/// through the returned handle. The busy timeout is set on the connection right away.
+/// Returns null on failure, including `SE_ERR_INVALID_ARG` for contention settings that only
+/// spin (commit retries with neither a busy timeout nor a backoff).
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_open_with_opts(path: *const c_char, opts: *const SE_OpenOptions) -> *mut SyncConnHandle {
+    let handle = sync_open(path);
+    if let (Some(h), Some(o)) = (unsafe { handle.as_mut() }, unsafe { opts.as_ref() }) {
+        h.max_op_bytes = (o.max_op_bytes > 0).then_some(o.max_op_bytes);
+        h.contention = ContentionPolicy { busy_timeout_ms: o.busy_timeout_ms, commit_retries: o.commit_retries, retry_backoff_ms: o.retry_backoff_ms };
+        if let Err(e) = h.engine() {
+            set_last_error(e.ffi_code(), &format!("{}", e));
+            sync_close(handle);
+            return std::ptr::null_mut();
+        }
     }
+    handle
 }
 
 /// Close a previously opened connection.
@@ -95,15 +444,16 @@ pub extern "C" fn sync_close(handle: *mut SyncConnHandle) {
 /// Initialize required metadata tables. Returns 0 on success, non-zero on error.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_init_schema(handle: *mut SyncConnHandle) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
     if let Some(h) = h {
-        let engine = SyncEngine::new(&h.conn);
+        let engine = h.engine();
         match engine.and_then(|e| e.init_schema()) {
             Ok(_) => { clear_last_error(); 0 },
-            Err(e) => { set_last_error(1, &format!("{}", e)); 1 },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 },
         }
     } else {
-        set_last_error(4, "null handle");
+        set_last_error(SE_ERR_INVALID_ARG, "null handle");
         2
     }
 }
@@ -111,16 +461,17 @@ pub extern "C" fn sync_init_schema(handle: *mut SyncConnHandle) -> c_int {
 /// Generate next HLC token for an origin. Returns newly allocated C string or null on error.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_next_hlc(handle: *mut SyncConnHandle, origin: *const c_char) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    let origin = match ptr_to_str(origin) { Ok(s) => s, Err(_) => { set_last_error(4, "invalid origin"); return std::ptr::null_mut() } };
+    let origin = match ptr_to_str(origin) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid origin"); return std::ptr::null_mut() } };
     if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return std::ptr::null_mut() } };
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
         match engine.next_hlc(origin) {
             Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
-            Err(e) => { set_last_error(1, &format!("{}", e)); std::ptr::null_mut() },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
         }
     } else {
-        set_last_error(4, "null handle");
+        set_last_error(SE_ERR_INVALID_ARG, "null handle");
         std::ptr::null_mut()
     }
 }
@@ -134,21 +485,18 @@ pub extern "C" fn sync_log_insert_fullrow(
     new_row_json: *const c_char,
     origin: *const c_char,
 ) -> i64 {
-    let h = unsafe { handle.as_mut() };
-    let (table_name, row_id, origin) = match (
-        ptr_to_str(table_name),
-        ptr_to_str(row_id),
-        ptr_to_str(origin),
-    ) {
-        (Ok(a), Ok(b), Ok(c)) => (a, b, c),
-        _ => return -1,
+    let _mirror = ErrorMirror::new(handle);
+    let Ok((table_name, row_id, origin)) = row_args(table_name, row_id, origin) else { return -1 };
+    let new_row_v = match parse_opt_json(new_row_json, "new_row_json") {
+        Ok(Some(v)) => v,
+        Ok(None) => { set_last_error(SE_ERR_INVALID_ARG, "invalid new_row_json"); return -1 },
+        Err(()) => return -1,
     };
-    let new_row_s = match ptr_to_str(new_row_json) { Ok(s) => s, Err(_) => return -1 };
-    let new_row_v: serde_json::Value = match serde_json::from_str(new_row_s) { Ok(v) => v, Err(_) => return -1 };
-    if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(_) => return -1 };
-        match engine.log_insert_fullrow(table_name, row_id, &new_row_v, origin) { Ok(id) => id, Err(_) => -1 }
-    } else { -1 }
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return -1 };
+    match h.engine().and_then(|e| e.log_insert_fullrow(table_name, row_id, &new_row_v, origin)) {
+        Ok(id) => { clear_last_error(); id },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); -1 },
+    }
 }
 
 /// Log an UPDATE with optional fields and snapshots. Returns change_id or -1.
@@ -162,41 +510,24 @@ pub extern "C" fn sync_log_update(
     old_row_json: *const c_char,   // nullable
     origin: *const c_char,
 ) -> i64 {
-    let h = unsafe { handle.as_mut() };
-    let (table_name, row_id, origin) = match (
-        ptr_to_str(table_name),
-        ptr_to_str(row_id),
-        ptr_to_str(origin),
-    ) {
-        (Ok(a), Ok(b), Ok(c)) => (a, b, c),
-        _ => return -1,
-    };
-    let columns_v: Option<serde_json::Value> = match opt_ptr_to_str(columns_json) {
-        Ok(Some(s)) => match serde_json::from_str(s) { Ok(v) => Some(v), Err(_) => return -1 },
-        Ok(None) => None,
-        Err(_) => return -1,
-    };
-    let new_row_v: Option<serde_json::Value> = match opt_ptr_to_str(new_row_json) {
-        Ok(Some(s)) => match serde_json::from_str(s) { Ok(v) => Some(v), Err(_) => return -1 },
-        Ok(None) => None,
-        Err(_) => return -1,
-    };
-    let old_row_v: Option<serde_json::Value> = match opt_ptr_to_str(old_row_json) {
-        Ok(Some(s)) => match serde_json::from_str(s) { Ok(v) => Some(v), Err(_) => return -1 },
-        Ok(None) => None,
-        Err(_) => return -1,
-    };
-    if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(_) => return -1 };
-        match engine.log_update(
-            table_name,
-            row_id,
-            columns_v.as_ref(),
-            new_row_v.as_ref(),
-            old_row_v.as_ref(),
-            origin,
-        ) { Ok(id) => id, Err(_) => -1 }
-    } else { -1 }
+    let _mirror = ErrorMirror::new(handle);
+    let Ok((table_name, row_id, origin)) = row_args(table_name, row_id, origin) else { return -1 };
+    let Ok(columns_v) = parse_opt_json(columns_json, "columns_json") else { return -1 };
+    let Ok(new_row_v) = parse_opt_json(new_row_json, "new_row_json") else { return -1 };
+    let Ok(old_row_v) = parse_opt_json(old_row_json, "old_row_json") else { return -1 };
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return -1 };
+    let logged = h.engine().and_then(|e| e.log_update(
+        table_name,
+        row_id,
+        columns_v.as_ref(),
+        new_row_v.as_ref(),
+        old_row_v.as_ref(),
+        origin,
+    ));
+    match logged {
+        Ok(id) => { clear_last_error(); id },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); -1 },
+    }
 }
 
 /// Log a DELETE. Returns change_id or -1.
@@ -207,72 +538,100 @@ pub extern "C" fn sync_log_delete(
     row_id: *const c_char,
     origin: *const c_char,
 ) -> i64 {
-    let h = unsafe { handle.as_mut() };
-    let (table_name, row_id, origin) = match (
-        ptr_to_str(table_name),
-        ptr_to_str(row_id),
-        ptr_to_str(origin),
-    ) {
-        (Ok(a), Ok(b), Ok(c)) => (a, b, c),
-        _ => return -1,
-    };
-    if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(_) => return -1 };
-        match engine.log_delete(table_name, row_id, origin) { Ok(id) => id, Err(_) => -1 }
-    } else { -1 }
+    let _mirror = ErrorMirror::new(handle);
+    let Ok((table_name, row_id, origin)) = row_args(table_name, row_id, origin) else { return -1 };
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return -1 };
+    match h.engine().and_then(|e| e.log_delete(table_name, row_id, origin)) {
+        Ok(id) => { clear_last_error(); id },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); -1 },
+    }
 }
 
 /// Get pending ops as JSON array string. Returns newly allocated C string or null on error.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_get_pending_ops_json(handle: *mut SyncConnHandle, limit: i64) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
     if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return std::ptr::null_mut() } };
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
         match engine.get_pending_ops(limit) {
             Ok(changes) => match serde_json::to_string(&changes) {
                 Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
-                Err(e) => { set_last_error(2, &format!("{}", e)); std::ptr::null_mut() },
+                Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
             },
-            Err(e) => { set_last_error(1, &format!("{}", e)); std::ptr::null_mut() },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
         }
-    } else { std::ptr::null_mut() }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); std::ptr::null_mut() }
+}
+
+/// Like `sync_get_pending_ops_json`, restricted to changes of `table_name`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_get_pending_ops_for_table_json(handle: *mut SyncConnHandle, table_name: *const c_char, limit: i64) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let table = match ptr_to_str(table_name) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid table_name"); return std::ptr::null_mut() } };
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.get_pending_ops_for_table(table, limit)) {
+        Ok(changes) => match serde_json::to_string(&changes) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// Like `sync_get_pending_ops_json`, compressed with zstd: the buffer is written through
+/// `(out_ptr, out_len)` and must be freed with `sync_bytes_free`. Requires the `zstd` feature.
+/// Returns 0 on success.
+#[cfg(feature = "zstd")]
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_get_pending_ops_compressed(handle: *mut SyncConnHandle, limit: i64, out_ptr: *mut *mut u8, out_len: *mut usize) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    if out_ptr.is_null() || out_len.is_null() { set_last_error(SE_ERR_INVALID_ARG, "out_ptr or out_len is null"); return 3; }
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    match h.engine().and_then(|e| e.get_pending_ops_zstd(limit)) {
+        Ok(bytes) => { bytes_to_out(bytes, out_ptr, out_len); clear_last_error(); 0 },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 },
+    }
 }
 
 /// Mark provided change ids as acked. Returns 0 on success.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_mark_ops_acked(handle: *mut SyncConnHandle, ids: *const i64, len: usize) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    if h.is_none() { set_last_error(4, "null handle"); return 2; }
-    if ids.is_null() && len > 0 { set_last_error(4, "ids null but len > 0"); return 3; }
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    if ids.is_null() && len > 0 { set_last_error(SE_ERR_INVALID_ARG, "ids null but len > 0"); return 3; }
     let slice = unsafe { std::slice::from_raw_parts(ids, len) };
     let h = h.unwrap();
-    let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
-    match engine.mark_ops_acked(slice) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(1, &format!("{}", e)); 1 } }
+    let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+    match engine.mark_ops_acked(slice) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 } }
 }
 
 /// Get the remote cursor if set. Returns empty string if not set, null on error.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_get_remote_cursor(handle: *mut SyncConnHandle) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
     if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return std::ptr::null_mut() } };
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
         match engine.get_remote_cursor() {
             Ok(Some(s)) => { clear_last_error(); to_cstring_ptr(&s) },
             Ok(None) => { clear_last_error(); to_cstring_ptr("") },
-            Err(e) => { set_last_error(1, &format!("{}", e)); std::ptr::null_mut() },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
         }
-    } else { std::ptr::null_mut() }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); std::ptr::null_mut() }
 }
 
 /// Set the remote cursor. Returns 0 on success.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_set_remote_cursor(handle: *mut SyncConnHandle, cursor: *const c_char) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    let cursor = match ptr_to_str(cursor) { Ok(s) => s, Err(_) => { set_last_error(4, "invalid cursor"); return 3 } };
+    let cursor = match ptr_to_str(cursor) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid cursor"); return 3 } };
     if let Some(h) = h {
-        let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
-        match engine.set_remote_cursor(cursor) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(1, &format!("{}", e)); 1 } }
-    } else { set_last_error(4, "null handle"); 2 }
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+        match engine.set_remote_cursor(cursor) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 } }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); 2 }
 }
 
 
@@ -284,59 +643,135 @@ pub extern "C" fn sync_last_error_code() -> c_int { LAST_ERROR.with(|le| le.borr
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_last_error_message() -> *mut c_char { to_cstring_ptr(&LAST_ERROR.with(|le| le.borrow().1.clone())) }
 
+/// Copy the last error for the current thread into `out`. Returns 0 on success.
+/// `out->message` is newly allocated and must be freed with sync_string_free.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_last_error(out: *mut SE_Error) -> c_int {
+    if out.is_null() { return 3; }
+    let (code, msg) = LAST_ERROR.with(|le| le.borrow().clone());
+    unsafe { *out = SE_Error { code, message: to_cstring_ptr(&msg) }; }
+    0
+}
+
+/// Return the ABI version this library was built with (see SE_ABI_VERSION).
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_abi_version() -> u32 { SE_ABI_VERSION }
+
 /// Mark provided change ids as pushed. Returns 0 on success.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_mark_ops_pushed(handle: *mut SyncConnHandle, ids: *const i64, len: usize) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    if h.is_none() { set_last_error(4, "null handle"); return 2; }
-    if ids.is_null() && len > 0 { set_last_error(4, "ids null but len > 0"); return 3; }
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    if ids.is_null() && len > 0 { set_last_error(SE_ERR_INVALID_ARG, "ids null but len > 0"); return 3; }
     let slice = unsafe { std::slice::from_raw_parts(ids, len) };
     let h = h.unwrap();
-    let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
-    match engine.mark_ops_pushed(slice) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(1, &format!("{}", e)); 1 } }
+    let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+    match engine.mark_ops_pushed(slice) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 } }
 }
 
 /// Get the current schema version. Returns 0 on success and writes to out_version.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_get_schema_version(handle: *mut SyncConnHandle, out_version: *mut i32) -> c_int {
-    if out_version.is_null() { set_last_error(4, "out_version is null"); return 3; }
+    let _mirror = ErrorMirror::new(handle);
+    if out_version.is_null() { set_last_error(SE_ERR_INVALID_ARG, "out_version is null"); return 3; }
     let h = unsafe { handle.as_mut() };
-    if h.is_none() { set_last_error(4, "null handle"); return 2; }
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
     let h = h.unwrap();
-    let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
+    let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
     match engine.get_schema_version() {
         Ok(v) => { unsafe { *out_version = v; } clear_last_error(); 0 },
-        Err(e) => { set_last_error(1, &format!("{}", e)); 1 }
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
     }
 }
 
-/// Run migrations up to target_version. Returns 0 on success.
+/// Run migrations up to target_version, including steps added with `sync_register_migration`. Returns 0 on success.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_run_migrations(handle: *mut SyncConnHandle, target_version: i32) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    if h.is_none() { set_last_error(4, "null handle"); return 2; }
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
     let h = h.unwrap();
-    let engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
-    match engine.run_migrations(target_version) {
+    let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+    match engine.run_migrations_with_steps(target_version, &h.migrations) {
         Ok(_) => { clear_last_error(); 0 },
-        Err(e) => { set_last_error(1, &format!("{}", e)); 1 }
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
+    }
+}
+
+/// True if any statement in `sql` starts with a transaction-control keyword.
+/// String literals, quoted identifiers and comments are skipped, and so is the `BEGIN ... END`
+/// body of a `CREATE TRIGGER` (including the `CASE ... END`s inside it).
+fn has_tx_control(sql: &str) -> bool {
+    const KEYWORDS: [&str; 6] = ["BEGIN", "COMMIT", "END", "ROLLBACK", "SAVEPOINT", "RELEASE"];
+    let b = sql.as_bytes();
+    let mut i = 0;
+    let mut at_start = true;
+    let mut create = false; // the current statement is a CREATE ...
+    let mut trigger = false; // ... TRIGGER whose body hasn't been closed yet
+    let mut depth = 0; // open BEGIN/CASE blocks inside the trigger body
+    while i < b.len() {
+        let c = b[i];
+        match c {
+            b'\'' | b'"' | b'`' | b'[' => {
+                let close = if c == b'[' { b']' } else { c };
+                i += 1;
+                while i < b.len() && b[i] != close { i += 1; }
+                i += 1;
+                at_start = false;
+            }
+            b'-' if b.get(i + 1) == Some(&b'-') => {
+                while i < b.len() && b[i] != b'\n' { i += 1; }
+            }
+            b'/' if b.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i + 1 < b.len() && !(b[i] == b'*' && b[i + 1] == b'/') { i += 1; }
+                i += 2;
+            }
+            b';' if depth > 0 => { i += 1; }
+            b';' => { at_start = true; create = false; trigger = false; i += 1; }
+            _ if c.is_ascii_whitespace() => { i += 1; }
+            _ if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = i;
+                while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') { i += 1; }
+                let word = &sql[start..i];
+                let is = |k: &str| word.eq_ignore_ascii_case(k);
+                if at_start {
+                    if KEYWORDS.iter().any(|k| is(k)) { return true; }
+                    create = is("CREATE");
+                } else if create && !trigger && depth == 0 && is("TRIGGER") {
+                    trigger = true;
+                } else if trigger && (is("CASE") || (depth == 0 && is("BEGIN"))) {
+                    depth += 1;
+                } else if depth > 0 && is("END") {
+                    depth -= 1;
+                    if depth == 0 { trigger = false; }
+                }
+                at_start = false;
+            }
+            _ => { at_start = false; i += 1; }
+        }
     }
+    false
 }
 
 /// Execute a SQL statement inside the current transaction context, if any (used by apply callback). Returns 0 on success.
+/// SQL containing BEGIN/COMMIT/END/ROLLBACK/SAVEPOINT/RELEASE statements is rejected with 4
+/// (last error `SE_ERR_TX_CONTROL`): the engine owns the transaction and commits it after the batch.
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_tx_exec_current(sql: *const c_char) -> c_int {
-    let sql = match ptr_to_str(sql) { Ok(s) => s, Err(_) => { set_last_error(4, "invalid sql"); return 3 } };
+    let sql = match ptr_to_str(sql) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid sql"); return 3 } };
+    if has_tx_control(sql) { set_last_error(SE_ERR_TX_CONTROL, "transaction control statements are not allowed here"); return 4; }
     let mut ran = false;
     let mut err: Option<String> = None;
     TLS_TX_PTR.with(|cell| {
         let ptr = *cell.borrow();
-        if ptr.is_null() { err = Some("no active transaction".to_string()); return; }
+        if ptr.is_null() { set_last_error(SE_ERR_GENERIC, "no active transaction"); return; }
         ran = true;
         unsafe {
-            match (&mut *ptr).execute_batch(sql) {
+            match (*ptr).execute_batch(sql) {
                 Ok(_) => { clear_last_error(); },
-                Err(e) => { set_last_error(1, &format!("{}", e)); err = Some(e.to_string()); }
+                Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); err = Some(e.to_string()); }
             }
         }
     });
@@ -345,22 +780,27 @@ pub extern "C" fn sync_tx_exec_current(sql: *const c_char) -> c_int {
 }
 
 fn cstr_or_none<'a>(p: *const c_char) -> Result<Option<&'a str>, ()> { opt_ptr_to_str(p) }
-fn str_or_fail<'a>(p: *const c_char, name: &str) -> Result<&'a str, ()> { ptr_to_str(p).map_err(|_| ()) }
+fn str_or_fail<'a>(p: *const c_char, _name: &str) -> Result<&'a str, ()> { ptr_to_str(p).map_err(|_| ()) }
 
 fn op_from_se(op: &SE_Op) -> Result<RemoteOp, SyncError> {
     let remote_id = str_or_fail(op.remote_id, "remote_id").map_err(|_| SyncError::State("remote_id"))?.to_string();
     let table_name = str_or_fail(op.table_name, "table_name").map_err(|_| SyncError::State("table_name"))?.to_string();
     let row_id = str_or_fail(op.row_id, "row_id").map_err(|_| SyncError::State("row_id"))?.to_string();
-    let op_type = match op.op_type { 0 => OpType::Insert, 1 => OpType::Update, 2 => OpType::Delete, _ => return Err(SyncError::State("invalid op_type")) };
+    let op_type = match op.op_type { SE_OP_INSERT => OpType::Insert, SE_OP_UPDATE => OpType::Update, SE_OP_DELETE => OpType::Delete, _ => return Err(SyncError::State("invalid op_type")) };
     let columns = match cstr_or_none(op.columns_json) { Ok(Some(s)) => Some(serde_json::from_str(s)?), Ok(None) => None, Err(_) => return Err(SyncError::State("columns_json")) };
     let new_row = match cstr_or_none(op.new_row_json) { Ok(Some(s)) => Some(serde_json::from_str(s)?), Ok(None) => None, Err(_) => return Err(SyncError::State("new_row_json")) };
     let old_row = match cstr_or_none(op.old_row_json) { Ok(Some(s)) => Some(serde_json::from_str(s)?), Ok(None) => None, Err(_) => return Err(SyncError::State("old_row_json")) };
     let hlc = str_or_fail(op.hlc, "hlc").map_err(|_| SyncError::State("hlc"))?.to_string();
+    crate::merge::parse_hlc_strict(&hlc)?;
     let origin = str_or_fail(op.origin, "origin").map_err(|_| SyncError::State("origin"))?.to_string();
     Ok(RemoteOp { remote_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin })
 }
 
-/// Apply a batch of remote ops transactionally. For each op, the callback is invoked; Swift may call `sync_tx_exec_current` within the callback to perform domain writes inside the same transaction. Returns 0 on success.
+/// Apply a batch of remote ops transactionally. For each op, the callback is invoked; Swift may call `sync_tx_exec_current` within the callback to perform domain writes inside the same transaction.
+/// The callback may return `SE_APPLY_SKIP` to ignore an op while still recording it as applied (see `SE_ApplyCallback`). Returns 0 on success.
+/// An op contending with a newer pending local change of the same row loses by last-writer-wins: it is recorded
+/// as applied without reaching the callback.
+/// Calling an apply entry point again from inside the callback is refused with 1 (last error `SE_ERR_NESTED_APPLY`).
 #[unsafe(no_mangle)]
 pub extern "C" fn sync_apply_remote_ops(
     handle: *mut SyncConnHandle,
@@ -369,63 +809,1129 @@ pub extern "C" fn sync_apply_remote_ops(
     cb: SE_ApplyCallback,
     user_data: *mut c_void,
 ) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
     let h = unsafe { handle.as_mut() };
-    if h.is_none() { set_last_error(4, "null handle"); return 2; }
-    if ops.is_null() && len > 0 { set_last_error(4, "ops null but len > 0"); return 3; }
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    if ops.is_null() && len > 0 { set_last_error(SE_ERR_INVALID_ARG, "ops null but len > 0"); return 3; }
     let h = h.unwrap();
-    let _engine = match SyncEngine::new(&h.conn) { Ok(e) => e, Err(e) => { set_last_error(1, &format!("{}", e)); return 1 } };
 
     // Build Rust RemoteOp list first to validate inputs.
-    let slice = unsafe { std::slice::from_raw_parts(ops, len) };
+    let slice = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ops, len) } };
     let mut parsed_ops: Vec<RemoteOp> = Vec::with_capacity(len);
     for o in slice.iter() {
-        match op_from_se(o) { Ok(ro) => parsed_ops.push(ro), Err(e) => { set_last_error(4, &format!("{}", e)); return 3 } }
+        match op_from_se(o) { Ok(ro) => parsed_ops.push(ro), Err(e) => { set_last_error(SE_ERR_INVALID_ARG, &format!("{}", e)); return 3 } }
     }
+    apply_ops_with_callback(h, &parsed_ops, cb, user_data).map_or_else(|rc| rc, |_| 0)
+}
 
-    let mut tx = match h.conn.unchecked_transaction() {
-        Ok(t) => t,
-        Err(e) => { set_last_error(1, &format!("{}", e)); return 1 }
+/// Like `sync_apply_remote_ops`, returning the `remote_id`s that were newly applied by this call as a
+/// JSON array: the ops that reached the callback. Duplicates, ops that lost to a pending local change, ops for
+/// unknown tables and quarantined ops are left out. The list is only returned
+/// once the transaction has committed. Returns null on error; free the result with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_apply_remote_ops_applied_json(
+    handle: *mut SyncConnHandle,
+    ops: *const SE_Op,
+    len: usize,
+    cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    if ops.is_null() && len > 0 { set_last_error(SE_ERR_INVALID_ARG, "ops null but len > 0"); return std::ptr::null_mut(); }
+    let slice = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ops, len) } };
+    let mut parsed_ops: Vec<RemoteOp> = Vec::with_capacity(len);
+    for o in slice.iter() {
+        match op_from_se(o) { Ok(ro) => parsed_ops.push(ro), Err(e) => { set_last_error(SE_ERR_INVALID_ARG, &format!("{}", e)); return std::ptr::null_mut() } }
+    }
+    let Ok(outcomes) = apply_ops_with_callback(h, &parsed_ops, cb, user_data) else { return std::ptr::null_mut() };
+    let applied: Vec<&str> = parsed_ops
+        .iter()
+        .zip(outcomes)
+        .filter(|(_, o)| matches!(o, OpOutcome::Applied | OpOutcome::Conflict(Winner::Remote) | OpOutcome::Tombstoned))
+        .map(|(op, _)| op.remote_id.as_str())
+        .collect();
+    match serde_json::to_string(&applied) {
+        Ok(s) => to_cstring_ptr(&s),
+        Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() }
+    }
+}
+
+/// Like `sync_apply_remote_ops`, taking the ops as a JSON array of `RemoteOp` objects (as sent by
+/// the server) instead of `SE_Op` structs. The callback receives each op as an `SE_Op` built from
+/// the JSON, valid for the duration of the call. Returns 0 on success, 3 on malformed JSON.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_apply_remote_ops_json(
+    handle: *mut SyncConnHandle,
+    ops_json: *const c_char,
+    cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    let json = match ptr_to_str(ops_json) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid ops_json"); return 3 } };
+    let parsed_ops: Vec<RemoteOp> = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); return 3 }
     };
-    // Place tx into TLS for callback to use.
-    let mut tx_box = Box::new(tx);
-    let tx_ptr: *mut rusqlite::Transaction<'static> = unsafe { transmute::<*mut rusqlite::Transaction<'_>, *mut rusqlite::Transaction<'static>>(&mut *tx_box) };
-    TLS_TX_PTR.with(|cell| *cell.borrow_mut() = tx_ptr);
-
-    for (idx, op) in parsed_ops.iter().enumerate() {
-        // Idempotency check
-        let seen = tx_box.query_row(
-            "SELECT 1 FROM applied_remote_ops WHERE remote_id=?1",
-            rusqlite::params![&op.remote_id],
-            |_r| Ok(()),
-        ).optional();
-        match seen {
-            Ok(Some(_)) => { continue; },
-            Ok(None) => {},
-            Err(e) => { TLS_TX_PTR.with(|cell| *cell.borrow_mut() = std::ptr::null_mut()); set_last_error(1, &format!("{}", e)); return 1; }
+    for op in &parsed_ops {
+        if let Err(e) = crate::merge::parse_hlc_strict(&op.hlc) { set_last_error(SE_ERR_INVALID_ARG, &format!("{}", e)); return 3 }
+    }
+    apply_ops_with_callback(h, &parsed_ops, cb, user_data).map_or_else(|rc| rc, |_| 0)
+}
+
+/// Apply path shared by every `sync_apply_remote_ops*` entry point: runs `ops` through the handle's
+/// engine (`apply_remote_ops_outcomes`) with `cb` as the applier and returns one outcome per op.
+/// On failure the last error is set and the code to return is given back: a failing callback's
+/// own code, 1 otherwise.
+fn apply_ops_with_callback(
+    h: &SyncConnHandle,
+    ops: &[RemoteOp],
+    cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+) -> Result<Vec<OpOutcome>, c_int> {
+    if reject_nested_apply() { return Err(1); }
+    match h.engine().and_then(|e| e.apply_remote_ops_outcomes(ops, &CallbackApplier { cb, user_data })) {
+        Ok(outcomes) => { clear_last_error(); Ok(outcomes) },
+        Err(SyncError::Callback(rc)) => { set_last_error(SE_ERR_CALLBACK, "apply callback failed"); Err(rc) },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); Err(1) },
+    }
+}
+
+/// List remote ops applied at or after `since_ms` as a JSON array of `{"remote_id","applied_ms"}` objects,
+/// ordered by applied_ms. Returns newly allocated C string or null on error.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_applied_ops_since_json(handle: *mut SyncConnHandle, since_ms: i64, limit: i64) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if let Some(h) = h {
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
+        match engine.applied_ops_since(since_ms, limit) {
+            Ok(rows) => {
+                let items: Vec<serde_json::Value> = rows
+                    .into_iter()
+                    .map(|(remote_id, applied_ms)| serde_json::json!({ "remote_id": remote_id, "applied_ms": applied_ms }))
+                    .collect();
+                match serde_json::to_string(&items) {
+                    Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+                    Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+                }
+            },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
         }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); std::ptr::null_mut() }
+}
 
-        // Callback
-        if let Some(func) = cb {
-            // Borrow the input op directly from the C slice; do not move.
-            let c_op_ptr: *const SE_Op = unsafe { slice.as_ptr().add(idx) };
-            let rc = func(user_data, c_op_ptr);
-            if rc != 0 { TLS_TX_PTR.with(|cell| *cell.borrow_mut() = std::ptr::null_mut()); set_last_error(3, "apply callback failed"); return rc; }
+/// Push one batch of up to `limit` pending changes through `push_cb` without pulling.
+/// `out_count` (nullable) receives the number of acked changes. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_push_only(
+    handle: *mut SyncConnHandle,
+    limit: i64,
+    push_cb: SE_PushCallback,
+    user_data: *mut c_void,
+    out_count: *mut usize,
+) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    let Some(push_fn) = push_cb else { set_last_error(SE_ERR_INVALID_ARG, "null push callback"); return 3 };
+    let h = h.unwrap();
+    let client = match h.engine().and_then(|e| SyncClient::from_engine(e, CallbackApplier { cb: None, user_data })) { Ok(c) => c, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+    let push = |ops: &[crate::oplog::Change]| -> Result<Vec<i64>, SyncError> {
+        let ops_json = CString::new(serde_json::to_string(ops)?).map_err(|_| SyncError::State("nul byte in ops json"))?;
+        let mut acked = vec![0i64; ops.len()];
+        let mut acked_len: usize = 0;
+        let rc = push_fn(user_data, ops_json.as_ptr(), acked.as_mut_ptr(), acked.len(), &mut acked_len);
+        if rc != 0 { return Err(SyncError::Callback(rc)); }
+        if acked_len > acked.len() { return Err(SyncError::State("acked len exceeds capacity")); }
+        acked.truncate(acked_len);
+        Ok(acked)
+    };
+    match client.push_only(push, limit) {
+        Ok(n) => {
+            if !out_count.is_null() { unsafe { *out_count = n; } }
+            clear_last_error();
+            0
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
+    }
+}
+
+/// Pull and apply up to `max_pages` pages through `pull_cb` without pushing. Each op is handed to
+/// `apply_cb` exactly like `sync_apply_remote_ops`, so it may call `sync_tx_exec_current`; ops losing
+/// to a newer pending local change are skipped and reported to the conflict callback the same way.
+/// `out_count` (nullable) receives the number of ops pulled. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_pull_only(
+    handle: *mut SyncConnHandle,
+    max_pages: usize,
+    pull_cb: SE_PullCallback,
+    apply_cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+    out_count: *mut usize,
+) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    let Some(pull_fn) = pull_cb else { set_last_error(SE_ERR_INVALID_ARG, "null pull callback"); return 3 };
+    if reject_nested_apply() { return 1; }
+    let h = h.unwrap();
+    let client = match h.engine().and_then(|e| SyncClient::from_engine(e, CallbackApplier { cb: apply_cb, user_data })) { Ok(c) => c, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+    let pull = |cursor: Option<String>| -> Result<(Vec<RemoteOp>, Option<String>), SyncError> {
+        let cursor_c = match cursor {
+            Some(c) => Some(CString::new(c).map_err(|_| SyncError::State("nul byte in cursor"))?),
+            None => None,
+        };
+        let mut page_ptr: *const c_char = std::ptr::null();
+        let rc = pull_fn(user_data, cursor_c.as_ref().map_or(std::ptr::null(), |c| c.as_ptr()), &mut page_ptr);
+        if rc != 0 { return Err(SyncError::Callback(rc)); }
+        let page_s = ptr_to_str(page_ptr).map_err(|_| SyncError::State("invalid page json"))?;
+        let page: PullPage = serde_json::from_str(page_s)?;
+        Ok((page.ops, page.cursor))
+    };
+    match client.pull_only(pull, max_pages) {
+        Ok(n) => {
+            if !out_count.is_null() { unsafe { *out_count = n; } }
+            clear_last_error();
+            0
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
+    }
+}
+
+/// Persist this install's origin id. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_set_own_origin(handle: *mut SyncConnHandle, origin: *const c_char) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    let origin = match ptr_to_str(origin) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid origin"); return 3 } };
+    if let Some(h) = h {
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+        match engine.set_own_origin(origin) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 } }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); 2 }
+}
+
+/// Get the stored origin id. Returns empty string if not set, null on error.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_get_own_origin(handle: *mut SyncConnHandle) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if let Some(h) = h {
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
+        match engine.own_origin() {
+            Ok(Some(s)) => { clear_last_error(); to_cstring_ptr(&s) },
+            Ok(None) => { clear_last_error(); to_cstring_ptr("") },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
         }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); std::ptr::null_mut() }
+}
+
+/// Like `sync_init_schema`, but with an explicit journal mode: "WAL", "DELETE", "TRUNCATE" or
+/// "PERSIST" (case-insensitive). Returns 0 on success, 3 for an unknown mode.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_init_schema_with_journal_mode(handle: *mut SyncConnHandle, mode: *const c_char) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    let mode = match ptr_to_str(mode).ok().and_then(JournalMode::from_name) { Some(m) => m, None => { set_last_error(SE_ERR_INVALID_ARG, "invalid journal mode"); return 3 } };
+    if let Some(h) = h {
+        match h.engine().and_then(|e| e.init_schema_with_journal_mode(mode)) { Ok(_) => { clear_last_error(); 0 }, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 } }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); 2 }
+}
 
-        // Record as applied
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        if let Err(e) = tx_box.execute(
-            "INSERT INTO applied_remote_ops(remote_id, applied_ms) VALUES(?1, ?2)",
-            rusqlite::params![&op.remote_id, now_ms],
-        ) { TLS_TX_PTR.with(|cell| *cell.borrow_mut() = std::ptr::null_mut()); set_last_error(1, &format!("{}", e)); return 1; }
+/// Register host SQL that migrates the schema to `version`; `sync_run_migrations` runs it in the
+/// same transaction that bumps the version. Steps live on the handle only, so register them after
+/// every `sync_open`. The first version must be at least 2; each later one must follow the previous
+/// without gaps.
+/// Returns 0 on success, 3 for an out-of-order version, 4 for transaction-control SQL.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_register_migration(handle: *mut SyncConnHandle, version: i32, sql: *const c_char) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    let sql = match ptr_to_str(sql) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid sql"); return 3 } };
+    let Some(h) = h else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    let expected_ok = match h.migrations.keys().next_back() { Some(last) => version == last + 1, None => version >= 2 };
+    if !expected_ok { set_last_error(SE_ERR_INVALID_ARG, "migration versions must start at 2 and have no gaps"); return 3; }
+    if has_tx_control(sql) { set_last_error(SE_ERR_TX_CONTROL, "transaction control statements are not allowed here"); return 4; }
+    h.migrations.insert(version, sql.to_string());
+    clear_last_error();
+    0
+}
+
+/// Highest HLC seen per origin as a JSON object `{origin: hlc}`. Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_hlc_frontier_json(handle: *mut SyncConnHandle) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if let Some(h) = h {
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return std::ptr::null_mut() } };
+        match engine.hlc_frontier() {
+            Ok(f) => match serde_json::to_string(&f) {
+                Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+                Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+            },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+        }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); std::ptr::null_mut() }
+}
+
+/// Mark local changes as acked by HLC. `hlcs_json` is a JSON array of HLC tokens; unknown tokens
+/// are ignored. Writes the number of changes marked to `out_count` (may be null). Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_confirm_pushed_by_hlc_json(handle: *mut SyncConnHandle, hlcs_json: *const c_char, out_count: *mut usize) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    let raw = match ptr_to_str(hlcs_json) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid hlcs_json"); return 3 } };
+    let hlcs: Vec<String> = match serde_json::from_str(raw) { Ok(v) => v, Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); return 3 } };
+    if let Some(h) = h {
+        let engine = match h.engine() { Ok(e) => e, Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); return 1 } };
+        let refs: Vec<&str> = hlcs.iter().map(String::as_str).collect();
+        match engine.confirm_pushed_by_hlc(&refs) {
+            Ok(n) => { if !out_count.is_null() { unsafe { *out_count = n; } } clear_last_error(); 0 },
+            Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 },
+        }
+    } else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); 2 }
+}
+
+/// Register `cb` to receive every remote cursor committed through this handle (`sync_set_remote_cursor`,
+/// `sync_pull_only`), e.g. to mirror it into host checkpoint storage. Pass null to unregister.
+/// Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_set_cursor_callback(handle: *mut SyncConnHandle, cb: SE_CursorCallback, user_data: *mut c_void) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    h.cursor_cb = cb;
+    h.cursor_user_data = user_data;
+    clear_last_error();
+    0
+}
+
+/// Register `cb` to learn about remote ops that lose to a pending local change when applied through
+/// any `sync_apply_remote_ops*` entry point or `sync_pull_only`. It runs inside the apply transaction,
+/// before the op is recorded as applied. Pass null to unregister. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_set_conflict_callback(handle: *mut SyncConnHandle, cb: SE_ConflictCallback, user_data: *mut c_void) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    h.conflict_cb = cb;
+    h.conflict_user_data = user_data;
+    clear_last_error();
+    0
+}
+
+/// Quarantine a remote op once the apply callback has failed on it `attempts` times (see
+/// `SyncEngine::with_max_apply_attempts`): it is recorded in `failed_remote_ops`, skipped, and the
+/// rest of its batch commits with outcome `SE_OUTCOME_QUARANTINED` for it. Earlier failures still
+/// abort the batch. Pass 0 to disable (the default). Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_set_max_apply_attempts(handle: *mut SyncConnHandle, attempts: u32) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    h.max_apply_attempts = (attempts > 0).then_some(attempts);
+    clear_last_error();
+    0
+}
+
+/// Write whether `sync_init_schema` has already completed on this database to `out_bool`.
+/// Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_is_initialized(handle: *mut SyncConnHandle, out_bool: *mut bool) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    if out_bool.is_null() { set_last_error(SE_ERR_INVALID_ARG, "out_bool is null"); return 3; }
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    match h.engine().and_then(|e| e.is_initialized()) {
+        Ok(v) => { unsafe { *out_bool = v; } clear_last_error(); 0 },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
     }
+}
 
-    // Clear TLS and commit
-    TLS_TX_PTR.with(|cell| *cell.borrow_mut() = std::ptr::null_mut());
-    match tx_box.commit() {
-        Ok(_) => { clear_last_error(); 0 },
-        Err(e) => { set_last_error(1, &format!("{}", e)); 1 }
+/// Copy the last error of the most recent call made with `handle` into `out`, whichever thread
+/// made that call. Returns 0 on success; `out->message` must be freed with `sync_string_free`.
+///
+/// Unlike `sync_last_error`, which is per thread and must be read on the thread that made the
+/// failing call, this slot survives hops between threads (e.g. Swift continuations resuming on
+/// another executor). The trade-off: it is shared, so with concurrent calls on one handle it
+/// holds the outcome of whichever call finished last.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_handle_last_error(handle: *mut SyncConnHandle, out: *mut SE_Error) -> c_int {
+    if out.is_null() { return 3; }
+    let Some(h) = (unsafe { handle.as_ref() }) else { return 2 };
+    let (code, msg) = match h.last_error.lock() { Ok(slot) => slot.clone(), Err(_) => return 1 };
+    unsafe { *out = SE_Error { code, message: to_cstring_ptr(&msg) }; }
+    0
+}
+
+/// Like `sync_apply_remote_ops`, writing one `SE_OUTCOME_*` code per op into `out_outcomes`
+/// (room for `len` codes), in input order.
+/// Returns 0 on success, or a failing callback's own code as `sync_apply_remote_ops` does.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_apply_remote_ops_outcomes(
+    handle: *mut SyncConnHandle,
+    ops: *const SE_Op,
+    len: usize,
+    cb: SE_ApplyCallback,
+    user_data: *mut c_void,
+    out_outcomes: *mut c_int,
+) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let h = unsafe { handle.as_mut() };
+    if h.is_none() { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2; }
+    if (ops.is_null() || out_outcomes.is_null()) && len > 0 { set_last_error(SE_ERR_INVALID_ARG, "ops or out_outcomes null but len > 0"); return 3; }
+    let h = h.unwrap();
+    let slice = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ops, len) } };
+    let mut parsed_ops: Vec<RemoteOp> = Vec::with_capacity(len);
+    for o in slice.iter() {
+        match op_from_se(o) { Ok(ro) => parsed_ops.push(ro), Err(e) => { set_last_error(SE_ERR_INVALID_ARG, &format!("{}", e)); return 3 } }
+    }
+    match apply_ops_with_callback(h, &parsed_ops, cb, user_data) {
+        Ok(outcomes) => {
+            let out = unsafe { std::slice::from_raw_parts_mut(out_outcomes, outcomes.len()) };
+            for (slot, o) in out.iter_mut().zip(outcomes) {
+                *slot = match o {
+                    OpOutcome::Applied => SE_OUTCOME_APPLIED,
+                    OpOutcome::Duplicate => SE_OUTCOME_DUPLICATE,
+                    OpOutcome::Conflict(Winner::Local) => SE_OUTCOME_CONFLICT_LOCAL,
+                    OpOutcome::Conflict(Winner::Remote) => SE_OUTCOME_CONFLICT_REMOTE,
+                    OpOutcome::Tombstoned => SE_OUTCOME_TOMBSTONED,
+                    OpOutcome::UnknownTable => SE_OUTCOME_UNKNOWN_TABLE,
+                    OpOutcome::Quarantined => SE_OUTCOME_QUARANTINED,
+                };
+            }
+            0
+        },
+        Err(rc) => rc,
     }
 }
 
+/// Build the push envelope `{"origin", "ops", "client_max_hlc"}` for up to `limit` pending ops as JSON.
+/// Requires the origin set with `sync_set_own_origin`. Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_build_push_envelope_json(handle: *mut SyncConnHandle, limit: i64) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.build_push_envelope(limit)) {
+        Ok(env) => match serde_json::to_string(&env) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// Tables with local changes as a sorted JSON array of names. `status` (nullable) limits it to tables
+/// with changes in that sync_status. Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_tracked_tables_json(handle: *mut SyncConnHandle, status: *const c_char) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let status = match opt_ptr_to_str(status) { Ok(s) => s, Err(_) => { set_last_error(SE_ERR_INVALID_ARG, "invalid status"); return std::ptr::null_mut() } };
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.tracked_tables(status)) {
+        Ok(tables) => match serde_json::to_string(&tables) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// Page counts and file sizes as a JSON `StorageReport` object (see `SyncEngine::storage_report`).
+/// Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_storage_report_json(handle: *mut SyncConnHandle) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.storage_report()) {
+        Ok(report) => match serde_json::to_string(&report) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// Checkpoint the WAL with one of the `SE_CHECKPOINT_*` modes (see `SyncEngine::checkpoint`).
+/// `out` may be null; otherwise it receives the frame counts. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_checkpoint(handle: *mut SyncConnHandle, mode: c_int, out: *mut SE_CheckpointResult) -> c_int {
+    let _mirror = ErrorMirror::new(handle);
+    let mode = match mode {
+        SE_CHECKPOINT_PASSIVE => CheckpointMode::Passive,
+        SE_CHECKPOINT_FULL => CheckpointMode::Full,
+        SE_CHECKPOINT_RESTART => CheckpointMode::Restart,
+        SE_CHECKPOINT_TRUNCATE => CheckpointMode::Truncate,
+        _ => { set_last_error(SE_ERR_INVALID_ARG, "invalid checkpoint mode"); return 3 }
+    };
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return 2 };
+    match h.engine().and_then(|e| e.checkpoint(mode)) {
+        Ok(r) => {
+            if let Some(out) = unsafe { out.as_mut() } {
+                *out = SE_CheckpointResult { busy: r.busy.into(), log_frames: r.log_frames, checkpointed_frames: r.checkpointed_frames };
+            }
+            clear_last_error();
+            0
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); 1 }
+    }
+}
+
+/// Number of local changes per status as a JSON object (see `SyncEngine::count_by_status`).
+/// Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_count_by_status_json(handle: *mut SyncConnHandle) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.count_by_status()) {
+        Ok(counts) => match serde_json::to_string(&counts) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+/// One local change, whatever its status, as a JSON `Change` object, or `null` if `change_id`
+/// does not exist (see `SyncEngine::get_change`). Null on error; free with `sync_string_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn sync_get_change_json(handle: *mut SyncConnHandle, change_id: i64) -> *mut c_char {
+    let _mirror = ErrorMirror::new(handle);
+    let Some(h) = (unsafe { handle.as_mut() }) else { set_last_error(SE_ERR_INVALID_ARG, "null handle"); return std::ptr::null_mut() };
+    match h.engine().and_then(|e| e.get_change(change_id)) {
+        Ok(change) => match serde_json::to_string(&change) {
+            Ok(s) => { clear_last_error(); to_cstring_ptr(&s) },
+            Err(e) => { set_last_error(SE_ERR_SERDE, &format!("{}", e)); std::ptr::null_mut() },
+        },
+        Err(e) => { set_last_error(e.ffi_code(), &format!("{}", e)); std::ptr::null_mut() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cs(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    /// Path of a fresh file database for `name`, removing leftovers of earlier runs.
+    fn temp_db(name: &str) -> (CString, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("se_ffi_{}_{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        (cs(path.to_str().unwrap()), path)
+    }
+
+    fn open_mem() -> *mut SyncConnHandle {
+        let handle = sync_open(cs(":memory:").as_ptr());
+        assert_eq!(sync_init_schema(handle), 0);
+        handle
+    }
+
+    fn conn<'a>(handle: *mut SyncConnHandle) -> &'a rusqlite::Connection {
+        unsafe { &(*handle).conn }
+    }
+
+    /// JSON array of `n` remote INSERTs into `trips`, `r0..`, from origin `server`.
+    fn ops_json(n: usize) -> CString {
+        let ops: Vec<serde_json::Value> = (0..n)
+            .map(|i| serde_json::json!({
+                "remote_id": format!("r{}", i), "table_name": "trips", "row_id": i.to_string(),
+                "op_type": "INSERT", "columns": null, "new_row": {"id": i}, "old_row": null,
+                "hlc": format!("{}-0-server", 1000 + i), "origin": "server",
+            }))
+            .collect();
+        cs(&serde_json::Value::from(ops).to_string())
+    }
+
+    fn applied_ids(handle: *mut SyncConnHandle) -> Vec<String> {
+        let mut stmt = conn(handle).prepare("SELECT remote_id FROM applied_remote_ops ORDER BY remote_id").unwrap();
+        stmt.query_map([], |r| r.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn committed_header_matches_cbindgen_output() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_crate(dir)
+            .with_config(config)
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+        let committed = std::fs::read_to_string(format!("{}/dist/sync_engine.h", dir)).unwrap();
+        assert_eq!(String::from_utf8(generated).unwrap(), committed, "dist/sync_engine.h is stale; run scripts/gen_header.sh");
+    }
+
+    #[test]
+    fn last_error_fills_se_error() {
+        assert_eq!(sync_init_schema(std::ptr::null_mut()), 2);
+        let mut err = SE_Error { code: SE_OK, message: std::ptr::null_mut() };
+        assert_eq!(sync_last_error(&mut err), 0);
+        assert_eq!(err.code, SE_ERR_INVALID_ARG);
+        assert_eq!(unsafe { CStr::from_ptr(err.message) }.to_str().unwrap(), "null handle");
+        sync_string_free(err.message);
+        assert_eq!(sync_abi_version(), SE_ABI_VERSION);
+    }
+
+    #[test]
+    fn busy_database_reports_se_err_busy() {
+        let (path, _) = temp_db("busy");
+        let opts = SE_OpenOptions { max_op_bytes: 0, busy_timeout_ms: 1, commit_retries: 0, retry_backoff_ms: 0 };
+        let writer = sync_open_with_opts(path.as_ptr(), &opts);
+        assert_eq!(sync_init_schema(writer), 0);
+        let holder = sync_open(path.as_ptr());
+        unsafe { &(*holder).conn }.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+        let id = sync_log_insert_fullrow(writer, cs("trips").as_ptr(), cs("1").as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr());
+
+        assert_eq!(id, -1);
+        assert_eq!(sync_last_error_code(), SE_ERR_BUSY);
+        unsafe { &(*holder).conn }.execute_batch("ROLLBACK").unwrap();
+        sync_close(holder);
+        sync_close(writer);
+    }
+
+    #[test]
+    fn constraint_violation_maps_to_se_err_constraint() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t(id INTEGER PRIMARY KEY); INSERT INTO t VALUES(1);").unwrap();
+        let err = conn.execute("INSERT INTO t VALUES(1)", []).unwrap_err();
+        assert_eq!(err.ffi_code(), SE_ERR_CONSTRAINT);
+        assert_eq!(SyncError::from(err).ffi_code(), SE_ERR_CONSTRAINT);
+    }
+
+    extern "C" fn skip_every_other(user_data: *mut c_void, _op: *const SE_Op) -> c_int {
+        let calls = unsafe { &mut *(user_data as *mut usize) };
+        *calls += 1;
+        if *calls % 2 == 0 { SE_APPLY_SKIP } else { SE_APPLY_OK }
+    }
+
+    extern "C" fn always_fail(_user_data: *mut c_void, _op: *const SE_Op) -> c_int { 42 }
+
+    #[test]
+    fn skipped_ops_are_still_recorded_as_applied() {
+        let handle = open_mem();
+        let mut calls = 0usize;
+
+        let rc = sync_apply_remote_ops_json(handle, ops_json(4).as_ptr(), Some(skip_every_other), &mut calls as *mut usize as *mut c_void);
+
+        assert_eq!(rc, 0);
+        assert_eq!(calls, 4);
+        assert_eq!(applied_ids(handle), ["r0", "r1", "r2", "r3"]);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn failing_callback_aborts_the_batch_with_its_code() {
+        let handle = open_mem();
+
+        let rc = sync_apply_remote_ops_json(handle, ops_json(3).as_ptr(), Some(always_fail), std::ptr::null_mut());
+
+        assert_eq!(rc, 42);
+        assert_eq!(sync_last_error_code(), SE_ERR_CALLBACK);
+        assert!(applied_ids(handle).is_empty());
+        sync_close(handle);
+    }
+
+    #[test]
+    fn invalid_table_name_reports_invalid_arg() {
+        let handle = open_mem();
+
+        let id = sync_log_delete(handle, cs("trips; --").as_ptr(), cs("1").as_ptr(), cs("dev").as_ptr());
+
+        assert_eq!(id, -1);
+        assert_eq!(sync_last_error_code(), SE_ERR_INVALID_ARG);
+        sync_close(handle);
+    }
+
+    /// Tries to commit the engine's transaction, then writes the op's row; records both return codes.
+    extern "C" fn commit_then_insert(user_data: *mut c_void, op: *const SE_Op) -> c_int {
+        let codes = unsafe { &mut *(user_data as *mut Vec<c_int>) };
+        codes.push(sync_tx_exec_current(cs("COMMIT;").as_ptr()));
+        codes.push(sync_last_error_code());
+        let row_id = unsafe { CStr::from_ptr((*op).row_id) }.to_str().unwrap();
+        codes.push(sync_tx_exec_current(cs(&format!("INSERT INTO trips(id) VALUES({})", row_id)).as_ptr()));
+        SE_APPLY_OK
+    }
+
+    #[test]
+    fn tx_control_from_the_callback_is_rejected() {
+        let handle = open_mem();
+        conn(handle).execute_batch("CREATE TABLE trips(id INTEGER PRIMARY KEY)").unwrap();
+        let mut codes: Vec<c_int> = Vec::new();
+
+        let rc = sync_apply_remote_ops_json(handle, ops_json(2).as_ptr(), Some(commit_then_insert), &mut codes as *mut Vec<c_int> as *mut c_void);
+
+        assert_eq!(rc, 0);
+        assert_eq!(codes, [4, SE_ERR_TX_CONTROL, 0, 4, SE_ERR_TX_CONTROL, 0]);
+        let rows: i64 = conn(handle).query_row("SELECT COUNT(*) FROM trips", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 2);
+        assert_eq!(applied_ids(handle), ["r0", "r1"]);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn tx_control_detection_skips_literals_and_comments() {
+        assert!(has_tx_control("COMMIT;"));
+        assert!(has_tx_control("insert into t values(1); rollback"));
+        assert!(has_tx_control("/* c */ begin immediate"));
+        assert!(!has_tx_control("INSERT INTO t(note) VALUES('commit; begin')"));
+        assert!(!has_tx_control("-- commit\nUPDATE t SET \"end\"=1"));
+        assert!(!has_tx_control("INSERT INTO savepoints VALUES(1)"));
+    }
+
+    #[test]
+    fn tx_control_detection_allows_trigger_bodies() {
+        let trigger = "CREATE TRIGGER trips_touch AFTER UPDATE ON trips BEGIN
+UPDATE trips SET note = CASE WHEN NEW.km > 0 THEN 'moved' ELSE 'still' END WHERE id = NEW.id;
+INSERT INTO log VALUES(NEW.id);
+END;";
+        assert!(!has_tx_control(trigger));
+        assert!(!has_tx_control(&format!("CREATE TABLE log(id TEXT);\n{trigger}\nCREATE INDEX log_id ON log(id);")));
+        assert!(has_tx_control(&format!("{trigger} COMMIT;")));
+        assert!(has_tx_control("CREATE TEMP TRIGGER t AFTER INSERT ON trips BEGIN SELECT 1; END; END"));
+
+        let handle = open_mem();
+        let tables = cs("CREATE TABLE trips(id TEXT PRIMARY KEY, km INTEGER, note TEXT); CREATE TABLE log(id TEXT)");
+        assert_eq!(sync_register_migration(handle, 2, tables.as_ptr()), 0);
+        assert_eq!(sync_register_migration(handle, 3, cs(trigger).as_ptr()), 0);
+        assert_eq!(sync_run_migrations(handle, 3), 0);
+        conn(handle).execute_batch("INSERT INTO trips VALUES('1', 0, NULL); UPDATE trips SET km = 5").unwrap();
+        let note: String = conn(handle).query_row("SELECT note FROM trips", [], |r| r.get(0)).unwrap();
+        assert_eq!(note, "moved");
+        sync_close(handle);
+    }
+
+    #[test]
+    fn registered_host_migrations_run_in_order() {
+        let handle = open_mem();
+        assert_eq!(sync_register_migration(handle, 2, cs("CREATE TABLE trips(id INTEGER PRIMARY KEY)").as_ptr()), 0);
+        assert_eq!(sync_register_migration(handle, 4, cs("SELECT 1").as_ptr()), 3, "gaps are rejected");
+        assert_eq!(sync_register_migration(handle, 3, cs("CREATE INDEX trips_id ON trips(id)").as_ptr()), 0);
+        assert_eq!(sync_register_migration(handle, 4, cs("COMMIT").as_ptr()), 4);
+
+        assert_eq!(sync_run_migrations(handle, 3), 0);
+
+        let objects: i64 = conn(handle)
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE name IN ('trips','trips_id')", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(objects, 2);
+        let mut version = 0;
+        assert_eq!(sync_get_schema_version(handle, &mut version), 0);
+        assert_eq!(version, 3);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn bytes_round_trip_through_out_params_and_free() {
+        for len in [0usize, 1, 7, 4096] {
+            let mut data = Vec::with_capacity(len * 2); // spare capacity must not leak into the free
+            data.extend((0..len).map(|i| i as u8));
+            let (mut ptr, mut out_len) = (std::ptr::null_mut(), usize::MAX);
+
+            bytes_to_out(data.clone(), &mut ptr, &mut out_len);
+
+            assert_eq!(out_len, len);
+            assert!(!ptr.is_null());
+            assert_eq!(unsafe { std::slice::from_raw_parts(ptr, out_len) }, &data[..]);
+            sync_bytes_free(ptr, out_len);
+        }
+        sync_bytes_free(std::ptr::null_mut(), 0);
+    }
+
+    #[test]
+    fn open_options_limit_op_size() {
+        let opts = SE_OpenOptions { max_op_bytes: 32, busy_timeout_ms: 0, commit_retries: 0, retry_backoff_ms: 0 };
+        let handle = sync_open_with_opts(cs(":memory:").as_ptr(), &opts);
+        assert_eq!(sync_init_schema(handle), 0);
+        let big = cs(&format!(r#"{{"notes":"{}"}}"#, "x".repeat(64)));
+
+        let id = sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs("1").as_ptr(), big.as_ptr(), cs("dev").as_ptr());
+
+        assert_eq!(id, -1);
+        let msg = sync_last_error_message();
+        assert!(unsafe { CStr::from_ptr(msg) }.to_str().unwrap().contains("op too large"));
+        sync_string_free(msg);
+        let n: i64 = conn(handle).query_row("SELECT COUNT(*) FROM local_changes", [], |r| r.get(0)).unwrap();
+        assert_eq!(n, 0);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn open_options_reject_a_spinning_contention_policy() {
+        let opts = SE_OpenOptions { max_op_bytes: 0, busy_timeout_ms: 0, commit_retries: 3, retry_backoff_ms: 0 };
+
+        assert!(sync_open_with_opts(cs(":memory:").as_ptr(), &opts).is_null());
+
+        assert_eq!(sync_last_error_code(), SE_ERR_INVALID_ARG);
+        let msg = sync_last_error_message();
+        assert!(unsafe { CStr::from_ptr(msg) }.to_str().unwrap().contains("will spin"));
+        sync_string_free(msg);
+    }
+
+    fn handle_error(handle: *mut SyncConnHandle) -> (c_int, String) {
+        let mut err = SE_Error { code: SE_OK, message: std::ptr::null_mut() };
+        assert_eq!(sync_handle_last_error(handle, &mut err), 0);
+        let msg = unsafe { CStr::from_ptr(err.message) }.to_str().unwrap().to_string();
+        sync_string_free(err.message);
+        (err.code, msg)
+    }
+
+    #[test]
+    fn handle_error_is_readable_from_another_thread() {
+        let handle = open_mem();
+        let addr = handle as usize;
+
+        let rc = std::thread::spawn(move || {
+            sync_apply_remote_ops_json(addr as *mut SyncConnHandle, ops_json(1).as_ptr(), Some(always_fail), std::ptr::null_mut())
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(rc, 42);
+        assert_eq!(sync_last_error_code(), SE_OK); // the thread-local slot stayed on the worker
+        assert_eq!(handle_error(handle).0, SE_ERR_CALLBACK);
 
+        // The next call through the handle, on yet another thread, clears the slot.
+        std::thread::spawn(move || assert_eq!(sync_init_schema(addr as *mut SyncConnHandle), 0)).join().unwrap();
+        assert_eq!(handle_error(handle), (SE_OK, String::new()));
+        sync_close(handle);
+    }
+
+    #[test]
+    fn malformed_remote_hlc_is_rejected_at_the_boundary() {
+        let handle = open_mem();
+        let json = ops_json(2).into_string().unwrap().replace("1001-0-server", "abc-1-x");
+
+        let rc = sync_apply_remote_ops_json(handle, cs(&json).as_ptr(), None, std::ptr::null_mut());
+
+        assert_eq!(rc, 3);
+        assert_eq!(sync_last_error_code(), SE_ERR_INVALID_ARG);
+        assert!(applied_ids(handle).is_empty());
+        sync_close(handle);
+    }
+
+    #[test]
+    fn truncate_checkpoint_empties_the_wal() {
+        let (path, file) = temp_db("checkpoint");
+        let handle = sync_open(path.as_ptr());
+        assert_eq!(sync_init_schema(handle), 0);
+        for i in 0..200 {
+            let id = sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs(&i.to_string()).as_ptr(), cs(r#"{"pad":"xxxxxxxxxxxxxxxx"}"#).as_ptr(), cs("dev").as_ptr());
+            assert!(id > 0);
+        }
+        let wal = format!("{}-wal", file.display());
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        let mut res = SE_CheckpointResult { busy: -1, log_frames: -1, checkpointed_frames: -1 };
+
+        assert_eq!(sync_checkpoint(handle, SE_CHECKPOINT_TRUNCATE, &mut res), 0);
+
+        assert_eq!(res.busy, 0);
+        assert_eq!(res.log_frames, res.checkpointed_frames);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(sync_checkpoint(handle, 42, std::ptr::null_mut()), 3);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn duplicate_hlc_reports_se_err_conflict() {
+        let handle = open_mem();
+        let future = chrono::Utc::now().timestamp_millis() + 60_000;
+        let rewind = || {
+            conn(handle)
+                .execute_batch(&format!(
+                    "INSERT OR REPLACE INTO sync_kv(k, v) VALUES('hlc_last_ms:dev', '{}'), ('hlc_last_ctr:dev', '0')",
+                    future
+                ))
+                .unwrap()
+        };
+        let log = |row_id: &str| sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs(row_id).as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr());
+        rewind();
+        assert!(log("1") > 0);
+
+        rewind(); // the clock state is rolled back, so the same token is issued again
+        assert_eq!(log("2"), -1);
+
+        assert_eq!(sync_last_error_code(), SE_ERR_CONFLICT);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn next_hlc_dominates_an_applied_future_remote_op() {
+        let handle = open_mem();
+        let future = chrono::Utc::now().timestamp_millis() + 3_600_000;
+        let json = ops_json(1).into_string().unwrap().replace("1000-0-server", &format!("{}-3-server", future));
+        let mut calls = 0usize;
+        let rc = sync_apply_remote_ops_json(handle, cs(&json).as_ptr(), Some(skip_every_other), &mut calls as *mut usize as *mut c_void);
+        assert_eq!(rc, 0);
+
+        let next = sync_next_hlc(handle, cs("dev").as_ptr());
+        let token = unsafe { CStr::from_ptr(next) }.to_str().unwrap().to_string();
+        sync_string_free(next);
+
+        let hlc = crate::merge::parse_hlc_strict(&token).unwrap();
+        assert!((hlc.millis, hlc.counter) > (i128::from(future), 3), "{}", token);
+        sync_close(handle);
+    }
+
+    extern "C" fn record_ids(user_data: *mut c_void, op: *const SE_Op) -> c_int {
+        let ids = unsafe { &mut *(user_data as *mut Vec<String>) };
+        ids.push(unsafe { CStr::from_ptr((*op).remote_id) }.to_str().unwrap().to_string());
+        SE_APPLY_OK
+    }
+
+    #[test]
+    fn json_ops_apply_once() {
+        let handle = open_mem();
+        let mut seen: Vec<String> = Vec::new();
+        let seen_ptr = &mut seen as *mut Vec<String> as *mut c_void;
+
+        assert_eq!(sync_apply_remote_ops_json(handle, ops_json(2).as_ptr(), Some(record_ids), seen_ptr), 0);
+        assert_eq!(sync_apply_remote_ops_json(handle, ops_json(2).as_ptr(), Some(record_ids), seen_ptr), 0);
+
+        assert_eq!(seen, ["r0", "r1"]);
+        assert_eq!(applied_ids(handle), ["r0", "r1"]);
+        assert_eq!(sync_apply_remote_ops_json(handle, cs("{not json").as_ptr(), Some(record_ids), seen_ptr), 3);
+        assert_eq!(sync_last_error_code(), SE_ERR_SERDE);
+        sync_close(handle);
+    }
+
+    /// Strings behind an `SE_Op` INSERT into `trips`: remote_id, table, row_id, new_row, hlc, origin.
+    struct OwnedOp([CString; 6]);
+
+    impl OwnedOp {
+        fn insert(remote_id: &str, row_id: &str, hlc: &str) -> Self {
+            OwnedOp([cs(remote_id), cs("trips"), cs(row_id), cs("{}"), cs(hlc), cs("server")])
+        }
+
+        fn op(&self) -> SE_Op {
+            let [remote_id, table_name, row_id, new_row, hlc, origin] = &self.0;
+            SE_Op {
+                remote_id: remote_id.as_ptr(),
+                table_name: table_name.as_ptr(),
+                row_id: row_id.as_ptr(),
+                op_type: SE_OP_INSERT,
+                columns_json: std::ptr::null(),
+                new_row_json: new_row.as_ptr(),
+                old_row_json: std::ptr::null(),
+                hlc: hlc.as_ptr(),
+                origin: origin.as_ptr(),
+            }
+        }
+    }
+
+    #[test]
+    fn applied_json_lists_only_newly_applied_ids() {
+        let handle = open_mem();
+        let owned = [
+            OwnedOp::insert("a", "1", "100-0-server"),
+            OwnedOp::insert("b", "2", "101-0-server"),
+            OwnedOp::insert("a", "1", "100-0-server"),
+        ];
+        let ops: Vec<SE_Op> = owned.iter().map(OwnedOp::op).collect();
+        let applied = |ops: &[SE_Op]| {
+            let mut seen: Vec<String> = Vec::new();
+            let json = sync_apply_remote_ops_applied_json(handle, ops.as_ptr(), ops.len(), Some(record_ids), &mut seen as *mut Vec<String> as *mut c_void);
+            let ids: Vec<String> = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+            sync_string_free(json);
+            ids
+        };
+
+        assert_eq!(applied(&ops[..1]), ["a"]);
+        assert_eq!(applied(&ops), ["b"], "a was applied by the first call, and repeats in the batch");
+        assert!(applied(&ops).is_empty());
+        sync_close(handle);
+    }
+
+    #[test]
+    fn applied_json_leaves_out_ops_that_lost_a_conflict() {
+        let handle = open_mem();
+        assert!(sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs("9").as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr()) > 0);
+        let owned = [OwnedOp::insert("win", "1", "100-0-server"), OwnedOp::insert("lose", "9", "101-0-server")];
+        let ops: Vec<SE_Op> = owned.iter().map(OwnedOp::op).collect();
+        let mut seen: Vec<String> = Vec::new();
+
+        let json = sync_apply_remote_ops_applied_json(handle, ops.as_ptr(), ops.len(), Some(record_ids), &mut seen as *mut _ as *mut c_void);
+        let ids: Vec<String> = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        sync_string_free(json);
+
+        assert_eq!(ids, ["win"]);
+        assert_eq!(ids, seen);
+        assert_eq!(applied_ids(handle).len(), 2);
+        sync_close(handle);
+    }
+
+    struct Nested {
+        handle: *mut SyncConnHandle,
+        rc: c_int,
+        code: c_int,
+    }
+
+    extern "C" fn apply_nested(user_data: *mut c_void, _op: *const SE_Op) -> c_int {
+        let nested = unsafe { &mut *(user_data as *mut Nested) };
+        nested.rc = sync_apply_remote_ops_json(nested.handle, ops_json(1).as_ptr(), None, std::ptr::null_mut());
+        nested.code = sync_last_error_code();
+        SE_APPLY_OK
+    }
+
+    #[test]
+    fn no_transaction_is_left_behind_by_apply() {
+        let handle = open_mem();
+        let mut nested = Nested { handle, rc: -1, code: -1 };
+
+        let rc = sync_apply_remote_ops_json(handle, ops_json(1).as_ptr(), Some(apply_nested), &mut nested as *mut Nested as *mut c_void);
+
+        assert_eq!(rc, 0);
+        assert_eq!((nested.rc, nested.code), (1, SE_ERR_NESTED_APPLY));
+        assert_eq!(sync_apply_remote_ops_json(handle, ops_json(2).as_ptr(), Some(always_fail), std::ptr::null_mut()), 42);
+
+        assert_eq!(sync_tx_exec_current(cs("CREATE TABLE t(a)").as_ptr()), 2);
+        let msg = sync_last_error_message();
+        assert_eq!(unsafe { CStr::from_ptr(msg) }.to_str().unwrap(), "no active transaction");
+        sync_string_free(msg);
+        sync_close(handle);
+    }
+
+    extern "C" fn record_conflict(user_data: *mut c_void, op: *const SE_Op, reason: c_int) {
+        let seen = unsafe { &mut *(user_data as *mut Vec<(String, c_int)>) };
+        seen.push((unsafe { CStr::from_ptr((*op).remote_id) }.to_str().unwrap().to_string(), reason));
+    }
+
+    #[test]
+    fn conflict_callback_reports_each_losing_op() {
+        let handle = open_mem();
+        let mut conflicts: Vec<(String, c_int)> = Vec::new();
+        let rc = sync_set_conflict_callback(handle, Some(record_conflict), &mut conflicts as *mut _ as *mut c_void);
+        assert_eq!(rc, 0);
+        assert!(sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs("0").as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr()) > 0);
+        assert!(sync_log_delete(handle, cs("trips").as_ptr(), cs("1").as_ptr(), cs("dev").as_ptr()) > 0);
+        let mut seen: Vec<String> = Vec::new();
+
+        let rc = sync_apply_remote_ops_json(handle, ops_json(3).as_ptr(), Some(record_ids), &mut seen as *mut _ as *mut c_void);
+
+        assert_eq!(rc, 0);
+        assert_eq!(conflicts, [("r0".to_string(), SE_CONFLICT_LOCAL_CHANGE), ("r1".to_string(), SE_CONFLICT_LOCAL_DELETE)]);
+        assert_eq!(seen, ["r2"]);
+        assert_eq!(applied_ids(handle).len(), 3, "losing ops are still recorded as applied");
+        sync_close(handle);
+    }
+
+    extern "C" fn fail_on_r1(_user_data: *mut c_void, op: *const SE_Op) -> c_int {
+        if unsafe { CStr::from_ptr((*op).remote_id) }.to_bytes() == b"r1" { 42 } else { SE_APPLY_OK }
+    }
+
+    #[test]
+    fn max_apply_attempts_quarantines_over_ffi() {
+        let handle = open_mem();
+        assert_eq!(sync_set_max_apply_attempts(handle, 2), 0);
+
+        assert_eq!(sync_apply_remote_ops_json(handle, ops_json(3).as_ptr(), Some(fail_on_r1), std::ptr::null_mut()), 42);
+        assert!(applied_ids(handle).is_empty());
+        assert_eq!(sync_apply_remote_ops_json(handle, ops_json(3).as_ptr(), Some(fail_on_r1), std::ptr::null_mut()), 0);
+
+        assert_eq!(applied_ids(handle).len(), 3);
+        let quarantined = SyncEngine::new(conn(handle)).unwrap().get_quarantined_ops().unwrap();
+        assert_eq!(quarantined.iter().map(|q| q.op.remote_id.as_str()).collect::<Vec<_>>(), ["r1"]);
+        sync_close(handle);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_pending_ops_match_the_json() {
+        let handle = open_mem();
+        for row in ["1", "2"] {
+            assert!(sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs(row).as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr()) > 0);
+        }
+        let (mut ptr, mut len) = (std::ptr::null_mut(), 0usize);
+
+        assert_eq!(sync_get_pending_ops_compressed(handle, 10, &mut ptr, &mut len), 0);
+        let decoded = zstd::decode_all(unsafe { std::slice::from_raw_parts(ptr, len) }).unwrap();
+        sync_bytes_free(ptr, len);
+
+        let json = sync_get_pending_ops_json(handle, 10);
+        let expected: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        sync_string_free(json);
+        let changes: Vec<crate::oplog::Change> = serde_json::from_slice(&decoded).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(serde_json::to_value(&changes).unwrap(), expected);
+        assert_eq!(sync_get_pending_ops_compressed(handle, 10, std::ptr::null_mut(), &mut len), 3);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn applies_only_unseen_ids_of_a_mixed_batch() {
+        let handle = open_mem();
+        let owned: Vec<OwnedOp> = (0..6)
+            .map(|i| OwnedOp::insert(&format!("r{i}"), &i.to_string(), &format!("{}-0-server", 1000 + i)))
+            .collect();
+        let ops: Vec<SE_Op> = owned.iter().map(OwnedOp::op).collect();
+        let odd: Vec<SE_Op> = owned.iter().skip(1).step_by(2).map(OwnedOp::op).collect();
+        assert_eq!(sync_apply_remote_ops(handle, odd.as_ptr(), odd.len(), None, std::ptr::null_mut()), 0);
+        let mut seen: Vec<String> = Vec::new();
+
+        let rc = sync_apply_remote_ops(handle, ops.as_ptr(), ops.len(), Some(record_ids), &mut seen as *mut _ as *mut c_void);
+
+        assert_eq!(rc, 0);
+        assert_eq!(seen, ["r0", "r2", "r4"]);
+        assert_eq!(applied_ids(handle).len(), 6);
+        sync_close(handle);
+    }
+
+    #[test]
+    fn outcomes_return_the_callback_code() {
+        let handle = open_mem();
+        assert!(sync_log_delete(handle, cs("trips").as_ptr(), cs("1").as_ptr(), cs("dev").as_ptr()) > 0);
+        let owned: Vec<OwnedOp> =
+            (0..2).map(|i| OwnedOp::insert(&format!("r{i}"), &i.to_string(), "1000-0-server")).collect();
+        let ops: Vec<SE_Op> = owned.iter().map(OwnedOp::op).collect();
+        let mut outcomes = [-1; 2];
+
+        let rc = sync_apply_remote_ops_outcomes(handle, ops.as_ptr(), ops.len(), Some(always_fail), std::ptr::null_mut(), outcomes.as_mut_ptr());
+        assert_eq!(rc, 42);
+        assert_eq!(sync_last_error_code(), SE_ERR_CALLBACK);
+
+        let rc = sync_apply_remote_ops_outcomes(handle, ops.as_ptr(), ops.len(), None, std::ptr::null_mut(), outcomes.as_mut_ptr());
+        assert_eq!(rc, 0);
+        assert_eq!(outcomes, [SE_OUTCOME_APPLIED, SE_OUTCOME_CONFLICT_LOCAL]);
+        sync_close(handle);
+    }
+
+    /// Pull pages for `sync_pull_only`: `first` for the initial (null) cursor, then an empty page.
+    struct Pages {
+        first: CString,
+        empty: CString,
+    }
+
+    extern "C" fn pull_pages(user_data: *mut c_void, cursor: *const c_char, out_page_json: *mut *const c_char) -> c_int {
+        let pages = unsafe { &*(user_data as *const Pages) };
+        let page = if cursor.is_null() { &pages.first } else { &pages.empty };
+        unsafe { *out_page_json = page.as_ptr() };
+        0
+    }
+
+    #[test]
+    fn pull_only_reports_conflicts_like_apply() {
+        let handle = open_mem();
+        let mut conflicts: Vec<(String, c_int)> = Vec::new();
+        assert_eq!(sync_set_conflict_callback(handle, Some(record_conflict), &mut conflicts as *mut _ as *mut c_void), 0);
+        assert!(sync_log_insert_fullrow(handle, cs("trips").as_ptr(), cs("0").as_ptr(), cs("{}").as_ptr(), cs("dev").as_ptr()) > 0);
+        assert!(sync_log_delete(handle, cs("trips").as_ptr(), cs("1").as_ptr(), cs("dev").as_ptr()) > 0);
+        let pages = Pages {
+            first: cs(&format!(r#"{{"ops":{},"cursor":"c1"}}"#, ops_json(3).to_str().unwrap())),
+            empty: cs(r#"{"ops":[],"cursor":"c1"}"#),
+        };
+        let mut pulled = 0usize;
+
+        let rc = sync_pull_only(handle, 5, Some(pull_pages), None, &pages as *const Pages as *mut c_void, &mut pulled);
+
+        assert_eq!(rc, 0);
+        assert_eq!(pulled, 3);
+        assert_eq!(conflicts, [("r0".to_string(), SE_CONFLICT_LOCAL_CHANGE), ("r1".to_string(), SE_CONFLICT_LOCAL_DELETE)]);
+        let pending: i64 = conn(handle)
+            .query_row("SELECT COUNT(*) FROM local_changes WHERE sync_status='pending'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(pending, 2, "the local changes that won are still to be pushed");
+        sync_close(handle);
+    }
+}
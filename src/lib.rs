@@ -2,7 +2,20 @@ pub mod oplog;
 pub mod sync;
 pub mod merge;
 pub mod ffi;
+pub mod applier;
 
-pub use oplog::{ApplyDomainOp, Change, RemoteOp, SyncEngine, SyncError};
+pub use oplog::{
+    ApplyDomainOp, ApplyPlan, ApplyReport, ApplyStats, CheckpointMode, CheckpointResult, Change,
+    Conflict, ConflictPolicy, ContentionPolicy, CursorOrder, DeletePolicy, JournalMode,
+    LocalChangeInput, Migration, OpOutcome, PendingOps, PlanAction, PushEnvelope, QuarantinedOp,
+    RemoteOp, StorageReport, SyncEngine, SyncError, Winner, decode_row_id, diff_columns,
+    encode_row_id, merge_remote_update,
+};
 pub use sync::SyncClient;
-pub use merge::{lww_merge_row, should_overwrite, parse_hlc};
\ No newline at end of file
+#[cfg(feature = "tokio")]
+pub use sync::sync_cycle_async;
+pub use applier::AppendOnlyApplier;
+pub use merge::{
+    Hlc, Resolution, TieBreak, canonical_json, lww_merge_fields, lww_merge_row, merge_row_tracked,
+    should_overwrite, should_overwrite_with, parse_hlc, parse_hlc_strict, resolve, values_equal,
+};
@@ -1,30 +1,445 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
 use serde_json::Value;
 
+use crate::oplog::{Change, OpType, SyncError};
+
+/// Parsed HLC token "millis-counter-origin".
+///
+/// Ordered by millis, then counter, then origin compared lexicographically as a final
+/// tiebreak, so two tokens only compare equal when they are identical.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hlc {
+    pub millis: i128,
+    pub counter: i64,
+    pub origin: String,
+}
+
+impl Hlc {
+    /// Parse leniently: a missing or non-numeric millis/counter reads as 0 and a missing
+    /// origin as "". The origin may itself contain '-'.
+    pub fn parse(s: &str) -> Self {
+        let mut parts = s.splitn(3, '-');
+        let millis = parts.next().unwrap_or("0").parse::<i128>().unwrap_or(0);
+        let counter = parts.next().unwrap_or("0").parse::<i64>().unwrap_or(0);
+        let origin = parts.next().unwrap_or("").to_string();
+        Hlc { millis, counter, origin }
+    }
+
+    /// Same as `Ord::cmp`, spelled out for call sites.
+    pub fn compare(&self, other: &Hlc) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl FromStr for Hlc {
+    type Err = SyncError;
+
+    /// Strict; see `parse_hlc_strict`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hlc_strict(s)
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.millis, self.counter, self.origin)
+    }
+}
+
+/// How two HLCs with the same millis and counter are ordered by their origin.
+/// Every peer syncing the same data must use the same direction, or they resolve the
+/// tie differently and diverge permanently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieBreak {
+    /// The lexicographically greater origin wins (the default).
+    #[default]
+    GreaterOrigin,
+    /// The lexicographically lesser origin wins.
+    LesserOrigin,
+}
+
+/// True if the local change wins over the remote one: higher `(millis, counter)`, then the
+/// greater origin. Same as `should_overwrite_with(.., TieBreak::GreaterOrigin)`.
 pub fn should_overwrite(local_hlc: &str, remote_hlc: &str) -> bool {
-    parse_hlc(local_hlc) > parse_hlc(remote_hlc)
+    should_overwrite_with(local_hlc, remote_hlc, TieBreak::GreaterOrigin)
 }
 
-pub fn parse_hlc(s: &str) -> (i128, i64, String) {
+/// Like `should_overwrite`, breaking `(millis, counter)` ties by origin per `tie`.
+/// Identical tokens never overwrite.
+pub fn should_overwrite_with(local_hlc: &str, remote_hlc: &str, tie: TieBreak) -> bool {
+    let (l, r) = (Hlc::parse(local_hlc), Hlc::parse(remote_hlc));
+    let by_origin = match tie {
+        TieBreak::GreaterOrigin => l.origin.cmp(&r.origin),
+        TieBreak::LesserOrigin => r.origin.cmp(&l.origin),
+    };
+    (l.millis, l.counter).cmp(&(r.millis, r.counter)).then(by_origin).is_gt()
+}
+
+/// Outcome of `resolve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Local,
+    Remote,
+    /// Identical tokens: both sides hold the same write. Callers must treat this as a no-op.
+    Equal,
+}
+
+/// Which of two HLC tokens wins: higher `(millis, counter)`, then the greater origin.
+/// Symmetric, so two nodes resolving each other's ops agree: if node A gets `Local` for
+/// `(a, b)`, node B gets `Remote` for `(b, a)`.
+pub fn resolve(local_hlc: &str, remote_hlc: &str) -> Resolution {
+    match Hlc::parse(local_hlc).cmp(&Hlc::parse(remote_hlc)) {
+        Ordering::Greater => Resolution::Local,
+        Ordering::Less => Resolution::Remote,
+        Ordering::Equal => Resolution::Equal,
+    }
+}
+
+/// Parse an HLC token, rejecting anything that is not `millis-counter-origin` with integer
+/// `millis` and `counter` and a non-empty origin (which may itself contain '-').
+/// Use this wherever a token comes from outside the engine.
+pub fn parse_hlc_strict(s: &str) -> Result<Hlc, SyncError> {
     let mut parts = s.splitn(3, '-');
-    let ms = parts.next().unwrap_or("0").parse::<i128>().unwrap_or(0);
-    let ctr = parts.next().unwrap_or("0").parse::<i64>().unwrap_or(0);
-    let origin = parts.next().unwrap_or("").to_string();
-    (ms, ctr, origin)
+    let (Some(ms), Some(ctr), Some(origin)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(SyncError::State("hlc must be millis-counter-origin"));
+    };
+    let millis = ms.parse::<i128>().map_err(|_| SyncError::State("hlc millis is not an integer"))?;
+    let counter = ctr.parse::<i64>().map_err(|_| SyncError::State("hlc counter is not an integer"))?;
+    if origin.is_empty() {
+        return Err(SyncError::State("hlc origin is empty"));
+    }
+    Ok(Hlc { millis, counter, origin: origin.to_string() })
+}
+
+/// Split an HLC token into `(millis, counter, origin)`; see `Hlc::parse`.
+/// Lenient, for tokens the engine wrote itself; see `parse_hlc_strict` for untrusted input.
+pub fn parse_hlc(s: &str) -> (i128, i64, String) {
+    let h = Hlc::parse(s);
+    (h.millis, h.counter, h.origin)
+}
+
+/// JSON equality that compares numbers by value, so `1` and `1.0` are equal.
+pub fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x == y || x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(a, b)| values_equal(a, b))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter().all(|(k, v)| y.get(k).is_some_and(|w| values_equal(v, w)))
+        }
+        _ => a == b,
+    }
+}
+
+/// Compact JSON with object keys sorted at every level, so equal values always serialize to
+/// the same bytes whatever order their keys were built in.
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(v, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, k) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(k.clone()).to_string());
+                out.push(':');
+                write_canonical(&map[k], out);
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
 }
 
+/// Apply `remote` over `local`. Without `changed_fields` the remote row replaces the local one.
+/// With it, the list is authoritative: each listed field takes the remote value, an explicit
+/// `null` included, and a listed field missing from `remote` is removed. Unlisted fields keep
+/// their local value. A `local` that is not a JSON object is replaced by `remote`.
 pub fn lww_merge_row(local: &Value, remote: &Value, changed_fields: Option<&[&str]>) -> Value {
     match changed_fields {
         None => remote.clone(),
+        Some(_) if !local.is_object() => remote.clone(),
         Some(fields) => {
             let mut out = local.clone();
-            for k in fields {
-                if let Some(v) = remote.get(*k) {
-                    if let Some(obj) = out.as_object_mut() {
-                        obj.insert((*k).to_string(), v.clone());
+            if let Some(obj) = out.as_object_mut() {
+                for k in fields {
+                    match remote.get(*k) {
+                        Some(v) => {
+                            obj.insert((*k).to_string(), v.clone());
+                        }
+                        None => {
+                            obj.remove(*k);
+                        }
                     }
                 }
             }
             out
         }
     }
-}
\ No newline at end of file
+}
+
+/// Field-level last-writer-wins: for every field present on either side, keep the value
+/// whose field HLC is greater, and return the merged row with the merged field HLC map.
+/// A field without an HLC on one side loses to the other side; with neither, the local
+/// value is kept when there is one. If the winning side lacks the field, it is absent from the result.
+///
+/// Callers must persist the per-field HLCs next to the row, e.g. a JSON object column such
+/// as `_field_hlcs TEXT` on the domain table, updated with the returned map on every merge
+/// and, for local edits, set to the change's HLC for each field in `columns`.
+pub fn lww_merge_fields(
+    local: &Value,
+    local_field_hlcs: &BTreeMap<String, String>,
+    remote: &Value,
+    remote_field_hlcs: &BTreeMap<String, String>,
+) -> (Value, BTreeMap<String, String>) {
+    let empty = serde_json::Map::new();
+    let l = local.as_object().unwrap_or(&empty);
+    let r = remote.as_object().unwrap_or(&empty);
+    let mut fields: Vec<&String> = l.keys().chain(r.keys()).collect();
+    fields.sort();
+    fields.dedup();
+
+    let mut row = serde_json::Map::new();
+    let mut hlcs = BTreeMap::new();
+    for k in fields {
+        let remote_wins = match (local_field_hlcs.get(k), remote_field_hlcs.get(k)) {
+            (Some(lh), Some(rh)) => Hlc::parse(rh) > Hlc::parse(lh),
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (None, None) => !l.contains_key(k),
+        };
+        let (src, src_hlcs) = if remote_wins { (r, remote_field_hlcs) } else { (l, local_field_hlcs) };
+        if let Some(v) = src.get(k) {
+            row.insert(k.clone(), v.clone());
+        }
+        if let Some(h) = src_hlcs.get(k) {
+            hlcs.insert(k.clone(), h.clone());
+        }
+    }
+    (Value::Object(row), hlcs)
+}
+
+/// Like `lww_merge_row`, but also return the names of the fields whose value in the
+/// merged row differs from `local` (fields listed in `changed_fields` whose remote
+/// value equals the local one are not reported).
+pub fn merge_row_tracked(
+    local: &Value,
+    remote: &Value,
+    changed_fields: Option<&[&str]>,
+) -> (Value, Vec<String>) {
+    let merged = lww_merge_row(local, remote, changed_fields);
+    let mut changed = Vec::new();
+    if let Some(obj) = merged.as_object() {
+        for (k, v) in obj {
+            if local.get(k) != Some(v) {
+                changed.push(k.clone());
+            }
+        }
+    }
+    if let Some(obj) = local.as_object() {
+        for k in obj.keys() {
+            if merged.get(k).is_none() {
+                changed.push(k.clone());
+            }
+        }
+    }
+    (merged, changed)
+}
+
+/// Fold a key's changes, in the order given, into the row they produce.
+/// Returns `None` when the final state is a tombstone (or nothing was ever inserted).
+/// UPDATEs with `columns` copy only those fields from `new_row`; without `columns`,
+/// every field of `new_row` is taken.
+pub fn replay_changes(changes: &[Change]) -> Option<Value> {
+    let mut state: Option<Value> = None;
+    for ch in changes {
+        match ch.op_type {
+            OpType::Insert => state = ch.new_row.clone(),
+            OpType::Delete => state = None,
+            OpType::Update => {
+                let Some(new_row) = &ch.new_row else { continue };
+                let cols: Option<Vec<&str>> = ch
+                    .columns
+                    .as_ref()
+                    .and_then(|c| c.as_array())
+                    .map(|a| a.iter().filter_map(|c| c.as_str()).collect());
+                let base = state.take().unwrap_or_else(|| Value::Object(Default::default()));
+                let keys: Vec<&str> = match &cols {
+                    Some(cols) => cols.clone(),
+                    None => new_row
+                        .as_object()
+                        .map(|o| o.keys().map(|k| k.as_str()).collect())
+                        .unwrap_or_default(),
+                };
+                state = Some(lww_merge_row(&base, new_row, Some(&keys)));
+            }
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_row_tracked_reports_only_fields_that_changed() {
+        let local = json!({"id": 1, "name": "a", "category": "x", "note": "n"});
+        let remote = json!({"id": 1, "name": "a", "category": "y", "note": "m"});
+
+        let (merged, changed) = merge_row_tracked(&local, &remote, Some(&["name", "category"]));
+
+        assert_eq!(merged, json!({"id": 1, "name": "a", "category": "y", "note": "n"}));
+        assert_eq!(changed, vec!["category".to_string()]);
+    }
+
+    #[test]
+    fn merge_row_tracked_reports_removed_fields() {
+        let local = json!({"id": 1, "name": "a", "note": "n"});
+        let remote = json!({"id": 1, "name": "b"});
+
+        let (merged, mut changed) = merge_row_tracked(&local, &remote, None);
+
+        changed.sort();
+        assert_eq!(merged, remote);
+        assert_eq!(changed, vec!["name".to_string(), "note".to_string()]);
+    }
+
+    #[test]
+    fn listed_field_with_explicit_null_is_cleared() {
+        let local = json!({"id": 1, "note": "n", "name": "a"});
+        let remote = json!({"id": 1, "note": null, "name": "ignored"});
+
+        let merged = lww_merge_row(&local, &remote, Some(&["note"]));
+
+        assert_eq!(merged, json!({"id": 1, "note": null, "name": "a"}));
+    }
+
+    #[test]
+    fn listed_field_missing_from_remote_is_removed() {
+        let local = json!({"id": 1, "note": "n", "name": "a"});
+        let remote = json!({"id": 1});
+
+        assert_eq!(lww_merge_row(&local, &remote, Some(&["note"])), json!({"id": 1, "name": "a"}));
+        // Unlisted, the missing key means "unchanged".
+        assert_eq!(lww_merge_row(&local, &remote, Some(&[])), local);
+    }
+
+    #[test]
+    fn peers_with_the_same_tie_break_converge() {
+        let (a, b) = ("100-3-alpha", "100-3-beta");
+        let winner = |local: &'static str, remote: &'static str, tie| {
+            if should_overwrite_with(local, remote, tie) { local } else { remote }
+        };
+
+        assert_eq!(TieBreak::default(), TieBreak::GreaterOrigin);
+        for (tie, expected) in [(TieBreak::GreaterOrigin, b), (TieBreak::LesserOrigin, a)] {
+            // Each peer sees its own write as local and the other's as remote.
+            assert_eq!(winner(a, b, tie), expected, "{:?} on alpha", tie);
+            assert_eq!(winner(b, a, tie), expected, "{:?} on beta", tie);
+        }
+        // The origin only breaks ties; a later counter wins either way.
+        assert!(should_overwrite_with("100-4-alpha", b, TieBreak::GreaterOrigin));
+        assert!(!should_overwrite_with(a, a, TieBreak::LesserOrigin));
+    }
+
+    #[test]
+    fn hlc_orders_by_millis_counter_then_origin() {
+        let h = |s: &str| s.parse::<Hlc>().unwrap();
+
+        assert_eq!(h("100-1-a").compare(&h("100-2-a")), Ordering::Less);
+        assert!(h("101-0-a") > h("100-9-z"));
+        // Equal millis and counter: the origin breaks the tie lexicographically.
+        assert!(h("100-1-b") > h("100-1-a"));
+        assert_eq!(h("100-1-a").compare(&h("100-1-a")), Ordering::Equal);
+
+        assert_eq!(h("100-1-node-a").to_string(), "100-1-node-a");
+        assert_eq!(parse_hlc("100-1-node-a"), (100, 1, "node-a".to_string()));
+    }
+
+    #[test]
+    fn strict_parser_rejects_malformed_tokens() {
+        assert_eq!(
+            parse_hlc_strict("123-4-node").unwrap(),
+            Hlc { millis: 123, counter: 4, origin: "node".to_string() }
+        );
+        assert!(parse_hlc_strict("abc-1-x").is_err());
+        assert!(parse_hlc_strict("1-abc-x").is_err());
+        assert!(parse_hlc_strict("1-2").is_err());
+        assert!(parse_hlc_strict("1-2-").is_err());
+        // The lenient parser lets the same garbage through as zeros.
+        assert_eq!(parse_hlc("abc-1-x"), (0, 1, "x".to_string()));
+    }
+
+    #[test]
+    fn field_level_merge_keeps_the_newest_value_per_field() {
+        let hlcs = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let local = json!({"name": "local name", "km": 10, "note": "only local"});
+        let remote = json!({"name": "remote name", "km": 12, "tag": "only remote"});
+        let local_hlcs = hlcs(&[("name", "200-0-dev"), ("km", "100-0-dev"), ("note", "50-0-dev")]);
+        let remote_hlcs = hlcs(&[("name", "150-0-srv"), ("km", "180-0-srv"), ("tag", "60-0-srv")]);
+
+        let (row, merged_hlcs) = lww_merge_fields(&local, &local_hlcs, &remote, &remote_hlcs);
+
+        assert_eq!(row, json!({"name": "local name", "km": 12, "note": "only local", "tag": "only remote"}));
+        assert_eq!(
+            merged_hlcs,
+            hlcs(&[("name", "200-0-dev"), ("km", "180-0-srv"), ("note", "50-0-dev"), ("tag", "60-0-srv")])
+        );
+    }
+
+    #[test]
+    fn lww_merge_row_takes_listed_fields_from_remote() {
+        let local = json!({"id": 1, "name": "a", "category": "x", "note": "n"});
+        let remote = json!({"id": 1, "name": "b", "category": "y", "note": "m"});
+
+        let merged = lww_merge_row(&local, &remote, Some(&["name", "category"]));
+
+        assert_eq!(merged, json!({"id": 1, "name": "b", "category": "y", "note": "n"}));
+        assert_eq!(lww_merge_row(&local, &remote, None), remote);
+        // A non-object local can't be merged into; the remote row replaces it.
+        assert_eq!(lww_merge_row(&json!(null), &remote, Some(&["name"])), remote);
+    }
+
+    #[test]
+    fn resolve_is_symmetric_between_peers() {
+        let pairs = [("100-0-a", "100-0-b"), ("100-1-a", "100-0-b"), ("99-9-z", "100-0-a")];
+        for (x, y) in pairs {
+            let on_x = resolve(x, y);
+            let on_y = resolve(y, x);
+            let winner_x = if on_x == Resolution::Local { x } else { y };
+            let winner_y = if on_y == Resolution::Local { y } else { x };
+            assert_ne!(on_x, on_y, "{} vs {}", x, y);
+            assert_eq!(winner_x, winner_y, "{} vs {}", x, y);
+        }
+        assert_eq!(resolve("100-0-b", "100-0-a"), Resolution::Local, "higher origin wins ties");
+        assert_eq!(resolve("100-0-a", "100-0-a"), Resolution::Equal);
+    }
+}
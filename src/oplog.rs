@@ -1,13 +1,65 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::BufRead;
+
 use chrono::Utc;
 use rusqlite::{Connection, OptionalExtension, Transaction, params};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
+use crate::merge::{Hlc, TieBreak, canonical_json, lww_merge_row, parse_hlc, parse_hlc_strict, should_overwrite, should_overwrite_with, values_equal};
+
+/// Version of the engine's own metadata tables, stored in `sync_kv` as `engine_schema`
+/// once `init_schema` completes. Bump it whenever `init_schema` creates or upgrades something new.
+const ENGINE_SCHEMA: i64 = 7;
+
+/// How the engine waits out lock contention with other connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ContentionPolicy {
+    /// SQLite `busy_timeout`: how long a statement waits for a lock before failing with BUSY.
+    pub busy_timeout_ms: u32,
+    /// Extra attempts at a COMMIT that failed with BUSY/LOCKED before giving up.
+    pub commit_retries: u32,
+    /// Pause between commit attempts.
+    pub retry_backoff_ms: u32,
+}
+
+impl ContentionPolicy {
+    /// A reason to reconsider the policy, if any: a zero timeout with no retries fails on the
+    /// first contention, and retries without any backoff or timeout just spin.
+    pub fn warning(&self) -> Option<&'static str> {
+        match (self.busy_timeout_ms, self.commit_retries, self.retry_backoff_ms) {
+            (0, 0, _) => Some("no busy timeout and no commit retries: any contention fails immediately"),
+            (0, r, 0) if r > 0 => Some("commit retries without backoff or busy timeout will spin"),
+            _ => None,
+        }
+    }
+}
+
+/// How far, in millis, the wall clock may lag the last issued HLC before `next_hlc` fails.
+pub const DEFAULT_MAX_DRIFT_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Age, in millis, past which `SyncEngine::prune_applied_ops_default` forgets applied remote ops.
+pub const DEFAULT_APPLIED_OPS_TTL_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+/// Highest HLC counter issued within one millisecond; the next token moves to the next millisecond.
+pub const HLC_COUNTER_MAX: i64 = 0xFFFF;
+
+/// Feed used by engines that don't call `SyncEngine::with_feed`.
+pub const DEFAULT_FEED: &str = "";
+
 /// Logical operation type captured in the oplog.
+/// Serialized as the SQL form ("INSERT"/"UPDATE"/"DELETE"); the old "Insert"/"insert"
+/// spellings are still accepted when deserializing.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum OpType {
+    #[serde(alias = "Insert", alias = "insert")]
     Insert,
+    #[serde(alias = "Update", alias = "update")]
     Update,
+    #[serde(alias = "Delete", alias = "delete")]
     Delete,
 }
 
@@ -21,6 +73,74 @@ impl OpType {
     }
 }
 
+/// SQLite journal mode applied by `init_schema_with_journal_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JournalMode {
+    #[default]
+    Wal,
+    Delete,
+    Truncate,
+    Persist,
+}
+
+impl JournalMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Wal => "WAL",
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+        }
+    }
+
+    /// Parse a mode name, case-insensitively. Returns `None` outside the supported set.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [JournalMode::Wal, JournalMode::Delete, JournalMode::Truncate, JournalMode::Persist]
+            .into_iter()
+            .find(|m| m.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Mode of `SyncEngine::checkpoint`, as for `PRAGMA wal_checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckpointMode {
+    #[default]
+    Passive,
+    Full,
+    Restart,
+    Truncate,
+}
+
+impl CheckpointMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckpointMode::Passive => "PASSIVE",
+            CheckpointMode::Full => "FULL",
+            CheckpointMode::Restart => "RESTART",
+            CheckpointMode::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+/// Frame counts returned by `SyncEngine::checkpoint`. Both counts are -1 when the
+/// database is not in WAL mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointResult {
+    pub busy: bool,             // a FULL/RESTART/TRUNCATE checkpoint could not finish
+    pub log_frames: i64,        // frames in the WAL
+    pub checkpointed_frames: i64,
+}
+
+/// How `SyncEngine::set_remote_cursor_checked` compares cursors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CursorOrder {
+    /// Compare as strings (e.g. zero-padded or ISO-8601 cursors).
+    #[default]
+    Lexical,
+    /// Compare as integers; a cursor that does not parse as one is rejected.
+    Numeric,
+}
+
 /// Local change recorded by the client oplog.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Change {
@@ -34,6 +154,616 @@ pub struct Change {
     pub hlc: String,                        // hybrid/logical clock token
     pub origin: String,                     // stable client id
     pub sync_status: String,                // 'pending' | 'pushed' | 'acked'
+    #[serde(default)]
+    pub content_hash: Option<String>,       // hex SHA-256 of canonical_json(new_row); None without new_row
+    #[serde(default)]
+    pub group_id: Option<i64>,              // shared by changes logged together by `log_batch`
+}
+
+impl Change {
+    pub fn is_insert(&self) -> bool {
+        self.op_type == OpType::Insert
+    }
+
+    pub fn is_update(&self) -> bool {
+        self.op_type == OpType::Update
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.op_type == OpType::Delete
+    }
+
+    /// Field `key` of `new_row`; `None` when absent or when there is no `new_row` (DELETE).
+    pub fn new_field(&self, key: &str) -> Option<&serde_json::Value> {
+        self.new_row.as_ref()?.get(key)
+    }
+
+    /// Field `key` of `old_row`; `None` when absent or when there is no `old_row`.
+    pub fn old_field(&self, key: &str) -> Option<&serde_json::Value> {
+        self.old_row.as_ref()?.get(key)
+    }
+
+    /// Column names listed in `columns`. Non-string entries are skipped; a missing or
+    /// non-array `columns` yields an empty list.
+    pub fn changed_columns(&self) -> Vec<&str> {
+        self.columns
+            .as_ref()
+            .and_then(|c| c.as_array())
+            .map(|a| a.iter().filter_map(|c| c.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    /// This change as a `RemoteOp` with `remote_id` `local:<change_id>`, so an applier can
+    /// replay it (see `SyncEngine::replay_local`).
+    pub fn to_remote_op(&self) -> RemoteOp {
+        RemoteOp {
+            remote_id: format!("local:{}", self.change_id),
+            table_name: self.table_name.clone(),
+            row_id: self.row_id.clone(),
+            op_type: self.op_type,
+            columns: self.columns.clone(),
+            new_row: self.new_row.clone(),
+            old_row: self.old_row.clone(),
+            hlc: self.hlc.clone(),
+            origin: self.origin.clone(),
+        }
+    }
+}
+
+/// True for a plain SQL identifier: `[A-Za-z_][A-Za-z0-9_]*`.
+pub(crate) fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Bind a JSON value as the closest SQLite type; arrays and objects are stored as JSON text.
+pub(crate) fn json_to_sql(v: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value as Sql;
+    match v {
+        serde_json::Value::Null => Sql::Null,
+        serde_json::Value::Bool(b) => Sql::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Sql::Integer(i),
+            None => Sql::Real(n.as_f64().unwrap_or(0.0)),
+        },
+        serde_json::Value::String(s) => Sql::Text(s.clone()),
+        other => Sql::Text(other.to_string()),
+    }
+}
+
+/// Convert a SQLite value to JSON; blobs become arrays of byte values.
+fn sql_to_json(v: rusqlite::types::ValueRef<'_>) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+    match v {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into_owned().into(),
+        ValueRef::Blob(b) => b.iter().map(|&x| serde_json::Value::from(x)).collect(),
+    }
+}
+
+/// `INSERT ... ON CONFLICT(pk_col) DO UPDATE` the fields of `row` into `table_name`.
+/// `table_name` and `pk_col` must already be validated identifiers; the keys of `row` are checked here.
+fn upsert_row(
+    conn: &Connection,
+    table_name: &str,
+    pk_col: &str,
+    row: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), SyncError> {
+    if !row.keys().all(|k| is_identifier(k)) {
+        return Err(SyncError::State("column is not a valid identifier"));
+    }
+    let cols: Vec<&str> = row.keys().map(String::as_str).collect();
+    let placeholders: Vec<String> = (1..=cols.len()).map(|i| format!("?{}", i)).collect();
+    let updates: Vec<String> = cols
+        .iter()
+        .filter(|c| **c != pk_col)
+        .map(|c| format!("{0}=excluded.{0}", c))
+        .collect();
+    let on_conflict = if updates.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET {}", updates.join(","))
+    };
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT({}) {}",
+        table_name,
+        cols.join(","),
+        placeholders.join(","),
+        pk_col,
+        on_conflict
+    );
+    let values: Vec<rusqlite::types::Value> = row.values().map(json_to_sql).collect();
+    conn.execute(&sql, rusqlite::params_from_iter(values))?;
+    Ok(())
+}
+
+/// Pending ops in the shape the server accepts on push; see `SyncEngine::build_push_envelope`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushEnvelope {
+    pub origin: String,
+    pub ops: Vec<Change>,
+    pub client_max_hlc: Option<String>, // HLC of the newest change logged by `origin`
+    #[serde(skip)]
+    pub change_ids: Vec<i64>, // ids of `ops`, to mark pushed once the server accepts them
+}
+
+/// Sorted names of the fields whose value differs (per `merge::values_equal`) between two
+/// JSON objects, including fields present on one side only. A non-object reads as `{}`.
+pub fn diff_columns(old_row: &serde_json::Value, new_row: &serde_json::Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let old = old_row.as_object().unwrap_or(&empty);
+    let new = new_row.as_object().unwrap_or(&empty);
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter(|k| match (old.get(*k), new.get(*k)) {
+            (Some(a), Some(b)) => !values_equal(a, b),
+            _ => true,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Separator between the parts of a composite `row_id`; see `encode_row_id`.
+pub const ROW_ID_SEPARATOR: char = '|';
+
+/// Build a `row_id` for a composite primary key: `parts` joined by `ROW_ID_SEPARATOR`, with
+/// `\` and the separator inside a part escaped by a backslash, so `decode_row_id` always
+/// recovers the original parts, empty ones included. A single part containing neither
+/// character encodes to itself. An empty slice encodes like `[""]`.
+pub fn encode_row_id(parts: &[&str]) -> String {
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push(ROW_ID_SEPARATOR);
+        }
+        for c in part.chars() {
+            if c == '\\' || c == ROW_ID_SEPARATOR {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Split a `row_id` built by `encode_row_id` back into its parts. Always returns at least one
+/// part; a trailing lone backslash is kept literally.
+pub fn decode_row_id(s: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let cur = parts.last_mut().expect("never empty");
+        match c {
+            '\\' => cur.push(chars.next().unwrap_or('\\')),
+            ROW_ID_SEPARATOR => parts.push(String::new()),
+            _ => cur.push(c),
+        }
+    }
+    parts
+}
+
+/// Reject change keys that would produce unusable oplog entries. `table_name` must be
+/// a plain SQL identifier (`[A-Za-z_][A-Za-z0-9_]*`) since appliers interpolate it.
+pub(crate) fn validate_change_key(table_name: &str, row_id: &str) -> Result<(), SyncError> {
+    if table_name.trim().is_empty() {
+        return Err(SyncError::Validation("table_name is empty".to_string()));
+    }
+    if !is_identifier(table_name) {
+        return Err(SyncError::Validation("table_name is not a valid identifier".to_string()));
+    }
+    if row_id.trim().is_empty() {
+        return Err(SyncError::Validation("row_id is empty".to_string()));
+    }
+    Ok(())
+}
+
+/// Map a `local_changes` row selected as
+/// `change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id`.
+fn change_from_row(r: &rusqlite::Row<'_>) -> rusqlite::Result<Change> {
+    let op_str: String = r.get(3)?;
+    let to_json = |idx| -> rusqlite::Result<Option<serde_json::Value>> {
+        let s: Option<String> = r.get(idx)?;
+        Ok(s.map(|raw| {
+            serde_json::from_str::<serde_json::Value>(&raw).unwrap_or(serde_json::Value::Null)
+        }))
+    };
+
+    let new_row = to_json(5)?;
+    Ok(Change {
+        change_id: r.get(0)?,
+        table_name: r.get(1)?,
+        row_id: r.get(2)?,
+        op_type: match op_str.as_str() {
+            "INSERT" => OpType::Insert,
+            "UPDATE" => OpType::Update,
+            "DELETE" => OpType::Delete,
+            _ => OpType::Update,
+        },
+        columns: to_json(4)?,
+        old_row: to_json(6)?,
+        hlc: r.get(7)?,
+        origin: r.get(8)?,
+        sync_status: r.get(9)?,
+        group_id: r.get(10)?,
+        content_hash: content_hash(new_row.as_ref()),
+        new_row,
+    })
+}
+
+/// `ALTER TABLE table ADD COLUMN column decl` unless the column already exists.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), SyncError> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name=?2",
+        params![table, column],
+        |r| r.get::<_, i64>(0).map(|n| n > 0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, decl))?;
+    }
+    Ok(())
+}
+
+/// Canonical row id for `row_id` as recorded by `SyncEngine::alias_row` (itself if not aliased).
+fn resolve_alias(conn: &Connection, table_name: &str, row_id: &str) -> Result<String, SyncError> {
+    let to: Option<String> = conn
+        .query_row(
+            "SELECT to_row_id FROM row_aliases WHERE table_name=?1 AND from_row_id=?2",
+            params![table_name, row_id],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(to.unwrap_or_else(|| row_id.to_string()))
+}
+
+/// Lowercase hex SHA-256 of `canonical_json(new_row)`, the same canonical form as `SyncEngine::row_checksum`.
+fn content_hash(new_row: Option<&serde_json::Value>) -> Option<String> {
+    new_row.map(|v| Sha256::digest(canonical_json(v).as_bytes()).iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Direction in which to sort `origin` so that, among equal `(hlc_ms, hlc_ctr)`, the origin
+/// `tie` lets win comes first.
+fn winning_origin_first(tie: TieBreak) -> &'static str {
+    match tie {
+        TieBreak::GreaterOrigin => "DESC",
+        TieBreak::LesserOrigin => "ASC",
+    }
+}
+
+/// HLC and op type of the newest 'pending' local change for a row, if any, ties broken by `tie`.
+fn latest_pending(
+    conn: &Connection,
+    table_name: &str,
+    row_id: &str,
+    tie: TieBreak,
+) -> Result<Option<(String, OpType)>, SyncError> {
+    latest_in_status(conn, table_name, row_id, "'pending'", tie)
+}
+
+/// Like `latest_pending`, over the changes whose status is in `statuses` (a SQL list body).
+fn latest_in_status(
+    conn: &Connection,
+    table_name: &str,
+    row_id: &str,
+    statuses: &str,
+    tie: TieBreak,
+) -> Result<Option<(String, OpType)>, SyncError> {
+    let row: Option<(String, String)> = conn
+        .query_row(
+            &format!(
+                "SELECT hlc, op_type FROM local_changes
+WHERE table_name=?1 AND row_id=?2 AND sync_status IN ({})
+ORDER BY hlc_ms DESC, hlc_ctr DESC, origin {}
+LIMIT 1",
+                statuses,
+                winning_origin_first(tie)
+            ),
+            params![table_name, row_id],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    Ok(row.map(|(hlc, op)| {
+        let op_type = if op == "DELETE" { OpType::Delete } else { OpType::Update };
+        (hlc, op_type)
+    }))
+}
+
+/// Last issued `(millis, counter)` of `origin`, or of the legacy unkeyed clock for `None`.
+fn hlc_state(conn: &Connection, origin: Option<&str>) -> Result<Option<(i64, i64)>, SyncError> {
+    let key = |name: &str| match origin {
+        Some(o) => format!("{}:{}", name, o),
+        None => name.to_string(),
+    };
+    let get = |k: String| -> Result<Option<i64>, SyncError> {
+        let v: Option<String> = conn
+            .prepare_cached("SELECT v FROM sync_kv WHERE k=?1")?
+            .query_row(params![k], |r| r.get(0))
+            .optional()?;
+        Ok(v.map(|s| s.parse::<i64>().unwrap_or(0)))
+    };
+    let Some(ms) = get(key("hlc_last_ms"))? else {
+        return Ok(None);
+    };
+    Ok(Some((ms, get(key("hlc_last_ctr"))?.unwrap_or(0))))
+}
+
+/// `(millis, counter)` of the highest remote HLC passed to `observe_hlc_in`, if any.
+fn observed_hlc(conn: &Connection) -> Result<Option<(i64, i64)>, SyncError> {
+    let v: Option<String> = conn
+        .prepare_cached("SELECT v FROM sync_kv WHERE k='hlc_observed'")?
+        .query_row([], |r| r.get(0))
+        .optional()?;
+    Ok(v.map(|s| {
+        let h = Hlc::parse(&s);
+        (h.millis as i64, h.counter)
+    }))
+}
+
+/// Record `hlc` as observed if it is ahead of what was observed so far and no more than
+/// `DEFAULT_MAX_DRIFT_MS` ahead of the wall clock. Returns whether it was recorded.
+fn observe_hlc_in(conn: &Connection, hlc: &Hlc) -> Result<bool, SyncError> {
+    let now_ms = Utc::now().timestamp_millis() as i128;
+    if hlc.millis - now_ms > DEFAULT_MAX_DRIFT_MS as i128 {
+        return Ok(false);
+    }
+    let theirs = (hlc.millis as i64, hlc.counter);
+    if observed_hlc(conn)?.is_some_and(|seen| seen >= theirs) {
+        return Ok(false);
+    }
+    conn.execute(
+        "INSERT INTO sync_kv(k,v) VALUES('hlc_observed',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+        params![hlc.to_string()],
+    )?;
+    Ok(true)
+}
+
+fn put_hlc_state(conn: &Connection, origin: &str, ms: i64, ctr: i64) -> Result<(), SyncError> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+    )?;
+    for (name, v) in [("hlc_last_ms", ms), ("hlc_last_ctr", ctr)] {
+        stmt.execute(params![format!("{}:{}", name, origin), v.to_string()])?;
+    }
+    Ok(())
+}
+
+/// Issue the next HLC token for `origin` inside `conn`'s open transaction; see `next_hlc_with_drift`.
+fn issue_hlc(conn: &Connection, origin: &str, max_drift_ms: i64) -> Result<String, SyncError> {
+    let now_ms: i64 = Utc::now().timestamp_millis();
+    // An origin without its own state yet starts from the unkeyed clock older versions
+    // kept, if any, so it never issues tokens below ones already handed out here.
+    let own = match hlc_state(conn, Some(origin))? {
+        Some(state) => state,
+        None => hlc_state(conn, None)?.unwrap_or((0, 0)),
+    };
+    // Never fall behind a remote op already applied here (see `observe_hlc`).
+    let (last_ms, ctr) = own.max(observed_hlc(conn)?.unwrap_or((0, 0)));
+
+    if last_ms.saturating_sub(now_ms) > max_drift_ms {
+        return Err(SyncError::State("clock regression"));
+    }
+    let (next_ms, next_ctr) = if now_ms > last_ms {
+        (now_ms, 0)
+    } else if ctr >= HLC_COUNTER_MAX {
+        (last_ms + 1, 0)
+    } else {
+        (last_ms, ctr + 1)
+    };
+
+    put_hlc_state(conn, origin, next_ms, next_ctr)?;
+    Ok(format!("{}-{}-{}", next_ms, next_ctr, origin))
+}
+
+/// Move the watermark of `origin` up to `hlc`; an older or equal `hlc` leaves it alone.
+fn advance_watermark(conn: &Connection, origin: &str, hlc: &str) -> Result<(), SyncError> {
+    let current: Option<String> = conn
+        .prepare_cached("SELECT hlc FROM origin_watermarks WHERE origin=?1")?
+        .query_row(params![origin], |r| r.get(0))
+        .optional()?;
+    if current.is_some_and(|c| c == hlc || should_overwrite(&c, hlc)) {
+        return Ok(());
+    }
+    conn.prepare_cached(
+        "INSERT INTO origin_watermarks(origin, hlc) VALUES(?1, ?2)
+ON CONFLICT(origin) DO UPDATE SET hlc=excluded.hlc",
+    )?
+    .execute(params![origin, hlc])?;
+    Ok(())
+}
+
+/// Why `apply_in_tx` aborted a batch: `op` is set when the applier rejected it short of its
+/// last attempt, so the failure can be counted once the batch has rolled back.
+struct ApplyFailure {
+    err: SyncError,
+    op: Option<Box<RemoteOp>>,
+}
+
+impl<E: Into<SyncError>> From<E> for ApplyFailure {
+    fn from(err: E) -> Self {
+        Self { err: err.into(), op: None }
+    }
+}
+
+/// Count a failed attempt to apply `op` in `failed_remote_ops`, quarantining it if asked.
+fn record_apply_failure(
+    conn: &Connection,
+    feed: &str,
+    op: &RemoteOp,
+    err: &SyncError,
+    quarantine: bool,
+) -> Result<(), SyncError> {
+    conn.execute(
+        "INSERT INTO failed_remote_ops(feed, remote_id, op, attempts, last_error, failed_ms, quarantined)
+VALUES(?1, ?2, ?3, 1, ?4, ?5, ?6)
+ON CONFLICT(feed, remote_id) DO UPDATE SET
+attempts=attempts+1, op=excluded.op, last_error=excluded.last_error,
+failed_ms=excluded.failed_ms, quarantined=excluded.quarantined",
+        params![
+            feed,
+            &op.remote_id,
+            serde_json::to_string(op)?,
+            err.to_string(),
+            Utc::now().timestamp_millis(),
+            quarantine,
+        ],
+    )?;
+    Ok(())
+}
+
+/// `SyncEngine::mark_status` on `conn`, so it can run inside a caller's transaction.
+fn mark_status_in(conn: &Connection, ids: &[i64], status: &str) -> Result<usize, SyncError> {
+    if !matches!(status, "pending" | "pushed" | "acked") {
+        return Err(SyncError::State("invalid sync_status"));
+    }
+    let n = conn.execute(
+        "UPDATE local_changes
+SET sync_status=?1, pushed_ms=CASE WHEN ?1='pushed' THEN ?3 ELSE pushed_ms END
+WHERE change_id IN (SELECT value FROM json_each(?2))",
+        params![status, serde_json::to_string(ids)?, Utc::now().timestamp_millis()],
+    )?;
+    Ok(n)
+}
+
+/// True if the newest local change for a row (any status, ties broken by `tie`) is a DELETE.
+fn is_tombstoned(conn: &Connection, table_name: &str, row_id: &str, tie: TieBreak) -> Result<bool, SyncError> {
+    let op: Option<String> = conn
+        .query_row(
+            &format!(
+                "SELECT op_type FROM local_changes
+WHERE table_name=?1 AND row_id=?2
+ORDER BY hlc_ms DESC, hlc_ctr DESC, origin {}
+LIMIT 1",
+                winning_origin_first(tie)
+            ),
+            params![table_name, row_id],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(op.as_deref() == Some("DELETE"))
+}
+
+/// Mark 'pending' local changes for `op`'s row that lose to `op.hlc` (ties broken by `tie`)
+/// as 'acked'.
+fn supersede_pending(tx: &Transaction<'_>, op: &RemoteOp, tie: TieBreak) -> Result<usize, SyncError> {
+    let (ms, ctr, origin) = parse_hlc(&op.hlc);
+    // Swapping the origins flips their comparison, so the lesser origin sorts as newer.
+    let older = match tie {
+        TieBreak::GreaterOrigin => "(hlc_ms, hlc_ctr, origin) < (?3, ?4, ?5)",
+        TieBreak::LesserOrigin => "(hlc_ms, hlc_ctr, ?5) < (?3, ?4, origin)",
+    };
+    let n = tx.execute(
+        &format!(
+            "UPDATE local_changes SET sync_status='acked'
+WHERE table_name=?1 AND row_id=?2 AND sync_status='pending'
+AND {}",
+            older
+        ),
+        params![&op.table_name, &op.row_id, ms as i64, ctr, origin],
+    )?;
+    Ok(n)
+}
+
+/// Typed input for `SyncEngine::log`. Build it with `LocalChangeInput::builder`,
+/// which checks that the payload fits the op type before anything reaches SQL.
+#[derive(Debug, Clone)]
+pub struct LocalChangeInput {
+    table_name: String,
+    row_id: String,
+    op_type: OpType,
+    columns: Option<serde_json::Value>,
+    new_row: Option<serde_json::Value>,
+    old_row: Option<serde_json::Value>,
+}
+
+impl LocalChangeInput {
+    pub fn builder(table_name: &str, row_id: &str, op_type: OpType) -> LocalChangeBuilder {
+        LocalChangeBuilder {
+            input: LocalChangeInput {
+                table_name: table_name.to_string(),
+                row_id: row_id.to_string(),
+                op_type,
+                columns: None,
+                new_row: None,
+                old_row: None,
+            },
+        }
+    }
+}
+
+/// Builder for `LocalChangeInput`.
+#[derive(Debug, Clone)]
+pub struct LocalChangeBuilder {
+    input: LocalChangeInput,
+}
+
+impl LocalChangeBuilder {
+    /// JSON array of changed column names (UPDATE only).
+    pub fn columns(mut self, columns: serde_json::Value) -> Self {
+        self.input.columns = Some(columns);
+        self
+    }
+
+    /// Snapshot after the op (INSERT/UPDATE).
+    pub fn new_row(mut self, new_row: serde_json::Value) -> Self {
+        self.input.new_row = Some(new_row);
+        self
+    }
+
+    /// Snapshot before the op (UPDATE/DELETE).
+    pub fn old_row(mut self, old_row: serde_json::Value) -> Self {
+        self.input.old_row = Some(old_row);
+        self
+    }
+
+    /// Validate the key (see `validate_change_key`) and the payload against the op type:
+    /// - INSERT needs `new_row` and takes neither `columns` nor `old_row`,
+    /// - UPDATE needs `columns` or `new_row`,
+    /// - DELETE takes neither `columns` nor `new_row`,
+    /// - `columns`, when present, must be an array of strings.
+    pub fn build(self) -> Result<LocalChangeInput, SyncError> {
+        let i = &self.input;
+        validate_change_key(&i.table_name, &i.row_id)?;
+        match i.op_type {
+            OpType::Insert => {
+                if i.new_row.is_none() {
+                    return Err(SyncError::State("insert requires new_row"));
+                }
+                if i.columns.is_some() || i.old_row.is_some() {
+                    return Err(SyncError::State("insert takes only new_row"));
+                }
+            }
+            OpType::Update => {
+                if i.columns.is_none() && i.new_row.is_none() {
+                    return Err(SyncError::State("update requires columns or new_row"));
+                }
+            }
+            OpType::Delete => {
+                if i.columns.is_some() || i.new_row.is_some() {
+                    return Err(SyncError::State("delete takes no columns or new_row"));
+                }
+            }
+        }
+        if let Some(cols) = &i.columns {
+            let all_strings = cols
+                .as_array()
+                .is_some_and(|a| a.iter().all(|c| c.is_string()));
+            if !all_strings {
+                return Err(SyncError::State("columns must be an array of strings"));
+            }
+        }
+        Ok(self.input)
+    }
 }
 
 /// Remote op pulled from the server feed.
@@ -56,113 +786,838 @@ pub enum SyncError {
     Sqlite(#[from] rusqlite::Error),
     #[error("serde: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
     #[error("invalid state: {0}")]
     State(&'static str),
+    #[error("callback failed with code {0}")]
+    Callback(i32),
+    #[error("validation failed: {0}")]
+    Validation(String),
+    /// A UNIQUE constraint rejected the write, e.g. a local change reusing an `(hlc, origin)`
+    /// already logged. Recoverable: regenerate the HLC and retry.
+    #[error("conflict: {0}")]
+    Conflict(&'static str),
+    /// An apply was started from inside the applier of another apply on the same thread.
+    #[error("nested apply not allowed")]
+    NestedApply,
+}
+
+impl SyncError {
+    /// True for SQLite busy/locked contention, which is worth retrying later
+    /// rather than surfacing as a fatal error.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SyncError::Sqlite(e) => matches!(
+                e.sqlite_error_code(),
+                Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Side that won a conflict under last-writer-wins.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Winner {
+    Local,
+    Remote,
+}
+
+/// How a conflict between a DELETE and a non-DELETE for the same row is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeletePolicy {
+    /// Higher HLC wins, as for any other conflict.
+    #[default]
+    LwwByHlc,
+    /// The DELETE wins whatever the HLCs.
+    DeleteWins,
+    /// The INSERT/UPDATE wins whatever the HLCs.
+    InsertWins,
+}
+
+/// How `SyncEngine::apply_remote_ops_with_policy` settles a remote op that contends with a
+/// pending local change for the same row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// The remote op is always applied.
+    RemoteWins,
+    /// The remote op is recorded as applied but never reaches the applier.
+    LocalWins,
+    /// Higher HLC wins, honouring the table's `DeletePolicy` and the engine's `TieBreak`:
+    /// with the default `DeletePolicy::LwwByHlc`, a pending local DELETE newer than a remote
+    /// INSERT/UPDATE keeps the row deleted.
+    #[default]
+    LastWriterWins,
+}
+
+/// A remote op that contended with a pending local change for the same row.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Conflict {
+    pub remote_id: String,
+    pub table_name: String,
+    pub row_id: String,
+    pub local_hlc: String,  // newest pending local change for the row
+    pub local_op: OpType,   // its op type; DELETE for a row deleted locally while edited remotely
+    pub remote_hlc: String,
+    pub winner: Winner,
+}
+
+/// What applying a remote op would do, as predicted by `SyncEngine::plan_apply`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanAction {
+    /// New op; it would reach the applier.
+    Apply,
+    /// Already in `applied_remote_ops`, or repeated earlier in the batch; it would be skipped.
+    Duplicate,
+    /// Contends with a pending local change; `winner` says whether it would be applied.
+    Conflict(Conflict),
+    /// Table outside `with_known_tables`; it would be recorded as applied without reaching the applier.
+    UnknownTable,
+    /// UPDATE/DELETE for a row whose newest local change is a DELETE. It would still
+    /// reach the applier, which will likely find no row.
+    Tombstoned,
+}
+
+/// What happened to one op of a batch applied with `SyncEngine::apply_remote_ops_outcomes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpOutcome {
+    /// Handed to the applier.
+    Applied,
+    /// Already applied (possibly earlier in the same batch); skipped.
+    Duplicate,
+    /// Contended with a pending local change; applied only if `Winner::Remote`.
+    Conflict(Winner),
+    /// UPDATE/DELETE for a row whose newest local change is a DELETE. Handed to the
+    /// applier, which likely found no row.
+    Tombstoned,
+    /// Table outside `with_known_tables`; recorded as applied without reaching the applier.
+    UnknownTable,
+    /// The applier failed on it `with_max_apply_attempts` times; recorded as applied and
+    /// kept in `get_quarantined_ops` instead.
+    Quarantined,
+}
+
+/// A remote op the applier kept failing on, as returned by `SyncEngine::get_quarantined_ops`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedOp {
+    pub op: RemoteOp,
+    pub attempts: i64,
+    pub last_error: String,
+    pub failed_ms: i64, // unix millis of the last failure
+}
+
+/// Database size figures returned by `SyncEngine::storage_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageReport {
+    pub page_count: i64,
+    pub page_size: i64,
+    pub wal_frames: Option<i64>, // frames in the WAL; None when not in WAL mode
+    pub db_bytes: Option<u64>,   // on-disk size of the database file; None for in-memory
+    pub wal_bytes: Option<u64>,  // on-disk size of the `-wal` file; None if absent or in-memory
+}
+
+/// Counts returned by `SyncEngine::apply_remote_ops`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyStats {
+    pub applied: usize, // ops handed to the applier
+    pub skipped: usize, // ops recorded or already known without reaching the applier
+}
+
+/// Result of `SyncEngine::simulate_apply`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    pub applied: usize,            // ops that reached the applier
+    pub outcomes: Vec<OpOutcome>,  // one per input op, in input order
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Per-op classification of a remote batch, in batch order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyPlan {
+    pub entries: Vec<(String, PlanAction)>, // (remote_id, action)
+}
+
+impl ApplyPlan {
+    /// Number of ops that would reach the applier.
+    pub fn apply_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, a)| match a {
+                PlanAction::Apply | PlanAction::Tombstoned => true,
+                PlanAction::Conflict(c) => c.winner == Winner::Remote,
+                PlanAction::Duplicate | PlanAction::UnknownTable => false,
+            })
+            .count()
+    }
 }
 
 /// Trait implemented by the host to apply a remote op into domain tables.
 /// This keeps the engine schema-agnostic.
 pub trait ApplyDomainOp {
     fn apply(&self, tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError>;
+
+    /// Row to write for `op` given the row currently stored. Defaults to
+    /// `merge_remote_update`; override to customise how partial updates are merged.
+    fn merged_row(&self, op: &RemoteOp, current_row: &serde_json::Value) -> serde_json::Value {
+        merge_remote_update(op, current_row)
+    }
+}
+
+/// Row an applier should write for remote `op` over `current_row`: `new_row` merged with
+/// `merge::lww_merge_row`, so an UPDATE listing `columns` touches only those fields and
+/// keeps the rest of `current_row`. Without `columns` (or for an INSERT) `new_row` replaces
+/// the row; without `new_row` (a DELETE) `current_row` is returned unchanged.
+pub fn merge_remote_update(op: &RemoteOp, current_row: &serde_json::Value) -> serde_json::Value {
+    let Some(new_row) = &op.new_row else {
+        return current_row.clone();
+    };
+    let columns: Option<Vec<&str>> = match op.op_type {
+        OpType::Update => op
+            .columns
+            .as_ref()
+            .and_then(|c| c.as_array())
+            .map(|a| a.iter().filter_map(|c| c.as_str()).collect()),
+        _ => None,
+    };
+    lww_merge_row(current_row, new_row, columns.as_deref())
+}
+
+/// Rewrites an outgoing change; see `SyncEngine::set_egress_transform`.
+pub type EgressTransform = Box<dyn Fn(&mut Change)>;
+
+/// Bounded least-recently-used set of remote ids known to be applied.
+#[derive(Debug, Default)]
+struct RecentIds {
+    capacity: usize,
+    tick: u64,
+    by_id: HashMap<String, u64>,
+    by_tick: BTreeMap<u64, String>,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, ..Self::default() }
+    }
+
+    /// True if `id` is cached; marks it as most recently used.
+    fn touch(&mut self, id: &str) -> bool {
+        let Some(old) = self.by_id.get(id).copied() else { return false };
+        self.tick += 1;
+        self.by_tick.remove(&old);
+        self.by_tick.insert(self.tick, id.to_string());
+        self.by_id.insert(id.to_string(), self.tick);
+        true
+    }
+
+    fn insert(&mut self, id: &str) {
+        if self.capacity == 0 || self.touch(id) {
+            return;
+        }
+        if self.by_id.len() == self.capacity
+            && let Some((_, evicted)) = self.by_tick.pop_first()
+        {
+            self.by_id.remove(&evicted);
+        }
+        self.tick += 1;
+        self.by_tick.insert(self.tick, id.to_string());
+        self.by_id.insert(id.to_string(), self.tick);
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(t) = self.by_id.remove(id) {
+            self.by_tick.remove(&t);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.by_id.clear();
+        self.by_tick.clear();
+    }
+}
+
+/// Observes a remote op skipped for targeting an unknown table; see `SyncEngine::with_known_tables`.
+pub type SkippedOpHook = Box<dyn Fn(&RemoteOp)>;
+
+/// Observes the remote cursor after a write commits; see `SyncEngine::set_on_cursor_advance`.
+pub type CursorHook = Box<dyn Fn(&str)>;
+
+/// Observes a remote op contending with a pending local change; see `SyncEngine::set_on_conflict`.
+pub type ConflictHook = Box<dyn Fn(&RemoteOp, &Conflict)>;
+
+/// One direction of a `Migration`.
+pub type MigrationStep = fn(&Transaction<'_>) -> Result<(), SyncError>;
+
+/// A schema step registered with `SyncEngine::with_migrations`; `up` moves the schema from
+/// `version - 1` to `version` inside the migration transaction, `down` moves it back.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i32,
+    pub up: MigrationStep,
+    pub down: Option<MigrationStep>, // None: irreversible
+}
+
+/// Iterator returned by `SyncEngine::pending_ops_iter`. Stops after the first error.
+pub struct PendingOps<'e, 'c> {
+    engine: &'e SyncEngine<'c>,
+    batch: i64,
+    last_seen: i64,
+    buf: VecDeque<Change>,
+    done: bool,
+}
+
+impl Iterator for PendingOps<'_, '_> {
+    type Item = Result<Change, SyncError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() && !self.done {
+            match self.engine.query_pending(None, self.last_seen, self.batch) {
+                Ok(rows) => {
+                    self.done = (rows.len() as i64) < self.batch;
+                    self.buf.extend(rows);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let ch = self.buf.pop_front()?;
+        self.last_seen = ch.change_id;
+        Some(Ok(ch))
+    }
 }
 
 /// SyncEngine encapsulates connection and common operations.
+/// Hot statements (the dedup lookup and marker insert of apply, the local_changes insert,
+/// the pending-ops query and the HLC state reads/writes) go through the connection's
+/// prepared statement cache (`prepare_cached`), so they are parsed once per connection.
+/// Measured in a release build on an in-memory database, against the same code using `prepare`:
+/// applying fresh ops in batches of 10 went from 8.2 to 3.8 µs per op, skipping already-applied
+/// ops from 1.14 to 0.83 µs, `log_insert_fullrow` from 22.3 to 13.7 µs and `get_pending_ops(10)`
+/// from 28 to 22 µs.
 pub struct SyncEngine<'c> {
     conn: &'c Connection,
+    read_conn: Option<&'c Connection>,
+    supersede_local: bool,
+    feed: String,
+    egress: Option<EgressTransform>,
+    max_op_bytes: Option<usize>,
+    delete_policies: HashMap<String, DeletePolicy>,
+    on_cursor_advance: Option<CursorHook>,
+    known_tables: Option<HashSet<String>>,
+    on_unknown_table: Option<SkippedOpHook>,
+    on_conflict: Option<ConflictHook>,
+    tie_break: TieBreak,
+    recent_ids: RefCell<RecentIds>,
+    max_apply_attempts: Option<i64>,
+    contention: ContentionPolicy,
+    migrations: Vec<Migration>, // sorted by version
+    cursor_order: CursorOrder,
+    #[cfg(feature = "jsonschema")]
+    validators: RefCell<HashMap<String, (String, jsonschema::Validator)>>, // table -> (schema JSON, compiled)
 }
 
 impl<'c> SyncEngine<'c> {
     /// Bind the engine to an existing SQLite connection.
     pub fn new(conn: &'c Connection) -> Result<Self, SyncError> {
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            read_conn: None,
+            supersede_local: false,
+            feed: DEFAULT_FEED.to_string(),
+            egress: None,
+            max_op_bytes: None,
+            delete_policies: HashMap::new(),
+            on_cursor_advance: None,
+            known_tables: None,
+            on_unknown_table: None,
+            on_conflict: None,
+            tie_break: TieBreak::default(),
+            recent_ids: RefCell::new(RecentIds::new(0)),
+            max_apply_attempts: None,
+            contention: ContentionPolicy::default(),
+            migrations: Vec::new(),
+            cursor_order: CursorOrder::default(),
+            #[cfg(feature = "jsonschema")]
+            validators: RefCell::new(HashMap::new()),
+        })
     }
 
-    /// Create required metadata tables and indexes.
-    /// Safe to call multiple times.
-    pub fn init_schema(&self) -> Result<(), SyncError> {
-        self.conn.execute_batch(
-            r#"
-PRAGMA journal_mode=WAL;
+    /// Route pure reads (`get_pending_ops`, `count_pending`, `applied_ops_since`,
+    /// `change_id_headroom`, `get_remote_cursor`) through a separate connection, typically opened with
+    /// `SQLITE_OPEN_READONLY` on the same WAL database, so status polling never waits
+    /// on a sync write.
+    ///
+    /// The read connection sees a snapshot as of its last read transaction: committed
+    /// writes show up on the next read, uncommitted writes on `conn` never do.
+    pub fn with_read_connection(mut self, ro_conn: &'c Connection) -> Self {
+        self.read_conn = Some(ro_conn);
+        self
+    }
 
-CREATE TABLE IF NOT EXISTS local_changes (
-change_id INTEGER PRIMARY KEY AUTOINCREMENT,
-table_name TEXT NOT NULL,
-row_id TEXT NOT NULL,
-op_type TEXT NOT NULL CHECK(op_type IN ('INSERT','UPDATE','DELETE')),
-columns TEXT, -- JSON array (nullable)
-new_row TEXT, -- JSON (nullable for DELETE)
-old_row TEXT, -- JSON (optional)
-hlc TEXT NOT NULL,
-origin TEXT NOT NULL,
-sync_status TEXT NOT NULL DEFAULT 'pending' CHECK(sync_status IN ('pending','pushed','acked')),
-UNIQUE(hlc, origin) -- idempotency for local generation
-);
+    /// When on, applying a remote op marks 'pending' local changes for the same row with
+    /// a lower HLC as 'acked', so a change the remote already overrode is never pushed.
+    /// Off by default: some hosts want every local edit to reach the server.
+    pub fn with_supersede_local(mut self, on: bool) -> Self {
+        self.supersede_local = on;
+        self
+    }
 
-CREATE INDEX IF NOT EXISTS idx_local_changes_status
-ON local_changes(sync_status, change_id);
+    /// Scope remote-op dedup (`applied_remote_ops`) and the remote cursor to `feed`, for
+    /// hosts pulling from several servers whose `remote_id`s may collide. Engines on the
+    /// same database with different feeds never see each other's applied ids or cursor.
+    /// Defaults to `DEFAULT_FEED`, which is what databases created before feeds used.
+    pub fn with_feed(mut self, feed: &str) -> Self {
+        self.feed = feed.to_string();
+        self
+    }
+
+    /// Run `f` on every change returned by `get_pending_ops`, e.g. to hash or drop
+    /// private fields before they are pushed. Only the returned copies are touched;
+    /// the rows in `local_changes` keep the original values (see `get_change`).
+    pub fn set_egress_transform(&mut self, f: EgressTransform) {
+        self.egress = Some(f);
+    }
+
+    /// Reject local changes whose serialized `columns`, `new_row` and `old_row` together
+    /// exceed `bytes`, so one runaway row can't stall every later push. No limit by default.
+    pub fn with_max_op_bytes(mut self, bytes: usize) -> Self {
+        self.max_op_bytes = Some(bytes);
+        self
+    }
+
+    /// Quarantine a remote op once the applier has failed on it `attempts` times: it is
+    /// recorded as applied, listed by `get_quarantined_ops`, and the rest of its batch commits.
+    /// Earlier failures still abort the batch (as without a limit), but are counted in
+    /// `failed_remote_ops`. Busy/locked errors are not counted. No limit by default.
+    pub fn with_max_apply_attempts(mut self, attempts: u32) -> Self {
+        self.max_apply_attempts = Some(attempts.max(1).into());
+        self
+    }
+
+    /// Only hand ops for `tables` to the applier. Ops for any other table (e.g. added by a
+    /// newer server) are recorded as applied and skipped, so an older client keeps syncing
+    /// instead of failing the batch. By default every table is applied.
+    pub fn with_known_tables(mut self, tables: HashSet<String>) -> Self {
+        self.known_tables = Some(tables);
+        self
+    }
+
+    /// Call `f` for every op skipped because its table is not in `with_known_tables`.
+    pub fn set_on_unknown_table(&mut self, f: SkippedOpHook) {
+        self.on_unknown_table = Some(f);
+    }
+
+    /// Call `f` for every conflict the policy-aware apply paths settle (`ConflictPolicy`),
+    /// whichever side wins, inside the apply transaction and before the op is recorded as
+    /// applied, e.g. to tell the user a row they deleted was edited remotely
+    /// (`Conflict::local_op` is DELETE).
+    pub fn set_on_conflict(&mut self, f: ConflictHook) {
+        self.on_conflict = Some(f);
+    }
+
+    fn is_known_table(&self, table_name: &str) -> bool {
+        self.known_tables.as_ref().is_none_or(|t| t.contains(table_name))
+    }
+
+    /// Call `f` with the new cursor each time `set_remote_cursor`, `set_remote_cursor_for`,
+    /// `compare_and_set_cursor` or `commit_cycle` (or `commit_cycle_for`) stores one, after the transaction commits, e.g. to mirror it into the
+    /// host's own checkpoint store.
+    pub fn set_on_cursor_advance(&mut self, f: CursorHook) {
+        self.on_cursor_advance = Some(f);
+    }
+
+    fn cursor_advanced(&self, cursor: &str) {
+        if let Some(f) = &self.on_cursor_advance {
+            f(cursor);
+        }
+    }
+
+    /// Resolve conflicts on `table_name` between a DELETE on one side and an INSERT/UPDATE
+    /// on the other with `policy` instead of plain last-writer-wins. Consulted wherever a
+    /// contended op is settled by `ConflictPolicy::LastWriterWins`: `apply_remote_ops_collect_conflicts`,
+    /// `apply_remote_ops_with_policy`, `apply_remote_ops_outcomes`, `simulate_apply`,
+    /// `SyncClient::pull_only` and every FFI apply entry point, plus the dry runs `plan_apply`
+    /// and `preview_conflicts`. Plain `apply_remote_ops` does no contention check and ignores it.
+    pub fn with_delete_policy(mut self, table_name: &str, policy: DeletePolicy) -> Self {
+        self.delete_policies.insert(table_name.to_string(), policy);
+        self
+    }
+
+    /// Apply `policy`: set the connection's busy timeout now, start the engine's transactions
+    /// with BEGIN IMMEDIATE, and retry commits that fail with BUSY/LOCKED. A policy with a
+    /// `ContentionPolicy::warning` is rejected with `SyncError::Validation` carrying it.
+    pub fn with_contention_policy(mut self, policy: ContentionPolicy) -> Result<Self, SyncError> {
+        if let Some(warning) = policy.warning() {
+            return Err(SyncError::Validation(warning.to_string()));
+        }
+        self.conn
+            .busy_timeout(std::time::Duration::from_millis(policy.busy_timeout_ms.into()))?;
+        self.contention = policy;
+        Ok(self)
+    }
+
+    /// Compare cursors with `order` in `set_remote_cursor_checked`.
+    pub fn with_cursor_order(mut self, order: CursorOrder) -> Self {
+        self.cursor_order = order;
+        self
+    }
+
+    /// Register schema steps for `run_migrations`, replacing any registered before.
+    pub fn with_migrations(mut self, mut migrations: Vec<Migration>) -> Self {
+        migrations.sort_by_key(|m| m.version);
+        self.migrations = migrations;
+        self
+    }
+
+    /// Start a write transaction. With a contention policy set, it is BEGIN IMMEDIATE, so the
+    /// write lock is taken (waiting up to the busy timeout) up front rather than on the first
+    /// write, where a deferred transaction that already read can only fail with BUSY.
+    fn begin(&self) -> Result<Transaction<'c>, SyncError> {
+        if self.contention == ContentionPolicy::default() {
+            Ok(Transaction::new_unchecked(self.conn, rusqlite::TransactionBehavior::Deferred)?)
+        } else {
+            self.begin_immediate()
+        }
+    }
+
+    /// BEGIN IMMEDIATE whatever the contention policy, for read-modify-writes that must be
+    /// serialized across connections. Waits up to the connection's busy timeout.
+    fn begin_immediate(&self) -> Result<Transaction<'c>, SyncError> {
+        Ok(Transaction::new_unchecked(self.conn, rusqlite::TransactionBehavior::Immediate)?)
+    }
+
+    /// Commit `tx`, retrying per the contention policy. A COMMIT that fails leaves the
+    /// transaction open, so it can be retried; on final failure dropping `tx` rolls it back.
+    fn commit(&self, tx: Transaction<'_>) -> Result<(), SyncError> {
+        let mut attempt = 0;
+        loop {
+            let err = match tx.execute_batch("COMMIT") {
+                Ok(()) => return Ok(()),
+                Err(e) => SyncError::from(e),
+            };
+            if attempt >= self.contention.commit_retries || !err.is_transient() {
+                return Err(err);
+            }
+            attempt += 1;
+            std::thread::sleep(std::time::Duration::from_millis(
+                self.contention.retry_backoff_ms.into(),
+            ));
+        }
+    }
+
+    /// Remember up to `capacity` recently applied remote ids in memory, so
+    /// `apply_remote_ops_stream` can drop redelivered ops without a query. Ids are cached only
+    /// after their batch commits, and misses fall back to `applied_remote_ops`. Markers removed
+    /// by another engine on the same database are not seen by this cache. Off by default.
+    pub fn with_dedup_cache(self, capacity: usize) -> Self {
+        *self.recent_ids.borrow_mut() = RecentIds::new(capacity);
+        self
+    }
+
+    /// Origin order used to break HLC ties in conflicts (see `merge::TieBreak`).
+    /// Defaults to `TieBreak::GreaterOrigin`; every peer must be configured the same way.
+    pub fn with_tie_break(mut self, tie: TieBreak) -> Self {
+        self.tie_break = tie;
+        self
+    }
+
+    /// Side that wins when remote `op` contends with a pending local change of `local_op` type.
+    fn conflict_winner(&self, op: &RemoteOp, local_hlc: &str, local_op: OpType) -> Winner {
+        let local_delete = local_op == OpType::Delete;
+        if local_delete != (op.op_type == OpType::Delete) {
+            let policy = self.delete_policies.get(&op.table_name).copied().unwrap_or_default();
+            match (policy, local_delete) {
+                (DeletePolicy::DeleteWins, true) | (DeletePolicy::InsertWins, false) => return Winner::Local,
+                (DeletePolicy::DeleteWins, false) | (DeletePolicy::InsertWins, true) => return Winner::Remote,
+                (DeletePolicy::LwwByHlc, _) => {}
+            }
+        }
+        if should_overwrite_with(local_hlc, &op.hlc, self.tie_break) {
+            Winner::Local
+        } else {
+            Winner::Remote
+        }
+    }
+
+    fn cursor_key(&self) -> String {
+        if self.feed == DEFAULT_FEED {
+            "remote_cursor".to_string()
+        } else {
+            format!("remote_cursor:{}", self.feed)
+        }
+    }
+
+    // `@` rather than `:` keeps a table cursor of the default feed from sharing the key of
+    // another feed's cursor (`remote_cursor:<feed>`).
+    fn table_cursor_key(&self, table_name: &str) -> String {
+        format!("{}@{}", self.cursor_key(), table_name)
+    }
+
+    fn reader(&self) -> &'c Connection {
+        self.read_conn.unwrap_or(self.conn)
+    }
+
+    /// Create required metadata tables and indexes.
+    /// Safe to call multiple times; returns early once `is_initialized` is true.
+    pub fn init_schema(&self) -> Result<(), SyncError> {
+        if self.is_initialized()? {
+            return Ok(());
+        }
+        self.init_schema_with_journal_mode(JournalMode::Wal)
+    }
+
+    /// True once `init_schema` has completed on this database for the current engine schema,
+    /// so hosts can skip re-initializing on launch.
+    pub fn is_initialized(&self) -> Result<bool, SyncError> {
+        let has_kv: bool = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='sync_kv'",
+            [],
+            |r| r.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        if !has_kv {
+            return Ok(false);
+        }
+        let ver: Option<String> = self
+            .conn
+            .query_row("SELECT v FROM sync_kv WHERE k='engine_schema'", [], |r| r.get(0))
+            .optional()?;
+        Ok(ver.and_then(|v| v.parse::<i64>().ok()) == Some(ENGINE_SCHEMA))
+    }
+
+    /// Like `init_schema`, but set `journal_mode` to `mode` first. Use DELETE or TRUNCATE
+    /// where WAL is unreliable (network filesystems, some shared app-group containers).
+    /// Always runs in full, even on an initialized database.
+    pub fn init_schema_with_journal_mode(&self, mode: JournalMode) -> Result<(), SyncError> {
+        let _: String = self.conn.query_row(
+            &format!("PRAGMA journal_mode={}", mode.as_str()),
+            [],
+            |r| r.get(0),
+        )?;
+        self.conn.execute_batch(
+            r#"
+CREATE TABLE IF NOT EXISTS local_changes (
+change_id INTEGER PRIMARY KEY AUTOINCREMENT,
+table_name TEXT NOT NULL,
+row_id TEXT NOT NULL,
+op_type TEXT NOT NULL CHECK(op_type IN ('INSERT','UPDATE','DELETE')),
+columns TEXT, -- JSON array (nullable)
+new_row TEXT, -- JSON (nullable for DELETE)
+old_row TEXT, -- JSON (optional)
+hlc TEXT NOT NULL,
+origin TEXT NOT NULL,
+sync_status TEXT NOT NULL DEFAULT 'pending' CHECK(sync_status IN ('pending','pushed','acked')),
+hlc_ms INTEGER, -- millis segment of hlc
+hlc_ctr INTEGER, -- counter segment of hlc
+pushed_ms INTEGER, -- when last marked 'pushed' (unix millis)
+group_id INTEGER, -- shared by the changes of one log_batch (change_id of its first change)
+UNIQUE(hlc, origin) -- idempotency for local generation
+);
+
+CREATE INDEX IF NOT EXISTS idx_local_changes_status
+ON local_changes(sync_status, change_id);
 
 CREATE TABLE IF NOT EXISTS applied_remote_ops (
-remote_id TEXT PRIMARY KEY,
-applied_ms INTEGER NOT NULL
+feed TEXT NOT NULL DEFAULT '',
+remote_id TEXT NOT NULL,
+applied_ms INTEGER NOT NULL,
+hlc TEXT, -- remote op's HLC (NULL for ops applied before it was recorded)
+origin TEXT,
+table_name TEXT, -- table the op targeted (NULL for ops applied before it was recorded)
+PRIMARY KEY(feed, remote_id)
 );
 
+CREATE INDEX IF NOT EXISTS idx_applied_remote_ops_ms
+ON applied_remote_ops(applied_ms);
+
 CREATE TABLE IF NOT EXISTS sync_kv (
 k TEXT PRIMARY KEY,
 v TEXT NOT NULL
 );
+
+CREATE TABLE IF NOT EXISTS row_aliases (
+table_name TEXT NOT NULL,
+from_row_id TEXT NOT NULL,
+to_row_id TEXT NOT NULL,
+PRIMARY KEY(table_name, from_row_id)
+);
+
+CREATE TABLE IF NOT EXISTS failed_remote_ops (
+feed TEXT NOT NULL DEFAULT '',
+remote_id TEXT NOT NULL,
+op TEXT NOT NULL, -- the RemoteOp as JSON
+attempts INTEGER NOT NULL,
+last_error TEXT NOT NULL,
+failed_ms INTEGER NOT NULL, -- last failure (unix millis)
+quarantined INTEGER NOT NULL DEFAULT 0, -- 1 once skipped for good, see with_max_apply_attempts
+PRIMARY KEY(feed, remote_id)
+);
+
+CREATE TABLE IF NOT EXISTS origin_watermarks (
+origin TEXT PRIMARY KEY,
+hlc TEXT NOT NULL -- newest HLC applied from this origin
+);
 "#,
         )?;
+        self.upgrade_local_changes()?;
+        self.upgrade_applied_remote_ops()?;
         // Ensure a schema version exists; default to 1
         self.conn.execute(
             "INSERT INTO sync_kv(k,v) VALUES('schema_version','1')
 ON CONFLICT(k) DO NOTHING",
             [],
         )?;
+        self.conn.execute(
+            "INSERT INTO sync_kv(k,v) VALUES('engine_schema',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![ENGINE_SCHEMA.to_string()],
+        )?;
         Ok(())
     }
 
-    /// Generate a monotonic HLC token "millis-counter-origin".
-    /// Stored in sync_kv: hlc_last_ms, hlc_last_ctr.
-    pub fn next_hlc(&self, origin: &str) -> Result<String, SyncError> {
-        let now_ms: i64 = Utc::now().timestamp_millis();
-        let tx = self.conn.unchecked_transaction()?;
-        let last_ms: i64 = tx
-            .query_row("SELECT v FROM sync_kv WHERE k='hlc_last_ms'", [], |r| {
-                r.get::<_, String>(0).map(|s| s.parse::<i64>().unwrap_or(0))
-            })
-            .optional()?
-            .unwrap_or(0);
+    /// Rebuild an `applied_remote_ops` table created before feeds (keyed on `remote_id`
+    /// alone) with the `(feed, remote_id)` key, moving existing rows to the default feed,
+    /// and add the `hlc`/`origin`/`table_name` columns if missing.
+    fn upgrade_applied_remote_ops(&self) -> Result<(), SyncError> {
+        let tx = self.begin()?;
+        let has_feed: bool = tx.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('applied_remote_ops') WHERE name='feed'",
+            [],
+            |r| r.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        if !has_feed {
+            tx.execute_batch(
+                "ALTER TABLE applied_remote_ops RENAME TO applied_remote_ops_old;
+CREATE TABLE applied_remote_ops (
+feed TEXT NOT NULL DEFAULT '',
+remote_id TEXT NOT NULL,
+applied_ms INTEGER NOT NULL,
+PRIMARY KEY(feed, remote_id)
+);
+INSERT INTO applied_remote_ops(feed, remote_id, applied_ms)
+SELECT '', remote_id, applied_ms FROM applied_remote_ops_old;
+DROP TABLE applied_remote_ops_old;
+CREATE INDEX IF NOT EXISTS idx_applied_remote_ops_ms
+ON applied_remote_ops(applied_ms);",
+            )?;
+        }
+        add_column_if_missing(&tx, "applied_remote_ops", "hlc", "TEXT")?;
+        add_column_if_missing(&tx, "applied_remote_ops", "origin", "TEXT")?;
+        add_column_if_missing(&tx, "applied_remote_ops", "table_name", "TEXT")?;
 
-        let ctr: i64 = tx
-            .query_row("SELECT v FROM sync_kv WHERE k='hlc_last_ctr'", [], |r| {
-                r.get::<_, String>(0).map(|s| s.parse::<i64>().unwrap_or(0))
-            })
-            .optional()?
-            .unwrap_or(0);
+        // Ops applied before `origin_watermarks` existed seed the watermark of their origin.
+        let unmarked: Vec<(String, String)> = {
+            let mut stmt = tx.prepare(
+                "SELECT origin, hlc FROM applied_remote_ops
+WHERE origin IS NOT NULL AND hlc IS NOT NULL
+AND origin NOT IN (SELECT origin FROM origin_watermarks)",
+            )?;
+            let it = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            it.collect::<Result<_, _>>()?
+        };
+        for (origin, hlc) in unmarked {
+            advance_watermark(&tx, &origin, &hlc)?;
+        }
+        self.commit(tx)?;
+        Ok(())
+    }
 
-        let (next_ms, next_ctr) = if now_ms > last_ms {
-            (now_ms, 0)
-        } else {
-            (last_ms, ctr + 1)
+    /// Bring a `local_changes` table created by an older build up to date:
+    /// add the `hlc_ms`/`hlc_ctr` columns if missing, backfill them, and index them.
+    fn upgrade_local_changes(&self) -> Result<(), SyncError> {
+        let tx = self.begin()?;
+        add_column_if_missing(&tx, "local_changes", "hlc_ms", "INTEGER")?;
+        add_column_if_missing(&tx, "local_changes", "hlc_ctr", "INTEGER")?;
+        add_column_if_missing(&tx, "local_changes", "pushed_ms", "INTEGER")?;
+        add_column_if_missing(&tx, "local_changes", "group_id", "INTEGER")?;
+
+        let rows: Vec<(i64, String)> = {
+            let mut stmt =
+                tx.prepare("SELECT change_id, hlc FROM local_changes WHERE hlc_ms IS NULL")?;
+            let it = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            it.collect::<Result<_, _>>()?
         };
+        for (id, hlc) in rows {
+            let (ms, ctr, _) = parse_hlc(&hlc);
+            tx.execute(
+                "UPDATE local_changes SET hlc_ms=?1, hlc_ctr=?2 WHERE change_id=?3",
+                params![ms as i64, ctr, id],
+            )?;
+        }
 
-        // persist
-        tx.execute(
-            "INSERT INTO sync_kv(k,v) VALUES('hlc_last_ms',?1)
-ON CONFLICT(k) DO UPDATE SET v=excluded.v",
-            params![next_ms.to_string()],
-        )?;
-        tx.execute(
-            "INSERT INTO sync_kv(k,v) VALUES('hlc_last_ctr',?1)
-ON CONFLICT(k) DO UPDATE SET v=excluded.v",
-            params![next_ctr.to_string()],
+        tx.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_local_changes_hlc
+ON local_changes(hlc_ms, hlc_ctr);
+CREATE INDEX IF NOT EXISTS idx_local_changes_group
+ON local_changes(group_id) WHERE group_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_local_changes_origin_hlc
+ON local_changes(origin, hlc_ms, hlc_ctr);",
         )?;
-        tx.commit()?;
+        self.commit(tx)?;
+        Ok(())
+    }
+
+    /// Generate a monotonic HLC token "millis-counter-origin".
+    /// Stored per origin in sync_kv: `hlc_last_ms:<origin>`, `hlc_last_ctr:<origin>`, so origins
+    /// sharing a database advance independently.
+    /// Fails with "clock regression" if the wall clock is more than `DEFAULT_MAX_DRIFT_MS`
+    /// behind the last issued token; see `next_hlc_with_drift`.
+    pub fn next_hlc(&self, origin: &str) -> Result<String, SyncError> {
+        self.next_hlc_with_drift(origin, DEFAULT_MAX_DRIFT_MS)
+    }
 
-        Ok(format!("{}-{}-{}", next_ms, next_ctr, origin))
+    /// Like `next_hlc`, failing with "clock regression" instead of issuing a token when the
+    /// last issued millis is more than `max_drift_ms` ahead of the wall clock (e.g. the device
+    /// clock was set back by years). Within the drift the counter advances as usual; when it
+    /// reaches `HLC_COUNTER_MAX` the millis is bumped by one and the counter restarts at 0.
+    ///
+    /// The read-modify-write runs under BEGIN IMMEDIATE, so connections sharing the file never
+    /// issue the same token; a contender waits up to the busy timeout (rusqlite's default, or
+    /// `ContentionPolicy::busy_timeout_ms`).
+    pub fn next_hlc_with_drift(&self, origin: &str, max_drift_ms: i64) -> Result<String, SyncError> {
+        let tx = self.begin_immediate()?;
+        let hlc = issue_hlc(&tx, origin, max_drift_ms)?;
+        self.commit(tx)?;
+        Ok(hlc)
+    }
+
+    /// Advance the clock past `remote_hlc`, so every token `next_hlc` issues afterwards, for
+    /// any origin, sorts after it. Tokens more than `DEFAULT_MAX_DRIFT_MS` ahead of the wall
+    /// clock are ignored rather than dragging the local clock into the far future.
+    /// The apply paths call this for every op they apply.
+    pub fn observe_hlc(&self, remote_hlc: &str) -> Result<(), SyncError> {
+        let hlc = parse_hlc_strict(remote_hlc)?;
+        let tx = self.begin_immediate()?;
+        if observe_hlc_in(&tx, &hlc)? {
+            self.commit(tx)?;
+        }
+        Ok(())
+    }
+
+    /// Move the unkeyed `hlc_last_ms`/`hlc_last_ctr` written by older versions to `origin`
+    /// (normally this install's primary origin), keeping whichever of the two states is newer.
+    /// Returns false when there was nothing to move.
+    pub fn migrate_hlc_state(&self, origin: &str) -> Result<bool, SyncError> {
+        let tx = self.begin_immediate()?;
+        let Some(legacy) = hlc_state(&tx, None)? else {
+            return Ok(false);
+        };
+        let (ms, ctr) = match hlc_state(&tx, Some(origin))? {
+            Some(own) if own > legacy => own,
+            _ => legacy,
+        };
+        put_hlc_state(&tx, origin, ms, ctr)?;
+        tx.execute("DELETE FROM sync_kv WHERE k IN ('hlc_last_ms','hlc_last_ctr')", [])?;
+        self.commit(tx)?;
+        Ok(true)
     }
 
     /// Insert a local change. Use the convenience wrappers below for common ops.
+    /// Rejects empty `table_name`/`row_id` and table names that are not plain identifiers.
+    /// A `row_id` aliased with `alias_row` is logged under its canonical id.
+    /// Fails with "op too large" when the payload exceeds `with_max_op_bytes`.
+    #[allow(clippy::too_many_arguments)]
     pub fn log_local_change(
         &self,
         table_name: &str,
@@ -174,27 +1629,120 @@ ON CONFLICT(k) DO UPDATE SET v=excluded.v",
         hlc: &str,
         origin: &str,
     ) -> Result<i64, SyncError> {
-        let tx = self.conn.unchecked_transaction()?;
-        tx.execute(
-            "INSERT INTO local_changes
-(table_name,row_id,op_type,columns,new_row,old_row,hlc,origin,sync_status)
-VALUES (?1,?2,?3,?4,?5,?6,?7,?8,'pending')",
-            params![
-                table_name,
-                row_id,
-                op_type.as_str(),
-                columns.map(|v| v.to_string()),
-                new_row.map(|v| v.to_string()),
-                old_row.map(|v| v.to_string()),
-                hlc,
-                origin,
-            ],
+        let tx = self.begin()?;
+        let id = self.insert_local_change(
+            &tx, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin,
         )?;
-        let id = tx.last_insert_rowid();
-        tx.commit()?;
+        self.commit(tx)?;
         Ok(id)
     }
 
+    /// Record several local changes in one transaction, each with its own `next_hlc` token
+    /// (strictly increasing in input order), and return their change_ids in input order.
+    /// Either every change is logged or none is. The changes share a `group_id` (the first
+    /// change_id), so `get_pending_groups` hands them to the push layer together.
+    pub fn log_batch(&self, inputs: &[LocalChangeInput], origin: &str) -> Result<Vec<i64>, SyncError> {
+        let tx = self.begin_immediate()?;
+        let mut ids = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let hlc = issue_hlc(&tx, origin, DEFAULT_MAX_DRIFT_MS)?;
+            ids.push(self.insert_local_change(
+                &tx,
+                &input.table_name,
+                &input.row_id,
+                input.op_type,
+                input.columns.as_ref(),
+                input.new_row.as_ref(),
+                input.old_row.as_ref(),
+                &hlc,
+                origin,
+            )?);
+        }
+        if let Some(&group_id) = ids.first() {
+            tx.execute(
+                "UPDATE local_changes SET group_id=?1
+WHERE change_id IN (SELECT value FROM json_each(?2))",
+                params![group_id, serde_json::to_string(&ids)?],
+            )?;
+        }
+        self.commit(tx)?;
+        Ok(ids)
+    }
+
+    /// The checks and INSERT behind `log_local_change`, inside the caller's transaction.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_local_change(
+        &self,
+        tx: &Transaction<'_>,
+        table_name: &str,
+        row_id: &str,
+        op_type: OpType,
+        columns: Option<&serde_json::Value>,
+        new_row: Option<&serde_json::Value>,
+        old_row: Option<&serde_json::Value>,
+        hlc: &str,
+        origin: &str,
+    ) -> Result<i64, SyncError> {
+        validate_change_key(table_name, row_id)?;
+        #[cfg(feature = "jsonschema")]
+        if let Some(row) = new_row {
+            self.validate_row(table_name, row)?;
+        }
+        let columns = columns.map(|v| v.to_string());
+        let new_row = new_row.map(|v| v.to_string());
+        let old_row = old_row.map(|v| v.to_string());
+        if let Some(max) = self.max_op_bytes {
+            let size: usize = [&columns, &new_row, &old_row]
+                .iter()
+                .map(|s| s.as_ref().map_or(0, String::len))
+                .sum();
+            if size > max {
+                return Err(SyncError::State("op too large"));
+            }
+        }
+        let (hlc_ms, hlc_ctr, _) = parse_hlc(hlc);
+        let row_id = resolve_alias(tx, table_name, row_id)?;
+        tx.prepare_cached(
+            "INSERT INTO local_changes
+(table_name,row_id,op_type,columns,new_row,old_row,hlc,origin,sync_status,hlc_ms,hlc_ctr)
+VALUES (?1,?2,?3,?4,?5,?6,?7,?8,'pending',?9,?10)",
+        )?
+        .execute(params![
+            table_name,
+            &row_id,
+            op_type.as_str(),
+            columns,
+            new_row,
+            old_row,
+            hlc,
+            origin,
+            hlc_ms as i64,
+            hlc_ctr,
+        ])
+        .map_err(|e| match e.sqlite_error() {
+            Some(f) if f.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE => {
+                SyncError::Conflict("duplicate (hlc, origin)")
+            }
+            _ => SyncError::from(e),
+        })?;
+        Ok(tx.last_insert_rowid())
+    }
+
+    /// Record a local change described by a validated `LocalChangeInput`.
+    pub fn log(&self, input: &LocalChangeInput, origin: &str) -> Result<i64, SyncError> {
+        let hlc = self.next_hlc(origin)?;
+        self.log_local_change(
+            &input.table_name,
+            &input.row_id,
+            input.op_type,
+            input.columns.as_ref(),
+            input.new_row.as_ref(),
+            input.old_row.as_ref(),
+            &hlc,
+            origin,
+        )
+    }
+
     /// Convenience: record a local INSERT with a full-row snapshot.
     pub fn log_insert_fullrow(
         &self,
@@ -239,6 +1787,26 @@ VALUES (?1,?2,?3,?4,?5,?6,?7,?8,'pending')",
         )
     }
 
+    /// Record an UPDATE whose `columns` are computed with `diff_columns(old_row, new_row)`,
+    /// storing that list and `new_row` but not `old_row`. A field dropped from `new_row` is
+    /// listed, so `merge::lww_merge_row` removes it on the other side.
+    /// Returns `None` without logging when nothing changed.
+    pub fn log_update_diff(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        old_row: &serde_json::Value,
+        new_row: &serde_json::Value,
+        origin: &str,
+    ) -> Result<Option<i64>, SyncError> {
+        let columns = diff_columns(old_row, new_row);
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let columns = serde_json::Value::from(columns);
+        self.log_update(table_name, row_id, Some(&columns), Some(new_row), None, origin).map(Some)
+    }
+
     /// Convenience: record a local DELETE.
     pub fn log_delete(
         &self,
@@ -259,172 +1827,3517 @@ VALUES (?1,?2,?3,?4,?5,?6,?7,?8,'pending')",
         )
     }
 
-    /// Fetch pending local changes that must be pushed.
-    pub fn get_pending_ops(&self, limit: i64) -> Result<Vec<Change>, SyncError> {
-        let mut stmt = self.conn.prepare(
-"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status
-FROM local_changes
-WHERE sync_status='pending'
-ORDER BY change_id ASC
-LIMIT ?1",
-)?;
-
-        let rows = stmt.query_map(params![limit], |r| {
-            let op_str: String = r.get(3)?;
-            let to_json = |idx| -> rusqlite::Result<Option<serde_json::Value>> {
-                let s: Option<String> = r.get(idx)?;
-                Ok(match s {
-                    Some(raw) => Some(
-                        serde_json::from_str::<serde_json::Value>(&raw)
-                            .unwrap_or(serde_json::Value::Null),
-                    ),
-                    None => None,
-                })
-            };
-
-            Ok(Change {
-                change_id: r.get(0)?,
-                table_name: r.get(1)?,
-                row_id: r.get(2)?,
-                op_type: match op_str.as_str() {
-                    "INSERT" => OpType::Insert,
-                    "UPDATE" => OpType::Update,
-                    "DELETE" => OpType::Delete,
-                    _ => OpType::Update,
-                },
-                columns: to_json(4)?,
-                new_row: to_json(5)?,
-                old_row: to_json(6)?,
-                hlc: r.get(7)?,
-                origin: r.get(8)?,
-                sync_status: r.get(9)?,
-            })
-        })?;
-
-        let mut out = Vec::new();
-        for ch in rows {
-            out.push(ch?);
-        }
-        Ok(out)
+    /// Like `log_delete`, keeping `old_row` (the row as it was before the delete) in the
+    /// change so it can be audited or restored later; see `tombstones`.
+    pub fn log_delete_with_snapshot(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        old_row: &serde_json::Value,
+        origin: &str,
+    ) -> Result<i64, SyncError> {
+        let hlc = self.next_hlc(origin)?;
+        self.log_local_change(
+            table_name,
+            row_id,
+            OpType::Delete,
+            None,
+            None,
+            Some(old_row),
+            &hlc,
+            origin,
+        )
     }
 
-    /// Mark a set of local changes as 'pushed' (server accepted receipt).
-    pub fn mark_ops_pushed(&self, ids: &[i64]) -> Result<(), SyncError> {
-        let tx = self.conn.unchecked_transaction()?;
-        for id in ids {
-            tx.execute(
-                "UPDATE local_changes SET sync_status='pushed' WHERE change_id=?1",
-                params![id],
-            )?;
-        }
-        tx.commit()?;
-        Ok(())
+    /// The newest `limit` local DELETEs (any status), newest HLC first, optionally only of
+    /// `table_name`. `old_row` holds the snapshot when logged with `log_delete_with_snapshot`.
+    /// Tombstones are kept until the acked changes are purged or compacted.
+    pub fn tombstones(&self, table_name: Option<&str>, limit: i64) -> Result<Vec<Change>, SyncError> {
+        let mut stmt = self.reader().prepare(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE op_type='DELETE' AND (?1 IS NULL OR table_name=?1)
+ORDER BY hlc_ms DESC, hlc_ctr DESC, origin DESC
+LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![table_name, limit], change_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
-    /// Mark a set of local changes as 'acked' (server has canonically applied them).
-    pub fn mark_ops_acked(&self, ids: &[i64]) -> Result<(), SyncError> {
-        let tx = self.conn.unchecked_transaction()?;
-        for id in ids {
-            tx.execute(
-                "UPDATE local_changes SET sync_status='acked' WHERE change_id=?1",
-                params![id],
-            )?;
+    /// Log `new_row` for a key only if it differs (per `merge::values_equal`) from the
+    /// `new_row` of the key's most recent change, whatever its status. Logs an INSERT when
+    /// the key has no history or ends in a DELETE, a full-row UPDATE otherwise.
+    /// Returns `None` when nothing was logged.
+    pub fn log_if_changed(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        new_row: &serde_json::Value,
+        origin: &str,
+    ) -> Result<Option<i64>, SyncError> {
+        let latest = self
+            .conn
+            .query_row(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE table_name=?1 AND row_id=?2
+ORDER BY hlc_ms DESC, hlc_ctr DESC, change_id DESC
+LIMIT 1",
+                params![table_name, row_id],
+                change_from_row,
+            )
+            .optional()?;
+        match latest {
+            Some(ch) if ch.op_type != OpType::Delete => {
+                if ch.new_row.as_ref().is_some_and(|r| values_equal(r, new_row)) {
+                    return Ok(None);
+                }
+                self.log_update(table_name, row_id, None, Some(new_row), None, origin).map(Some)
+            }
+            _ => self.log_insert_fullrow(table_name, row_id, new_row, origin).map(Some),
         }
-        tx.commit()?;
-        Ok(())
     }
 
-    /// Apply a batch of remote operations transactionally and idempotently.
-    /// - Uses `applied_remote_ops` to skip duplicates.
-    /// - Delegates actual domain table writes to `applier`.
-    pub fn apply_remote_ops<A: ApplyDomainOp>(
+    /// Merge `from_row_id` into `to_row_id`, e.g. after the server deduplicated two client
+    /// inserts of the same record onto a canonical id. Pending changes for `from_row_id`
+    /// are re-keyed to `to_row_id`, and later local changes for `from_row_id` are logged
+    /// under `to_row_id`. Returns the number of pending changes re-keyed.
+    pub fn alias_row(
         &self,
-        ops: &[RemoteOp],
-        applier: &A,
-    ) -> Result<(), SyncError> {
-        let tx = self.conn.unchecked_transaction()?;
-        for op in ops {
-            let seen = tx
-                .query_row(
-                    "SELECT 1 FROM applied_remote_ops WHERE remote_id=?1",
-                    params![&op.remote_id],
-                    |_r| Ok(()),
-                )
-                .optional()?;
-            if seen.is_some() {
-                continue; // idempotent skip
-            }
+        table_name: &str,
+        from_row_id: &str,
+        to_row_id: &str,
+    ) -> Result<usize, SyncError> {
+        validate_change_key(table_name, from_row_id)?;
+        validate_change_key(table_name, to_row_id)?;
+        let tx = self.begin()?;
+        let to_row_id = resolve_alias(&tx, table_name, to_row_id)?;
+        if to_row_id == from_row_id {
+            return Err(SyncError::State("row cannot be aliased to itself"));
+        }
+        tx.execute(
+            "INSERT INTO row_aliases(table_name, from_row_id, to_row_id) VALUES(?1,?2,?3)
+ON CONFLICT(table_name, from_row_id) DO UPDATE SET to_row_id=excluded.to_row_id",
+            params![table_name, from_row_id, &to_row_id],
+        )?;
+        // Keep aliases flat: anything that pointed at `from` now points at `to`.
+        tx.execute(
+            "UPDATE row_aliases SET to_row_id=?3 WHERE table_name=?1 AND to_row_id=?2",
+            params![table_name, from_row_id, &to_row_id],
+        )?;
+        let n = tx.execute(
+            "UPDATE local_changes SET row_id=?3
+WHERE table_name=?1 AND row_id=?2 AND sync_status='pending'",
+            params![table_name, from_row_id, &to_row_id],
+        )?;
+        self.commit(tx)?;
+        Ok(n)
+    }
 
-            applier.apply(&tx, op)?;
+    /// Like `log_insert_fullrow`, using the origin stored by `set_own_origin`.
+    pub fn log_insert_fullrow_own(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        new_row: &serde_json::Value,
+    ) -> Result<i64, SyncError> {
+        let origin = self.require_own_origin()?;
+        self.log_insert_fullrow(table_name, row_id, new_row, &origin)
+    }
 
-            let now_ms = Utc::now().timestamp_millis();
-            tx.execute(
-                "INSERT INTO applied_remote_ops(remote_id, applied_ms) VALUES(?1, ?2)",
-                params![&op.remote_id, now_ms],
+    /// Like `log_update`, using the origin stored by `set_own_origin`.
+    pub fn log_update_own(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        columns: Option<&serde_json::Value>,
+        new_row: Option<&serde_json::Value>,
+        old_row: Option<&serde_json::Value>,
+    ) -> Result<i64, SyncError> {
+        let origin = self.require_own_origin()?;
+        self.log_update(table_name, row_id, columns, new_row, old_row, &origin)
+    }
+
+    /// Like `log_delete`, using the origin stored by `set_own_origin`.
+    pub fn log_delete_own(&self, table_name: &str, row_id: &str) -> Result<i64, SyncError> {
+        let origin = self.require_own_origin()?;
+        self.log_delete(table_name, row_id, &origin)
+    }
+
+    /// Replace a key's acked history with a single change carrying the replayed state
+    /// (see `merge::replay_changes`) and the latest acked HLC: an INSERT of the current
+    /// row, or a DELETE if the row ends as a tombstone. The baseline reuses the newest
+    /// folded `change_id`, so pending/pushed changes for the key stay layered on top.
+    /// Does nothing when fewer than two acked changes exist.
+    pub fn squash_row_history(&self, table_name: &str, row_id: &str) -> Result<(), SyncError> {
+        let tx = self.begin()?;
+        let acked: Vec<Change> = {
+            let mut stmt = tx.prepare(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE table_name=?1 AND row_id=?2 AND sync_status='acked'
+ORDER BY hlc_ms ASC, hlc_ctr ASC, change_id ASC",
             )?;
+            let rows = stmt.query_map(params![table_name, row_id], change_from_row)?;
+            rows.collect::<Result<_, _>>()?
+        };
+        if acked.len() < 2 {
+            return Ok(());
+        }
+
+        let state = crate::merge::replay_changes(&acked);
+        let last_id = acked.iter().map(|c| c.change_id).max().unwrap_or(0);
+        let latest = &acked[acked.len() - 1];
+        for ch in &acked {
+            tx.execute("DELETE FROM local_changes WHERE change_id=?1", params![ch.change_id])?;
         }
-        tx.commit()?;
+        let (op_type, new_row) = match &state {
+            Some(row) => (OpType::Insert, Some(row.to_string())),
+            None => (OpType::Delete, None),
+        };
+        let (hlc_ms, hlc_ctr, _) = parse_hlc(&latest.hlc);
+        tx.execute(
+            "INSERT INTO local_changes
+(change_id,table_name,row_id,op_type,new_row,hlc,origin,sync_status,hlc_ms,hlc_ctr)
+VALUES (?1,?2,?3,?4,?5,?6,?7,'acked',?8,?9)",
+            params![
+                last_id,
+                table_name,
+                row_id,
+                op_type.as_str(),
+                new_row,
+                &latest.hlc,
+                &latest.origin,
+                hlc_ms as i64,
+                hlc_ctr,
+            ],
+        )?;
+        self.commit(tx)?;
         Ok(())
     }
 
-    /// Get or set the last remote cursor (server-side checkpoint).
-    pub fn get_remote_cursor(&self) -> Result<Option<String>, SyncError> {
-        let cur: Option<String> = self
-            .conn
-            .query_row("SELECT v FROM sync_kv WHERE k='remote_cursor'", [], |r| {
-                r.get(0)
-            })
+    /// Number of 'pending' local changes.
+    pub fn count_pending(&self) -> Result<i64, SyncError> {
+        let n = self.reader().query_row(
+            "SELECT COUNT(*) FROM local_changes WHERE sync_status='pending'",
+            [],
+            |r| r.get(0),
+        )?;
+        Ok(n)
+    }
+
+    /// Number of local changes in each status. 'pending', 'pushed' and 'acked' are always
+    /// present, with 0 when there are none.
+    pub fn count_by_status(&self) -> Result<HashMap<String, i64>, SyncError> {
+        let mut out: HashMap<String, i64> =
+            ["pending", "pushed", "acked"].iter().map(|s| (s.to_string(), 0)).collect();
+        let mut stmt = self
+            .reader()
+            .prepare("SELECT sync_status, COUNT(*) FROM local_changes GROUP BY sync_status")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?;
+        for row in rows {
+            let (status, n) = row?;
+            out.insert(status, n);
+        }
+        Ok(out)
+    }
+
+    /// Distinct tables with local changes, sorted, optionally only those with changes in `status`.
+    pub fn tracked_tables(&self, status: Option<&str>) -> Result<Vec<String>, SyncError> {
+        let mut stmt = self.reader().prepare(
+            "SELECT DISTINCT table_name FROM local_changes
+WHERE ?1 IS NULL OR sync_status=?1
+ORDER BY table_name",
+        )?;
+        let rows = stmt.query_map(params![status], |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Fetch pending local changes that must be pushed.
+    pub fn get_pending_ops(&self, limit: i64) -> Result<Vec<Change>, SyncError> {
+        self.query_pending(None, 0, limit)
+    }
+
+    /// Like `get_pending_ops`, restricted to changes of `table_name`.
+    pub fn get_pending_ops_for_table(&self, table_name: &str, limit: i64) -> Result<Vec<Change>, SyncError> {
+        self.query_pending(Some(table_name), 0, limit)
+    }
+
+    /// Iterate over pending changes in `change_id` order, fetching `batch` rows at a time
+    /// (egress transform applied), so a large backlog never sits in memory at once. Each batch
+    /// reads rows after the last one yielded, so changes acked meanwhile are not yielded and
+    /// changes logged meanwhile are.
+    pub fn pending_ops_iter(&self, batch: i64) -> PendingOps<'_, 'c> {
+        PendingOps { engine: self, batch: batch.max(1), last_seen: 0, buf: VecDeque::new(), done: false }
+    }
+
+    fn query_pending(
+        &self,
+        table_name: Option<&str>,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<Change>, SyncError> {
+        let mut stmt = self.reader().prepare_cached(&format!(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE sync_status='pending' AND change_id > ?2{}
+ORDER BY change_id ASC
+LIMIT ?1",
+            if table_name.is_some() { " AND table_name = ?3" } else { "" }
+        ))?;
+
+        let rows = match table_name {
+            Some(t) => stmt.query_map(params![limit, after_id, t], change_from_row)?,
+            None => stmt.query_map(params![limit, after_id], change_from_row)?,
+        };
+
+        let mut out = Vec::new();
+        for ch in rows {
+            let mut ch = ch?;
+            if let Some(f) = &self.egress {
+                f(&mut ch);
+                ch.content_hash = content_hash(ch.new_row.as_ref());
+            }
+            out.push(ch);
+        }
+        Ok(out)
+    }
+
+    /// `get_pending_ops(limit)` as a zstd-compressed JSON array, serialized straight into the
+    /// encoder instead of through an intermediate string.
+    #[cfg(feature = "zstd")]
+    pub fn get_pending_ops_zstd(&self, limit: i64) -> Result<Vec<u8>, SyncError> {
+        let ops = self.get_pending_ops(limit)?;
+        let mut enc = zstd::stream::Encoder::new(Vec::new(), 0).map_err(serde_json::Error::io)?;
+        serde_json::to_writer(&mut enc, &ops)?;
+        Ok(enc.finish().map_err(serde_json::Error::io)?)
+    }
+
+    /// Pending changes grouped for pushing: each `log_batch` group is one inner list, every
+    /// ungrouped change a list of its own, in `change_id` order (egress transform applied).
+    /// Covers the first `limit` pending changes plus the pending rest of any group they start,
+    /// so a group is never split across pushes.
+    pub fn get_pending_groups(&self, limit: i64) -> Result<Vec<Vec<Change>>, SyncError> {
+        let mut stmt = self.reader().prepare(
+"WITH head AS (
+SELECT change_id, group_id FROM local_changes
+WHERE sync_status='pending'
+ORDER BY change_id ASC
+LIMIT ?1
+)
+SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE sync_status='pending'
+AND (change_id IN (SELECT change_id FROM head)
+OR group_id IN (SELECT group_id FROM head WHERE group_id IS NOT NULL))
+ORDER BY change_id ASC",
+        )?;
+        let rows = stmt.query_map(params![limit], change_from_row)?;
+
+        let mut groups: Vec<Vec<Change>> = Vec::new();
+        let mut index: HashMap<i64, usize> = HashMap::new();
+        for ch in rows {
+            let mut ch = ch?;
+            if let Some(f) = &self.egress {
+                f(&mut ch);
+                ch.content_hash = content_hash(ch.new_row.as_ref());
+            }
+            match ch.group_id {
+                Some(g) if index.contains_key(&g) => groups[index[&g]].push(ch),
+                Some(g) => {
+                    index.insert(g, groups.len());
+                    groups.push(vec![ch]);
+                }
+                None => groups.push(vec![ch]),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Gather up to `limit` pending ops (egress transform applied), the origin stored by
+    /// `set_own_origin` and the HLC of that origin's newest local change into a push envelope.
+    pub fn build_push_envelope(&self, limit: i64) -> Result<PushEnvelope, SyncError> {
+        let origin = self.require_own_origin()?;
+        let ops = self.get_pending_ops(limit)?;
+        let client_max_hlc = self
+            .reader()
+            .query_row(
+                "SELECT hlc FROM local_changes WHERE origin=?1 ORDER BY hlc_ms DESC, hlc_ctr DESC LIMIT 1",
+                params![&origin],
+                |r| r.get(0),
+            )
             .optional()?;
-        Ok(cur)
+        let change_ids = ops.iter().map(|c| c.change_id).collect();
+        Ok(PushEnvelope { origin, ops, client_max_hlc, change_ids })
     }
-    pub fn set_remote_cursor(&self, cursor: &str) -> Result<(), SyncError> {
-        self.conn.execute(
-            "INSERT INTO sync_kv(k,v) VALUES('remote_cursor',?1)
-            ON CONFLICT(k) DO UPDATE SET v=excluded.v",
-            params![cursor],
+
+    /// Fetch one local change as stored, whatever its status. No egress transform is applied.
+    pub fn get_change(&self, change_id: i64) -> Result<Option<Change>, SyncError> {
+        let ch = self
+            .reader()
+            .query_row(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE change_id=?1",
+                params![change_id],
+                change_from_row,
+            )
+            .optional()?;
+        Ok(ch)
+    }
+
+    /// Move a set of local changes to `status` ('pending', 'pushed' or 'acked') in one
+    /// statement, e.g. straight from 'pending' to 'acked' when the server acks synchronously.
+    /// The ids are bound as a single JSON array, so SQLite's host-parameter limit does not
+    /// apply however many there are, and the one UPDATE is atomic on its own.
+    pub fn mark_status(&self, ids: &[i64], status: &str) -> Result<usize, SyncError> {
+        mark_status_in(self.conn, ids, status)
+    }
+
+    /// Mark a set of local changes as 'pushed' (server accepted receipt).
+    pub fn mark_ops_pushed(&self, ids: &[i64]) -> Result<(), SyncError> {
+        self.mark_status(ids, "pushed").map(|_| ())
+    }
+
+    /// Mark a set of local changes as 'acked' (server has canonically applied them).
+    pub fn mark_ops_acked(&self, ids: &[i64]) -> Result<(), SyncError> {
+        self.mark_status(ids, "acked").map(|_| ())
+    }
+
+    /// Mark every change of `group_id` as 'acked', for servers that ack a group as a whole.
+    /// Returns the number of changes updated.
+    pub fn mark_group_acked(&self, group_id: i64) -> Result<usize, SyncError> {
+        let n = self.conn.execute(
+            "UPDATE local_changes SET sync_status='acked' WHERE group_id=?1",
+            params![group_id],
         )?;
+        Ok(n)
+    }
+
+    /// Put changes marked 'pushed' before `older_than_ms` (unix millis) back to 'pending', so
+    /// ops left in flight by a crash between push and ack are sent again. Changes pushed before
+    /// `pushed_ms` was recorded count as stuck. Returns the number of changes re-queued.
+    pub fn resweep_stuck_pushed(&self, older_than_ms: i64) -> Result<usize, SyncError> {
+        let n = self.conn.execute(
+            "UPDATE local_changes SET sync_status='pending'
+WHERE sync_status='pushed' AND (pushed_ms IS NULL OR pushed_ms < ?1)",
+            params![older_than_ms],
+        )?;
+        Ok(n)
+    }
+
+    /// Mark local changes as 'acked' by HLC token instead of `change_id`, for servers
+    /// that confirm with the HLCs they applied. Each token is matched on `(hlc, origin)`,
+    /// with the origin taken from the token itself. Unknown tokens are ignored.
+    /// Returns the number of changes marked.
+    pub fn confirm_pushed_by_hlc(&self, hlcs: &[&str]) -> Result<usize, SyncError> {
+        let tx = self.begin()?;
+        let mut n = 0;
+        for hlc in hlcs {
+            let (_, _, origin) = parse_hlc(hlc);
+            n += tx.execute(
+                "UPDATE local_changes SET sync_status='acked'
+WHERE hlc=?1 AND origin=?2 AND sync_status IN ('pending','pushed')",
+                params![hlc, origin],
+            )?;
+        }
+        self.commit(tx)?;
+        Ok(n)
+    }
+
+    /// Finish a sync cycle in a single transaction: mark `acked_ids` as 'acked', put the
+    /// `pushed_ids` the server did not ack back to 'pending' and, if given, advance the remote
+    /// cursor. Either every write lands or none does, so a crash can't leave the oplog
+    /// statuses and the cursor out of step.
+    pub fn commit_cycle(
+        &self,
+        pushed_ids: &[i64],
+        acked_ids: &[i64],
+        new_cursor: Option<&str>,
+    ) -> Result<(), SyncError> {
+        self.commit_cycle_at(&self.cursor_key(), pushed_ids, acked_ids, new_cursor)
+    }
+
+    /// `commit_cycle` advancing the cursor of `table_name` (see `set_remote_cursor_for`)
+    /// instead of the feed-wide one.
+    pub fn commit_cycle_for(
+        &self,
+        table_name: &str,
+        pushed_ids: &[i64],
+        acked_ids: &[i64],
+        new_cursor: Option<&str>,
+    ) -> Result<(), SyncError> {
+        self.commit_cycle_at(&self.table_cursor_key(table_name), pushed_ids, acked_ids, new_cursor)
+    }
+
+    fn commit_cycle_at(
+        &self,
+        cursor_key: &str,
+        pushed_ids: &[i64],
+        acked_ids: &[i64],
+        new_cursor: Option<&str>,
+    ) -> Result<(), SyncError> {
+        let acked: HashSet<i64> = acked_ids.iter().copied().collect();
+        let unacked: Vec<i64> = pushed_ids.iter().copied().filter(|id| !acked.contains(id)).collect();
+        let tx = self.begin()?;
+        if !unacked.is_empty() {
+            mark_status_in(&tx, &unacked, "pending")?;
+        }
+        if !acked_ids.is_empty() {
+            mark_status_in(&tx, acked_ids, "acked")?;
+        }
+        if let Some(cursor) = new_cursor {
+            tx.execute(
+                "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+                params![cursor_key, cursor],
+            )?;
+        }
+        self.commit(tx)?;
+        if let Some(cursor) = new_cursor {
+            self.cursor_advanced(cursor);
+        }
         Ok(())
     }
 
-    /// Return the current integer schema version stored in `sync_kv`.
-    pub fn get_schema_version(&self) -> Result<i32, SyncError> {
-        let ver: Option<String> = self
+    /// Delete every 'acked' change. Returns the number of rows removed.
+    pub fn purge_acked(&self) -> Result<usize, SyncError> {
+        let n = self
             .conn
-            .query_row("SELECT v FROM sync_kv WHERE k='schema_version'", [], |r| r.get(0))
-            .optional()?;
-        Ok(ver.and_then(|s| s.parse::<i32>().ok()).unwrap_or(1))
+            .execute("DELETE FROM local_changes WHERE sync_status='acked'", [])?;
+        Ok(n)
     }
 
-    /// Run migrations up to `target_version` transactionally.
-    /// This placeholder uses no-op steps and only bumps the stored version.
-    /// Domain-specific migrations can be wired here in the future.
-    pub fn run_migrations(&self, target_version: i32) -> Result<(), SyncError> {
-        if target_version < 1 {
-            return Err(SyncError::State("invalid target_version"));
+    /// Delete 'acked' changes except the newest `keep_recent` (by HLC) of each
+    /// `(table_name, row_id)`, bounding the oplog while keeping the latest marker per row.
+    /// Returns the number of rows removed.
+    pub fn compact_acked(&self, keep_recent: i64) -> Result<usize, SyncError> {
+        if keep_recent < 0 {
+            return Err(SyncError::State("keep_recent must be >= 0"));
         }
-        let current = self.get_schema_version()?;
-        if current >= target_version { return Ok(()); }
+        let tx = self.begin()?;
+        let n = tx.execute(
+            "DELETE FROM local_changes WHERE change_id IN (
+  SELECT change_id FROM (
+    SELECT change_id, ROW_NUMBER() OVER (
+      PARTITION BY table_name, row_id
+      ORDER BY hlc_ms DESC, hlc_ctr DESC, origin DESC
+    ) AS rn
+    FROM local_changes
+    WHERE sync_status='acked'
+  ) WHERE rn > ?1
+)",
+            params![keep_recent],
+        )?;
+        self.commit(tx)?;
+        Ok(n)
+    }
+
+    /// How many more `change_id`s AUTOINCREMENT can hand out before SQLite refuses
+    /// inserts with SQLITE_FULL.
+    pub fn change_id_headroom(&self) -> Result<i64, SyncError> {
+        let seq: Option<i64> = self
+            .reader()
+            .query_row(
+                "SELECT seq FROM sqlite_sequence WHERE name='local_changes'",
+                [],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(i64::MAX - seq.unwrap_or(0))
+    }
 
-        let tx = self.conn.unchecked_transaction()?;
-        // Apply stepwise migrations here as needed.
-        // For now, we just advance the version without schema changes.
+    /// Reset the `change_id` sequence to the highest id still present (0 if the table is empty).
+    ///
+    /// Safe procedure after a full purge:
+    /// 1. make sure nothing is 'pending' or 'pushed' (push and ack everything first),
+    /// 2. call `purge_acked`,
+    /// 3. call this method.
+    ///
+    /// Refuses to run while 'pending' or 'pushed' rows remain, since the host may
+    /// still be tracking their ids.
+    pub fn reset_change_id_sequence(&self) -> Result<(), SyncError> {
+        let tx = self.begin()?;
+        let in_flight: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM local_changes WHERE sync_status IN ('pending','pushed')",
+            [],
+            |r| r.get(0),
+        )?;
+        if in_flight > 0 {
+            return Err(SyncError::State("pending or pushed changes remain"));
+        }
         tx.execute(
-            "INSERT INTO sync_kv(k,v) VALUES('schema_version',?1)
-ON CONFLICT(k) DO UPDATE SET v=excluded.v",
-            params![target_version.to_string()],
+            "UPDATE sqlite_sequence
+SET seq=(SELECT IFNULL(MAX(change_id),0) FROM local_changes)
+WHERE name='local_changes'",
+            [],
         )?;
-        tx.commit()?;
+        self.commit(tx)?;
         Ok(())
     }
 
-    /// Execute closure `f` inside a transaction and commit if `f` returns Ok.
-    pub fn with_tx<R, F>(&self, f: F) -> Result<R, SyncError>
-    where
-        F: FnOnce(&rusqlite::Transaction<'_>) -> Result<R, SyncError>,
-    {
-        let tx = self.conn.unchecked_transaction()?;
-        let result = f(&tx)?;
-        tx.commit()?;
+    /// Feed every local change with `change_id >= from_change_id`, whatever its sync status,
+    /// to `applier` in `change_id` order inside one transaction, e.g. to rebuild domain tables
+    /// from the oplog. Nothing is recorded in `applied_remote_ops` and no status changes.
+    /// Returns the number of changes replayed.
+    pub fn replay_local<A: ApplyDomainOp>(&self, applier: &A, from_change_id: i64) -> Result<usize, SyncError> {
+        let tx = self.begin()?;
+        let changes = {
+            let mut stmt = tx.prepare(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE change_id >= ?1
+ORDER BY change_id ASC",
+            )?;
+            let rows = stmt.query_map(params![from_change_id], change_from_row)?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for ch in &changes {
+            applier.apply(&tx, &ch.to_remote_op())?;
+        }
+        self.commit(tx)?;
+        Ok(changes.len())
+    }
+
+    /// Apply a batch of remote operations transactionally and idempotently.
+    /// - Uses `applied_remote_ops` to skip duplicates.
+    /// - Delegates actual domain table writes to `applier`.
+    ///
+    /// Returns how many ops reached `applier` and how many were skipped (duplicates,
+    /// unknown tables).
+    pub fn apply_remote_ops<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+    ) -> Result<ApplyStats, SyncError> {
+        let (applied, _) = self.apply_batch(ops, applier, None, None)?;
+        Ok(ApplyStats { applied, skipped: ops.len() - applied })
+    }
+
+    /// Apply NDJSON remote ops (one `RemoteOp` per line) from `reader`, committing every
+    /// `sub_batch` ops so memory stays bounded however long the feed is. Blank lines are
+    /// ignored. On error, sub-batches already committed stay applied; re-running is safe
+    /// thanks to dedup. Returns the number of ops read.
+    pub fn apply_remote_ops_stream<R: BufRead, A: ApplyDomainOp>(
+        &self,
+        reader: R,
+        applier: &A,
+        sub_batch: usize,
+    ) -> Result<usize, SyncError> {
+        if sub_batch == 0 {
+            return Err(SyncError::State("sub_batch must be > 0"));
+        }
+        let mut batch = Vec::with_capacity(sub_batch);
+        let mut total = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let op = serde_json::from_str::<RemoteOp>(&line)?;
+            total += 1;
+            if self.recent_ids.borrow_mut().touch(&op.remote_id) {
+                continue; // known duplicate
+            }
+            batch.push(op);
+            if batch.len() == sub_batch {
+                self.apply_remote_ops(&batch, applier)?;
+                self.remember_applied(&batch);
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            self.apply_remote_ops(&batch, applier)?;
+            self.remember_applied(&batch);
+        }
+        Ok(total)
+    }
+
+    fn remember_applied(&self, ops: &[RemoteOp]) {
+        let mut recent = self.recent_ids.borrow_mut();
+        for op in ops {
+            recent.insert(&op.remote_id);
+        }
+    }
+
+    /// Like `apply_remote_ops`, but resolve contention with pending local changes by
+    /// last-writer-wins (or the table's `DeletePolicy`) and report every conflict instead of failing on it.
+    /// When the local change has the higher HLC the remote op is recorded as applied
+    /// without reaching `applier`. Returns how many ops were handed to `applier`.
+    pub fn apply_remote_ops_collect_conflicts<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+    ) -> Result<(usize, Vec<Conflict>), SyncError> {
+        self.apply_batch(ops, applier, Some(ConflictPolicy::LastWriterWins), None)
+    }
+
+    /// Like `apply_remote_ops_collect_conflicts`, settling contention with pending local
+    /// changes by `policy`. Every contended op is reported, whichever side wins.
+    pub fn apply_remote_ops_with_policy<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+        policy: ConflictPolicy,
+    ) -> Result<(usize, Vec<Conflict>), SyncError> {
+        self.apply_batch(ops, applier, Some(policy), None)
+    }
+
+    /// Like `apply_remote_ops_collect_conflicts`, but return one `OpOutcome` per input op,
+    /// in input order.
+    pub fn apply_remote_ops_outcomes<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+    ) -> Result<Vec<OpOutcome>, SyncError> {
+        let mut outcomes = Vec::with_capacity(ops.len());
+        self.apply_batch(ops, applier, Some(ConflictPolicy::LastWriterWins), Some(&mut outcomes))?;
+        Ok(outcomes)
+    }
+
+    /// Run the batch exactly like `apply_remote_ops_outcomes`, then roll everything back,
+    /// leaving the database untouched. Unlike `plan_apply` this goes through `applier`, so
+    /// host SQL errors surface too. Hooks (e.g. `set_on_unknown_table`) still fire.
+    pub fn simulate_apply<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+    ) -> Result<ApplyReport, SyncError> {
+        let tx = self.begin()?;
+        let mut outcomes = Vec::with_capacity(ops.len());
+        let policy = Some(ConflictPolicy::LastWriterWins);
+        let (applied, conflicts) =
+            self.apply_in_tx(&tx, ops, applier, policy, Some(&mut outcomes)).map_err(|f| f.err)?;
+        drop(tx); // rollback
+        Ok(ApplyReport { applied, outcomes, conflicts })
+    }
+
+    fn apply_batch<A: ApplyDomainOp>(
+        &self,
+        ops: &[RemoteOp],
+        applier: &A,
+        policy: Option<ConflictPolicy>,
+        outcomes: Option<&mut Vec<OpOutcome>>,
+    ) -> Result<(usize, Vec<Conflict>), SyncError> {
+        let tx = self.begin()?;
+        let result = match self.apply_in_tx(&tx, ops, applier, policy, outcomes) {
+            Ok(result) => result,
+            Err(ApplyFailure { err, op }) => {
+                drop(tx); // rollback, then count the failure outside the batch
+                if let Some(op) = op {
+                    // Best effort: the applier's error is the one the caller needs to see.
+                    let _ = record_apply_failure(self.conn, &self.feed, &op, &err, false);
+                }
+                return Err(err);
+            }
+        };
+        self.commit(tx)?;
         Ok(result)
     }
+
+    /// The `remote_id`s of `ops` already recorded in `applied_remote_ops` for this feed,
+    /// fetched in one query rather than one lookup per op.
+    fn applied_among(&self, conn: &Connection, ops: &[RemoteOp]) -> Result<HashSet<String>, SyncError> {
+        if ops.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let ids: Vec<&str> = ops.iter().map(|o| o.remote_id.as_str()).collect();
+        let mut stmt = conn.prepare_cached(
+            "SELECT remote_id FROM applied_remote_ops
+WHERE feed=?1 AND remote_id IN (SELECT value FROM json_each(?2))",
+        )?;
+        let rows = stmt.query_map(params![&self.feed, serde_json::to_string(&ids)?], |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Hand `op` to `applier`. With `with_max_apply_attempts`, the applier runs in a savepoint
+    /// and an op at its last attempt is quarantined instead of failing: returns false then.
+    fn apply_or_quarantine<A: ApplyDomainOp>(
+        &self,
+        tx: &Transaction<'_>,
+        op: &RemoteOp,
+        applier: &A,
+    ) -> Result<bool, ApplyFailure> {
+        let Some(max) = self.max_apply_attempts else {
+            applier.apply(tx, op)?;
+            return Ok(true);
+        };
+        tx.execute_batch("SAVEPOINT apply_op")?;
+        let err = match applier.apply(tx, op) {
+            Ok(()) => {
+                tx.execute_batch("RELEASE apply_op")?;
+                tx.prepare_cached("DELETE FROM failed_remote_ops WHERE feed=?1 AND remote_id=?2")?
+                    .execute(params![&self.feed, &op.remote_id])?;
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+        tx.execute_batch("ROLLBACK TO apply_op; RELEASE apply_op")?;
+        if err.is_transient() {
+            return Err(err.into());
+        }
+        let attempts: i64 = tx
+            .query_row(
+                "SELECT attempts FROM failed_remote_ops WHERE feed=?1 AND remote_id=?2",
+                params![&self.feed, &op.remote_id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+        if attempts + 1 < max {
+            return Err(ApplyFailure { err, op: Some(Box::new(op.clone())) });
+        }
+        record_apply_failure(tx, &self.feed, op, &err, true)?;
+        Ok(false)
+    }
+
+    fn apply_in_tx<A: ApplyDomainOp>(
+        &self,
+        tx: &Transaction<'_>,
+        ops: &[RemoteOp],
+        applier: &A,
+        policy: Option<ConflictPolicy>, // None: no contention check, remote always applied
+        mut outcomes: Option<&mut Vec<OpOutcome>>,
+    ) -> Result<(usize, Vec<Conflict>), ApplyFailure> {
+        let mut applied = 0;
+        let mut conflicts = Vec::new();
+        let mut newest: Option<Hlc> = None;
+        let mut seen = self.applied_among(tx, ops)?;
+        for op in ops {
+            if !seen.insert(op.remote_id.clone()) {
+                if let Some(out) = outcomes.as_deref_mut() {
+                    out.push(OpOutcome::Duplicate);
+                }
+                continue; // idempotent skip
+            }
+
+            let known = self.is_known_table(&op.table_name);
+            if !known && let Some(f) = &self.on_unknown_table {
+                f(op);
+            }
+
+            let mut winner = Winner::Remote;
+            let mut outcome = if known { OpOutcome::Applied } else { OpOutcome::UnknownTable };
+            if known
+                && let Some(policy) = policy
+                && let Some((local_hlc, local_op)) = latest_pending(tx, &op.table_name, &op.row_id, self.tie_break)?
+            {
+                winner = match policy {
+                    ConflictPolicy::RemoteWins => Winner::Remote,
+                    ConflictPolicy::LocalWins => Winner::Local,
+                    ConflictPolicy::LastWriterWins => self.conflict_winner(op, &local_hlc, local_op),
+                };
+                outcome = OpOutcome::Conflict(winner);
+                let conflict = Conflict {
+                    remote_id: op.remote_id.clone(),
+                    table_name: op.table_name.clone(),
+                    row_id: op.row_id.clone(),
+                    local_hlc,
+                    local_op,
+                    remote_hlc: op.hlc.clone(),
+                    winner,
+                };
+                if let Some(f) = &self.on_conflict {
+                    f(op, &conflict);
+                }
+                conflicts.push(conflict);
+            }
+
+            if outcomes.is_some()
+                && outcome == OpOutcome::Applied
+                && op.op_type != OpType::Insert
+                && is_tombstoned(tx, &op.table_name, &op.row_id, self.tie_break)?
+            {
+                outcome = OpOutcome::Tombstoned;
+            }
+
+            if known && winner == Winner::Remote {
+                if self.apply_or_quarantine(tx, op, applier)? {
+                    applied += 1;
+                    if self.supersede_local {
+                        supersede_pending(tx, op, self.tie_break)?;
+                    }
+                } else {
+                    outcome = OpOutcome::Quarantined;
+                }
+            }
+
+            if let Some(out) = outcomes.as_deref_mut() {
+                out.push(outcome);
+            }
+
+            let now_ms = Utc::now().timestamp_millis();
+            tx.prepare_cached(
+                "INSERT INTO applied_remote_ops(feed, remote_id, applied_ms, hlc, origin, table_name)
+VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![&self.feed, &op.remote_id, now_ms, &op.hlc, &op.origin, &op.table_name])?;
+            advance_watermark(tx, &op.origin, &op.hlc)?;
+
+            let hlc = Hlc::parse(&op.hlc);
+            if newest.as_ref().is_none_or(|n| hlc > *n) {
+                newest = Some(hlc);
+            }
+        }
+        if let Some(hlc) = newest {
+            observe_hlc_in(tx, &hlc)?;
+        }
+        Ok((applied, conflicts))
+    }
+
+    /// Replace the contents of a server-owned table with a full snapshot, in one transaction:
+    /// every object in `rows` is upserted on `pk_col`, and local rows whose `pk_col` is not in
+    /// the snapshot are deleted. The snapshot is recorded in `applied_remote_ops` under
+    /// `snapshot:<table>:<snapshot_hlc>`, so re-applying it is a no-op.
+    /// Returns `(upserted, deleted)`, or `(0, 0)` for an already applied snapshot.
+    pub fn apply_table_snapshot(
+        &self,
+        table_name: &str,
+        pk_col: &str,
+        rows: &[serde_json::Value],
+        snapshot_hlc: &str,
+    ) -> Result<(usize, usize), SyncError> {
+        if !is_identifier(table_name) || !is_identifier(pk_col) {
+            return Err(SyncError::State("table_name and pk_col must be valid identifiers"));
+        }
+        let marker = format!("snapshot:{}:{}", table_name, snapshot_hlc);
+        let tx = self.begin()?;
+        let seen = tx
+            .query_row(
+                "SELECT 1 FROM applied_remote_ops WHERE feed=?1 AND remote_id=?2",
+                params![&self.feed, &marker],
+                |_r| Ok(()),
+            )
+            .optional()?;
+        if seen.is_some() {
+            return Ok((0, 0));
+        }
+
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in rows {
+            let obj = row
+                .as_object()
+                .ok_or(SyncError::State("snapshot row is not an object"))?;
+            let pk = obj
+                .get(pk_col)
+                .filter(|v| !v.is_null())
+                .ok_or(SyncError::State("snapshot row has no primary key"))?;
+            upsert_row(&tx, table_name, pk_col, obj)?;
+            keys.push(pk.clone());
+        }
+
+        let deleted = tx.execute(
+            &format!(
+                "DELETE FROM {0} WHERE {1} NOT IN (SELECT value FROM json_each(?1))",
+                table_name, pk_col
+            ),
+            params![serde_json::Value::Array(keys).to_string()],
+        )?;
+
+        let (_, _, origin) = parse_hlc(snapshot_hlc);
+        let now_ms = Utc::now().timestamp_millis();
+        tx.execute(
+            "INSERT INTO applied_remote_ops(feed, remote_id, applied_ms, hlc, origin, table_name)
+VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            params![&self.feed, &marker, now_ms, snapshot_hlc, origin, table_name],
+        )?;
+        self.commit(tx)?;
+        Ok((rows.len(), deleted))
+    }
+
+    /// Regenerate `table_name` from the oplog, e.g. after the domain table was corrupted:
+    /// for every row id with acked changes, fold them (see `merge::replay_changes`) and
+    /// upsert the result on `pk_col`, or delete the row if it ends as a tombstone. A row
+    /// whose replayed state lacks `pk_col` gets its row id. Runs in the caller's `tx`, so
+    /// the host decides when to commit. Returns the number of row ids rebuilt.
+    pub fn rebuild_table(
+        &self,
+        tx: &Transaction<'_>,
+        table_name: &str,
+        pk_col: &str,
+    ) -> Result<usize, SyncError> {
+        if !is_identifier(table_name) || !is_identifier(pk_col) {
+            return Err(SyncError::State("table_name and pk_col must be valid identifiers"));
+        }
+        let acked: Vec<Change> = {
+            let mut stmt = tx.prepare(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes
+WHERE table_name=?1 AND sync_status='acked'
+ORDER BY row_id ASC, hlc_ms ASC, hlc_ctr ASC, change_id ASC",
+            )?;
+            let rows = stmt.query_map(params![table_name], change_from_row)?;
+            rows.collect::<Result<_, _>>()?
+        };
+
+        let mut rebuilt = 0;
+        for history in acked.chunk_by(|a, b| a.row_id == b.row_id) {
+            let row_id = &history[0].row_id;
+            match crate::merge::replay_changes(history) {
+                Some(serde_json::Value::Object(mut row)) => {
+                    row.entry(pk_col.to_string())
+                        .or_insert_with(|| serde_json::Value::String(row_id.clone()));
+                    upsert_row(tx, table_name, pk_col, &row)?;
+                }
+                Some(_) => return Err(SyncError::State("replayed row is not an object")),
+                None => {
+                    tx.execute(
+                        &format!("DELETE FROM {} WHERE {}=?1", table_name, pk_col),
+                        params![row_id],
+                    )?;
+                }
+            }
+            rebuilt += 1;
+        }
+        Ok(rebuilt)
+    }
+
+    /// Classify each op of a batch the way `apply_remote_ops_collect_conflicts` would
+    /// treat it, without writing anything.
+    pub fn plan_apply(&self, ops: &[RemoteOp]) -> Result<ApplyPlan, SyncError> {
+        let conn = self.reader();
+        let mut seen_in_batch = std::collections::HashSet::new();
+        let mut plan = ApplyPlan::default();
+        for op in ops {
+            let seen = conn
+                .query_row(
+                    "SELECT 1 FROM applied_remote_ops WHERE feed=?1 AND remote_id=?2",
+                    params![&self.feed, &op.remote_id],
+                    |_r| Ok(()),
+                )
+                .optional()?;
+            let action = if seen.is_some() || !seen_in_batch.insert(op.remote_id.as_str()) {
+                PlanAction::Duplicate
+            } else if !self.is_known_table(&op.table_name) {
+                PlanAction::UnknownTable
+            } else if let Some((local_hlc, local_op)) = latest_pending(conn, &op.table_name, &op.row_id, self.tie_break)? {
+                let winner = self.conflict_winner(op, &local_hlc, local_op);
+                PlanAction::Conflict(Conflict {
+                    remote_id: op.remote_id.clone(),
+                    table_name: op.table_name.clone(),
+                    row_id: op.row_id.clone(),
+                    local_hlc,
+                    local_op,
+                    remote_hlc: op.hlc.clone(),
+                    winner,
+                })
+            } else if op.op_type != OpType::Insert && is_tombstoned(conn, &op.table_name, &op.row_id, self.tie_break)? {
+                PlanAction::Tombstoned
+            } else {
+                PlanAction::Apply
+            };
+            plan.entries.push((op.remote_id.clone(), action));
+        }
+        Ok(plan)
+    }
+
+    /// CRC32 of a domain row, for comparing against a checksum computed by the server.
+    /// The row is canonicalized as compact JSON of `{column: value}` with keys sorted
+    /// (reals as JSON numbers, blobs as byte arrays). Returns `None` if no row matches.
+    pub fn row_checksum(
+        &self,
+        table_name: &str,
+        pk_col: &str,
+        row_id: &str,
+    ) -> Result<Option<u32>, SyncError> {
+        if !is_identifier(table_name) || !is_identifier(pk_col) {
+            return Err(SyncError::State("table_name and pk_col must be valid identifiers"));
+        }
+        let row: Option<BTreeMap<String, serde_json::Value>> = self
+            .reader()
+            .query_row(
+                &format!("SELECT * FROM {} WHERE {}=?1", table_name, pk_col),
+                params![row_id],
+                |r| {
+                    let stmt = r.as_ref();
+                    (0..stmt.column_count())
+                        .map(|i| Ok((stmt.column_name(i)?.to_string(), sql_to_json(r.get_ref(i)?))))
+                        .collect()
+                },
+            )
+            .optional()?;
+        let Some(row) = row else { return Ok(None) };
+        let row = serde_json::Value::Object(row.into_iter().collect());
+        Ok(Some(crc32fast::hash(canonical_json(&row).as_bytes())))
+    }
+
+    /// For each op, in order, the conflict it would have with the newest 'pending' or 'pushed'
+    /// local change for its row, and which side would win. Ops without such a change are
+    /// left out. Read-only; duplicates are not filtered (see `plan_apply` for that).
+    pub fn preview_conflicts(&self, ops: &[RemoteOp]) -> Result<Vec<Conflict>, SyncError> {
+        let conn = self.reader();
+        let mut out = Vec::new();
+        for op in ops {
+            let Some((local_hlc, local_op)) =
+                latest_in_status(conn, &op.table_name, &op.row_id, "'pending','pushed'", self.tie_break)?
+            else {
+                continue;
+            };
+            out.push(Conflict {
+                remote_id: op.remote_id.clone(),
+                table_name: op.table_name.clone(),
+                row_id: op.row_id.clone(),
+                winner: self.conflict_winner(op, &local_hlc, local_op),
+                local_hlc,
+                local_op,
+                remote_hlc: op.hlc.clone(),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Highest HLC seen per origin: the newer of the origin's newest local change and its
+    /// watermark (`get_watermarks`). Watermarks only move forward, so pruning applied ops
+    /// does not move the frontier back. Remote ops applied before their HLC was recorded
+    /// are not counted.
+    pub fn hlc_frontier(&self) -> Result<HashMap<String, String>, SyncError> {
+        let mut frontier = self.get_watermarks()?;
+        let mut stmt = self.reader().prepare(
+            "SELECT o.origin,
+(SELECT hlc FROM local_changes l WHERE l.origin=o.origin ORDER BY hlc_ms DESC, hlc_ctr DESC LIMIT 1)
+FROM (SELECT DISTINCT origin FROM local_changes) o",
+        )?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (origin, hlc) = row?;
+            if frontier.get(&origin).is_none_or(|max| parse_hlc(max) < parse_hlc(&hlc)) {
+                frontier.insert(origin, hlc);
+            }
+        }
+        Ok(frontier)
+    }
+
+    /// Ops of this engine's feed quarantined after `with_max_apply_attempts` failures,
+    /// most recently failed first.
+    pub fn get_quarantined_ops(&self) -> Result<Vec<QuarantinedOp>, SyncError> {
+        let mut stmt = self.reader().prepare(
+            "SELECT op, attempts, last_error, failed_ms FROM failed_remote_ops
+WHERE feed=?1 AND quarantined=1
+ORDER BY failed_ms DESC",
+        )?;
+        let rows = stmt.query_map(params![&self.feed], |r| {
+            Ok((r.get::<_, String>(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })?;
+        let mut out = Vec::new();
+        for row in rows {
+            let (op, attempts, last_error, failed_ms) = row?;
+            out.push(QuarantinedOp { op: serde_json::from_str(&op)?, attempts, last_error, failed_ms });
+        }
+        Ok(out)
+    }
+
+    /// Newest HLC applied from each remote origin (origin -> HLC), as tracked by the apply
+    /// paths. Only ever moves forward; an origin that stops advancing points at a stuck peer.
+    pub fn get_watermarks(&self) -> Result<HashMap<String, String>, SyncError> {
+        let mut stmt = self.reader().prepare("SELECT origin, hlc FROM origin_watermarks")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        let mut out = HashMap::new();
+        for row in rows {
+            let (origin, hlc) = row?;
+            out.insert(origin, hlc);
+        }
+        Ok(out)
+    }
+
+    /// List `(remote_id, applied_ms)` for remote ops applied at or after `since_ms`,
+    /// ordered by `applied_ms`. Useful to reconcile against the server after a suspected divergence.
+    pub fn applied_ops_since(&self, since_ms: i64, limit: i64) -> Result<Vec<(String, i64)>, SyncError> {
+        let mut stmt = self.reader().prepare(
+            "SELECT remote_id, applied_ms
+FROM applied_remote_ops
+WHERE feed=?1 AND applied_ms >= ?2
+ORDER BY applied_ms ASC, remote_id ASC
+LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![&self.feed, since_ms, limit], |r| Ok((r.get(0)?, r.get(1)?)))?;
+
+        let mut out = Vec::new();
+        for row in rows {
+            out.push(row?);
+        }
+        Ok(out)
+    }
+
+    /// Delete this feed's `applied_remote_ops` rows with `applied_ms` before `older_than_ms`
+    /// (unix millis). Returns the number of rows removed.
+    ///
+    /// These rows are what makes applying idempotent: if the server redelivers a pruned op
+    /// (e.g. the cursor is reset or lost), it will be applied again. Only prune well past
+    /// anything the server could still resend; when unsure use `prune_applied_ops_default`.
+    pub fn prune_applied_ops(&self, older_than_ms: i64) -> Result<usize, SyncError> {
+        let n = self.conn.execute(
+            "DELETE FROM applied_remote_ops WHERE feed=?1 AND applied_ms < ?2",
+            params![&self.feed, older_than_ms],
+        )?;
+        // Pruned ids may still be cached; drop the cache so a redelivery reaches the database.
+        if n > 0 {
+            self.recent_ids.borrow_mut().clear();
+        }
+        Ok(n)
+    }
+
+    /// `prune_applied_ops` with a cutoff of `DEFAULT_APPLIED_OPS_TTL_MS` before now.
+    pub fn prune_applied_ops_default(&self) -> Result<usize, SyncError> {
+        self.prune_applied_ops(Utc::now().timestamp_millis() - DEFAULT_APPLIED_OPS_TTL_MS)
+    }
+
+    /// Forget that `remote_ids` were applied on this engine's feed, so they are applied
+    /// again if redelivered (e.g. after the host rebuilt the tables they wrote).
+    /// Returns the number of markers removed.
+    pub fn invalidate_applied(&self, remote_ids: &[&str]) -> Result<usize, SyncError> {
+        let n = self.conn.execute(
+            "DELETE FROM applied_remote_ops
+WHERE feed=?1 AND remote_id IN (SELECT value FROM json_each(?2))",
+            params![&self.feed, serde_json::to_string(remote_ids)?],
+        )?;
+        let mut recent = self.recent_ids.borrow_mut();
+        for id in remote_ids {
+            recent.remove(id);
+        }
+        Ok(n)
+    }
+
+    /// Like `invalidate_applied`, for every op on this feed that targeted `table_name`.
+    /// Ops applied before the table was recorded are not matched.
+    pub fn invalidate_applied_for_table(&self, table_name: &str) -> Result<usize, SyncError> {
+        let n = self.conn.execute(
+            "DELETE FROM applied_remote_ops WHERE feed=?1 AND table_name=?2",
+            params![&self.feed, table_name],
+        )?;
+        // The cache doesn't know the table of each id; drop it rather than risk skipping.
+        self.recent_ids.borrow_mut().clear();
+        Ok(n)
+    }
+
+    /// Get or set the last remote cursor (server-side checkpoint) of this engine's feed.
+    pub fn get_remote_cursor(&self) -> Result<Option<String>, SyncError> {
+        self.read_cursor(&self.cursor_key())
+    }
+    pub fn set_remote_cursor(&self, cursor: &str) -> Result<(), SyncError> {
+        self.write_cursor(&self.cursor_key(), cursor)
+    }
+
+    /// Get or set the cursor of `table_name` alone, for tables pulled from their own endpoint.
+    /// Independent of the feed-wide cursor and of every other table's cursor.
+    pub fn get_remote_cursor_for(&self, table_name: &str) -> Result<Option<String>, SyncError> {
+        self.read_cursor(&self.table_cursor_key(table_name))
+    }
+    pub fn set_remote_cursor_for(&self, table_name: &str, cursor: &str) -> Result<(), SyncError> {
+        self.write_cursor(&self.table_cursor_key(table_name), cursor)
+    }
+
+    fn read_cursor(&self, key: &str) -> Result<Option<String>, SyncError> {
+        let cur: Option<String> = self
+            .reader()
+            .query_row("SELECT v FROM sync_kv WHERE k=?1", params![key], |r| r.get(0))
+            .optional()?;
+        Ok(cur)
+    }
+
+    fn write_cursor(&self, key: &str, cursor: &str) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+            ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![key, cursor],
+        )?;
+        self.cursor_advanced(cursor);
+        Ok(())
+    }
+
+    /// Like `set_remote_cursor`, but fail with "cursor regressed" when `new` sorts strictly
+    /// before the stored cursor under `with_cursor_order`, instead of silently re-pulling.
+    /// Storing the same cursor again is allowed.
+    pub fn set_remote_cursor_checked(&self, new: &str) -> Result<(), SyncError> {
+        let numeric =
+            |c: &str| c.trim().parse::<i128>().map_err(|_| SyncError::State("cursor is not numeric"));
+        let tx = self.begin()?;
+        let current: Option<String> = tx
+            .query_row("SELECT v FROM sync_kv WHERE k=?1", params![self.cursor_key()], |r| {
+                r.get(0)
+            })
+            .optional()?;
+        if let Some(current) = current {
+            let regressed = match self.cursor_order {
+                CursorOrder::Lexical => new < current.as_str(),
+                CursorOrder::Numeric => numeric(new)? < numeric(&current)?,
+            };
+            if regressed {
+                return Err(SyncError::State("cursor regressed"));
+            }
+        } else if self.cursor_order == CursorOrder::Numeric {
+            numeric(new)?;
+        }
+        tx.execute(
+            "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![self.cursor_key(), new],
+        )?;
+        self.commit(tx)?;
+        self.cursor_advanced(new);
+        Ok(())
+    }
+
+    /// Set the remote cursor to `new` only if it currently equals `expected` (`None`: unset),
+    /// in one transaction. Returns whether the swap happened; on `false` nothing is written.
+    pub fn compare_and_set_cursor(&self, expected: Option<&str>, new: &str) -> Result<bool, SyncError> {
+        let tx = self.begin()?;
+        let current: Option<String> = tx
+            .query_row("SELECT v FROM sync_kv WHERE k=?1", params![self.cursor_key()], |r| {
+                r.get(0)
+            })
+            .optional()?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![self.cursor_key(), new],
+        )?;
+        self.commit(tx)?;
+        self.cursor_advanced(new);
+        Ok(true)
+    }
+
+    /// Persist this install's stable origin id in `sync_kv`.
+    pub fn set_own_origin(&self, origin: &str) -> Result<(), SyncError> {
+        if origin.trim().is_empty() {
+            return Err(SyncError::State("empty origin"));
+        }
+        self.conn.execute(
+            "INSERT INTO sync_kv(k,v) VALUES('own_origin',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![origin],
+        )?;
+        Ok(())
+    }
+
+    /// Return the origin stored by `set_own_origin`, if any.
+    pub fn own_origin(&self) -> Result<Option<String>, SyncError> {
+        let origin: Option<String> = self
+            .conn
+            .query_row("SELECT v FROM sync_kv WHERE k='own_origin'", [], |r| r.get(0))
+            .optional()?;
+        Ok(origin)
+    }
+
+    fn require_own_origin(&self) -> Result<String, SyncError> {
+        self.own_origin()?
+            .ok_or(SyncError::State("own origin not set"))
+    }
+
+    /// Move every local change from origin `from` to origin `to`, rewriting the origin
+    /// segment of each HLC token as well. Returns the number of rows rewritten.
+    /// Fails without writing anything if a rewritten `(hlc, origin)` pair already exists.
+    /// The stored own origin follows along when it equals `from`.
+    pub fn rewrite_origin(&self, from: &str, to: &str) -> Result<usize, SyncError> {
+        if to.trim().is_empty() {
+            return Err(SyncError::State("empty origin"));
+        }
+        if from == to {
+            return Ok(0);
+        }
+        let tx = self.begin()?;
+        let rows: Vec<(i64, String)> = {
+            let mut stmt =
+                tx.prepare("SELECT change_id, hlc FROM local_changes WHERE origin=?1")?;
+            let it = stmt.query_map(params![from], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            it.collect::<Result<_, _>>()?
+        };
+
+        for (id, hlc) in &rows {
+            let mut parts = hlc.splitn(3, '-');
+            let new_hlc = match (parts.next(), parts.next(), parts.next()) {
+                (Some(ms), Some(ctr), Some(o)) if o == from => format!("{}-{}-{}", ms, ctr, to),
+                _ => hlc.clone(),
+            };
+            let taken = tx
+                .query_row(
+                    "SELECT 1 FROM local_changes WHERE hlc=?1 AND origin=?2",
+                    params![&new_hlc, to],
+                    |_r| Ok(()),
+                )
+                .optional()?;
+            if taken.is_some() {
+                return Err(SyncError::State("origin rewrite collides with an existing change"));
+            }
+            tx.execute(
+                "UPDATE local_changes SET origin=?1, hlc=?2 WHERE change_id=?3",
+                params![to, &new_hlc, id],
+            )?;
+        }
+
+        tx.execute(
+            "UPDATE sync_kv SET v=?2 WHERE k='own_origin' AND v=?1",
+            params![from, to],
+        )?;
+        self.commit(tx)?;
+        Ok(rows.len())
+    }
+
+    /// Store a JSON Schema that every logged `new_row` for `table_name` must satisfy.
+    /// The schema is persisted in `sync_kv`, so it applies to every engine bound to this
+    /// database. Rows are validated as logged, so UPDATE snapshots must pass too.
+    #[cfg(feature = "jsonschema")]
+    pub fn set_table_schema(&self, table_name: &str, schema: serde_json::Value) -> Result<(), SyncError> {
+        let validator =
+            jsonschema::validator_for(&schema).map_err(|e| SyncError::Validation(e.to_string()))?;
+        let raw = schema.to_string();
+        self.conn.execute(
+            "INSERT INTO sync_kv(k,v) VALUES(?1,?2)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![format!("table_schema:{}", table_name), &raw],
+        )?;
+        self.validators.borrow_mut().insert(table_name.to_string(), (raw, validator));
+        Ok(())
+    }
+
+    /// Validate `row` against the schema stored for `table_name`, if any. Compiled validators
+    /// are cached per table and only rebuilt when the stored schema text changes, e.g. because
+    /// another engine on this database replaced it.
+    #[cfg(feature = "jsonschema")]
+    fn validate_row(&self, table_name: &str, row: &serde_json::Value) -> Result<(), SyncError> {
+        let raw: Option<String> = self
+            .conn
+            .prepare_cached("SELECT v FROM sync_kv WHERE k=?1")?
+            .query_row(params![format!("table_schema:{}", table_name)], |r| r.get(0))
+            .optional()?;
+        let mut validators = self.validators.borrow_mut();
+        let Some(raw) = raw else {
+            validators.remove(table_name);
+            return Ok(());
+        };
+        if validators.get(table_name).is_none_or(|(cached, _)| *cached != raw) {
+            let schema: serde_json::Value = serde_json::from_str(&raw)?;
+            let validator = jsonschema::validator_for(&schema)
+                .map_err(|e| SyncError::Validation(e.to_string()))?;
+            validators.insert(table_name.to_string(), (raw, validator));
+        }
+        let (_, validator) = &validators[table_name];
+        validator
+            .validate(row)
+            .map_err(|e| SyncError::Validation(format!("{}: {}", table_name, e)))
+    }
+
+    /// Report page counts and file sizes so the host can decide when to checkpoint or vacuum.
+    /// The WAL frame count comes from a PASSIVE checkpoint, which copies what it can into
+    /// the database without waiting on readers or writers.
+    pub fn storage_report(&self) -> Result<StorageReport, SyncError> {
+        let page_count: i64 = self.conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+        let page_size: i64 = self.conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+        let log_frames: i64 =
+            self.conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |r| r.get(1))?;
+        let path = self.conn.path().filter(|p| !p.is_empty());
+        let size = |p: &str| std::fs::metadata(p).ok().map(|m| m.len());
+        Ok(StorageReport {
+            page_count,
+            page_size,
+            wal_frames: (log_frames >= 0).then_some(log_frames),
+            db_bytes: path.and_then(size),
+            wal_bytes: path.and_then(|p| size(&format!("{}-wal", p))),
+        })
+    }
+
+    /// Run `PRAGMA wal_checkpoint(<mode>)`, copying WAL frames back into the database so the
+    /// `-wal` file stops growing; `Truncate` also shrinks it to zero bytes when it completes.
+    pub fn checkpoint(&self, mode: CheckpointMode) -> Result<CheckpointResult, SyncError> {
+        let res = self.conn.query_row(
+            &format!("PRAGMA wal_checkpoint({})", mode.as_str()),
+            [],
+            |r| {
+                Ok(CheckpointResult {
+                    busy: r.get::<_, i64>(0)? != 0,
+                    log_frames: r.get(1)?,
+                    checkpointed_frames: r.get(2)?,
+                })
+            },
+        )?;
+        Ok(res)
+    }
+
+    /// Return the current integer schema version stored in `sync_kv`.
+    pub fn get_schema_version(&self) -> Result<i32, SyncError> {
+        let ver: Option<String> = self
+            .conn
+            .query_row("SELECT v FROM sync_kv WHERE k='schema_version'", [], |r| r.get(0))
+            .optional()?;
+        Ok(ver.and_then(|s| s.parse::<i32>().ok()).unwrap_or(1))
+    }
+
+    /// Run the steps registered with `with_migrations` whose version is in
+    /// `(current, target_version]`, in order, in one transaction, recording each version as
+    /// it is reached and `target_version` at the end. If any step fails nothing is kept.
+    ///
+    /// When `target_version` is below the current version, the `down` of every registered
+    /// step in `(target_version, current]` runs instead, newest first; a step without `down`
+    /// fails the whole downgrade with "irreversible migration".
+    pub fn run_migrations(&self, target_version: i32) -> Result<(), SyncError> {
+        self.run_migrations_with_steps(target_version, &BTreeMap::new())
+    }
+
+    /// Like `run_migrations`, also executing the host SQL in `steps` (keyed by the
+    /// version it migrates to) for every version above the current one, in order,
+    /// inside the same transaction. For a version with both, the registered step runs first.
+    /// Fails if `steps` starts above `current + 1` and would be reached, since the versions
+    /// in between have no SQL.
+    pub fn run_migrations_with_steps(
+        &self,
+        target_version: i32,
+        steps: &BTreeMap<i32, String>,
+    ) -> Result<(), SyncError> {
+        if target_version < 1 {
+            return Err(SyncError::State("invalid target_version"));
+        }
+        if self.migrations.windows(2).any(|w| w[0].version == w[1].version) {
+            return Err(SyncError::State("duplicate migration version"));
+        }
+        let current = self.get_schema_version()?;
+        if current > target_version {
+            if steps.range(target_version + 1..=current).next().is_some() {
+                return Err(SyncError::State("irreversible migration"));
+            }
+            return self.migrate_down(current, target_version);
+        }
+        if current == target_version { return Ok(()); }
+
+        if let Some(&first) = steps.keys().next()
+            && first > current + 1
+            && target_version >= first
+        {
+            return Err(SyncError::State("missing migration steps before the first registered one"));
+        }
+
+        let range = current + 1..=target_version;
+        let mut versions: BTreeSet<i32> = steps.range(range.clone()).map(|(&v, _)| v).collect();
+        versions.extend(self.migrations.iter().map(|m| m.version).filter(|v| range.contains(v)));
+        versions.insert(target_version);
+
+        let tx = self.begin()?;
+        for v in versions {
+            if let Some(m) = self.migrations.iter().find(|m| m.version == v) {
+                (m.up)(&tx)?;
+            }
+            if let Some(sql) = steps.get(&v) {
+                tx.execute_batch(sql)?;
+            }
+            tx.execute(
+                "INSERT INTO sync_kv(k,v) VALUES('schema_version',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+                params![v.to_string()],
+            )?;
+        }
+        self.commit(tx)?;
+        Ok(())
+    }
+
+    fn migrate_down(&self, current: i32, target_version: i32) -> Result<(), SyncError> {
+        let tx = self.begin()?;
+        for m in self.migrations.iter().rev() {
+            if m.version <= target_version || m.version > current {
+                continue;
+            }
+            let down = m.down.ok_or(SyncError::State("irreversible migration"))?;
+            down(&tx)?;
+            tx.execute(
+                "INSERT INTO sync_kv(k,v) VALUES('schema_version',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+                params![(m.version - 1).to_string()],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO sync_kv(k,v) VALUES('schema_version',?1)
+ON CONFLICT(k) DO UPDATE SET v=excluded.v",
+            params![target_version.to_string()],
+        )?;
+        self.commit(tx)?;
+        Ok(())
+    }
+
+    /// Execute closure `f` inside a transaction and commit if `f` returns Ok.
+    pub fn with_tx<R, F>(&self, f: F) -> Result<R, SyncError>
+    where
+        F: FnOnce(&rusqlite::Transaction<'_>) -> Result<R, SyncError>,
+    {
+        let tx = self.begin()?;
+        let result = f(&tx)?;
+        self.commit(tx)?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn engine(conn: &Connection) -> SyncEngine<'_> {
+        let engine = SyncEngine::new(conn).unwrap();
+        engine.init_schema().unwrap();
+        engine
+    }
+
+    /// Path of a fresh file database for `name`, removing leftovers of earlier runs.
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("se_oplog_{}_{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm", "-journal"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        path
+    }
+
+    /// Applier recording the `remote_id` of every op it is handed.
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<String>>);
+
+    impl ApplyDomainOp for Recorder {
+        fn apply(&self, _tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+            self.0.borrow_mut().push(op.remote_id.clone());
+            Ok(())
+        }
+    }
+
+    fn remote(remote_id: &str, row_id: &str, op_type: OpType, hlc: &str) -> RemoteOp {
+        RemoteOp {
+            remote_id: remote_id.to_string(),
+            table_name: "trips".to_string(),
+            row_id: row_id.to_string(),
+            op_type,
+            columns: None,
+            new_row: (op_type != OpType::Delete).then(|| json!({"id": row_id})),
+            old_row: None,
+            hlc: hlc.to_string(),
+            origin: Hlc::parse(hlc).origin,
+        }
+    }
+
+    /// Log a pending local `op_type` change of `trips/row_id` with a fixed HLC.
+    fn local(e: &SyncEngine<'_>, row_id: &str, op_type: OpType, hlc: &str) -> i64 {
+        let row = json!({"id": row_id});
+        let new_row = (op_type != OpType::Delete).then_some(&row);
+        let origin = Hlc::parse(hlc).origin;
+        e.log_local_change("trips", row_id, op_type, None, new_row, None, hlc, &origin).unwrap()
+    }
+
+    fn status_of(conn: &Connection, change_id: i64) -> String {
+        conn.query_row(
+            "SELECT sync_status FROM local_changes WHERE change_id=?1",
+            params![change_id],
+            |r| r.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn commit_cycle_acks_and_advances_cursor_together() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a = e.log_insert_fullrow("trips", "1", &json!({"id": 1}), "dev").unwrap();
+        let b = e.log_insert_fullrow("trips", "2", &json!({"id": 2}), "dev").unwrap();
+
+        let c = e.log_insert_fullrow("trips", "3", &json!({"id": 3}), "dev").unwrap();
+        e.mark_ops_pushed(&[a, b, c]).unwrap();
+
+        e.commit_cycle(&[a, b, c], &[a, b], Some("c1")).unwrap();
+
+        assert_eq!(status_of(&conn, a), "acked");
+        assert_eq!(status_of(&conn, b), "acked");
+        assert_eq!(status_of(&conn, c), "pending", "pushed but not acked");
+        assert_eq!(e.get_remote_cursor().unwrap().as_deref(), Some("c1"));
+    }
+
+    #[test]
+    fn commit_cycle_rolls_back_acks_when_cursor_write_fails() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a = e.log_insert_fullrow("trips", "1", &json!({"id": 1}), "dev").unwrap();
+        let b = e.log_insert_fullrow("trips", "2", &json!({"id": 2}), "dev").unwrap();
+        e.mark_ops_pushed(&[a, b]).unwrap();
+        // Simulate a crash between the status updates and the cursor write.
+        conn.execute_batch(
+            "CREATE TRIGGER crash BEFORE INSERT ON sync_kv WHEN NEW.k LIKE 'remote_cursor%'
+BEGIN SELECT RAISE(ABORT, 'crash'); END;",
+        )
+        .unwrap();
+
+        assert!(e.commit_cycle(&[a, b], &[a], Some("c1")).is_err());
+
+        assert_eq!(status_of(&conn, a), "pushed");
+        assert_eq!(status_of(&conn, b), "pushed");
+        assert_eq!(e.get_remote_cursor().unwrap(), None);
+    }
+
+    #[test]
+    fn applied_ops_since_is_bounded_and_ordered() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        for (id, ms) in [("r3", 300), ("r1", 100), ("r4", 400), ("r2", 200)] {
+            conn.execute(
+                "INSERT INTO applied_remote_ops(feed, remote_id, applied_ms) VALUES('', ?1, ?2)",
+                params![id, ms],
+            )
+            .unwrap();
+        }
+
+        let rows = e.applied_ops_since(200, 10).unwrap();
+        assert_eq!(rows, vec![("r2".to_string(), 200), ("r3".to_string(), 300), ("r4".to_string(), 400)]);
+        assert_eq!(e.applied_ops_since(200, 2).unwrap().len(), 2);
+        assert!(e.applied_ops_since(401, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reset_change_id_sequence_after_full_purge() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ids: Vec<i64> = (0..50)
+            .map(|i| e.log_insert_fullrow("trips", &i.to_string(), &json!({"id": i}), "dev").unwrap())
+            .collect();
+        assert_eq!(e.change_id_headroom().unwrap(), i64::MAX - 50);
+
+        assert!(e.reset_change_id_sequence().is_err(), "pending rows must block the reset");
+        e.mark_ops_acked(&ids).unwrap();
+        assert_eq!(e.purge_acked().unwrap(), 50);
+        e.reset_change_id_sequence().unwrap();
+
+        let next = e.log_insert_fullrow("trips", "x", &json!({"id": "x"}), "dev").unwrap();
+        assert_eq!(next, 1);
+        let again = e.log_insert_fullrow("trips", "y", &json!({"id": "y"}), "dev").unwrap();
+        assert_eq!(again, 2);
+    }
+
+    #[test]
+    fn builder_rejects_payloads_that_do_not_fit_the_op() {
+        let build = |b: LocalChangeBuilder| b.build().unwrap_err().to_string();
+        let insert = LocalChangeInput::builder("trips", "1", OpType::Insert);
+        let update = LocalChangeInput::builder("trips", "1", OpType::Update);
+        let delete = LocalChangeInput::builder("trips", "1", OpType::Delete);
+
+        assert!(build(insert.clone()).contains("insert requires new_row"));
+        assert!(build(insert.new_row(json!({})).old_row(json!({}))).contains("insert takes only new_row"));
+        assert!(build(update.clone()).contains("update requires columns or new_row"));
+        assert!(build(update.columns(json!(["a", 1]))).contains("columns must be an array of strings"));
+        assert!(build(delete.new_row(json!({}))).contains("delete takes no columns or new_row"));
+        assert!(build(LocalChangeInput::builder("", "1", OpType::Delete)).contains("table_name is empty"));
+    }
+
+    #[test]
+    fn log_records_a_built_change() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let input = LocalChangeInput::builder("trips", "1", OpType::Update)
+            .columns(json!(["name"]))
+            .new_row(json!({"id": 1, "name": "b"}))
+            .old_row(json!({"id": 1, "name": "a"}))
+            .build()
+            .unwrap();
+
+        let id = e.log(&input, "dev").unwrap();
+
+        let ch = e.get_change(id).unwrap().unwrap();
+        assert_eq!(ch.op_type, OpType::Update);
+        assert_eq!(ch.new_row, Some(json!({"id": 1, "name": "b"})));
+        assert_eq!(ch.old_row, Some(json!({"id": 1, "name": "a"})));
+    }
+
+    #[test]
+    fn own_origin_overloads_use_the_stored_origin() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        assert!(e.log_insert_fullrow_own("trips", "1", &json!({"id": 1})).is_err());
+
+        e.set_own_origin("device-a").unwrap();
+        assert_eq!(e.own_origin().unwrap().as_deref(), Some("device-a"));
+        let ins = e.log_insert_fullrow_own("trips", "1", &json!({"id": 1})).unwrap();
+        let del = e.log_delete_own("trips", "1").unwrap();
+
+        for id in [ins, del] {
+            let ch = e.get_change(id).unwrap().unwrap();
+            assert_eq!(ch.origin, "device-a");
+            assert!(ch.hlc.ends_with("-device-a"));
+        }
+    }
+
+    #[test]
+    fn rewrite_origin_rewrites_the_hlc_segment() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.set_own_origin("old").unwrap();
+        let a = e.log_local_change("trips", "1", OpType::Delete, None, None, None, "1000-0-old", "old").unwrap();
+        let b = e.log_local_change("trips", "2", OpType::Delete, None, None, None, "1000-1-old", "old").unwrap();
+        let other = e.log_local_change("trips", "3", OpType::Delete, None, None, None, "1000-0-peer", "peer").unwrap();
+
+        assert_eq!(e.rewrite_origin("old", "new").unwrap(), 2);
+
+        let hlc_of = |id| e.get_change(id).unwrap().unwrap();
+        assert_eq!((hlc_of(a).hlc, hlc_of(a).origin), ("1000-0-new".to_string(), "new".to_string()));
+        assert_eq!(hlc_of(b).hlc, "1000-1-new");
+        assert_eq!(hlc_of(other).hlc, "1000-0-peer");
+        assert_eq!(e.own_origin().unwrap().as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn rewrite_origin_refuses_to_collide() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a = e.log_local_change("trips", "1", OpType::Delete, None, None, None, "1000-0-old", "old").unwrap();
+        e.log_local_change("trips", "2", OpType::Delete, None, None, None, "1000-0-new", "new").unwrap();
+
+        assert!(e.rewrite_origin("old", "new").is_err());
+        assert_eq!(e.get_change(a).unwrap().unwrap().origin, "old");
+    }
+
+    #[test]
+    fn hlc_range_queries_use_the_hlc_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let id = e.log_local_change("trips", "1", OpType::Delete, None, None, None, "1234-5-dev", "dev").unwrap();
+        let (ms, ctr): (i64, i64) = conn
+            .query_row("SELECT hlc_ms, hlc_ctr FROM local_changes WHERE change_id=?1", params![id], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((ms, ctr), (1234, 5));
+
+        let mut stmt = conn
+            .prepare("EXPLAIN QUERY PLAN SELECT change_id FROM local_changes WHERE hlc_ms BETWEEN ?1 AND ?2 ORDER BY hlc_ms, hlc_ctr")
+            .unwrap();
+        let plan: Vec<String> = stmt
+            .query_map(params![0, 2000], |r| r.get(3))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(plan.iter().any(|p| p.contains("idx_local_changes_hlc")), "{:?}", plan);
+    }
+
+    #[test]
+    fn init_schema_backfills_hlc_columns_of_old_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE local_changes (
+change_id INTEGER PRIMARY KEY AUTOINCREMENT, table_name TEXT NOT NULL, row_id TEXT NOT NULL,
+op_type TEXT NOT NULL, columns TEXT, new_row TEXT, old_row TEXT, hlc TEXT NOT NULL,
+origin TEXT NOT NULL, sync_status TEXT NOT NULL DEFAULT 'pending', UNIQUE(hlc, origin));
+INSERT INTO local_changes(table_name,row_id,op_type,hlc,origin) VALUES('trips','1','DELETE','77-3-dev','dev');",
+        )
+        .unwrap();
+
+        engine(&conn);
+
+        let (ms, ctr): (i64, i64) = conn
+            .query_row("SELECT hlc_ms, hlc_ctr FROM local_changes", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!((ms, ctr), (77, 3));
+    }
+
+    #[test]
+    fn log_rejects_empty_and_unsafe_keys() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let row = json!({"id": 1});
+        let reject = |table: &str, row_id: &str| match e.log_insert_fullrow(table, row_id, &row, "dev") {
+            Err(SyncError::Validation(msg)) => msg,
+            other => panic!("{:?}", other),
+        };
+
+        assert_eq!(reject("", "1"), "table_name is empty");
+        assert_eq!(reject("  \t", "1"), "table_name is empty");
+        assert_eq!(reject("trips; DROP TABLE trips", "1"), "table_name is not a valid identifier");
+        assert_eq!(reject("trips\"", "1"), "table_name is not a valid identifier");
+        assert_eq!(reject("trips", ""), "row_id is empty");
+        assert_eq!(reject("trips", "   "), "row_id is empty");
+        assert_eq!(e.count_pending().unwrap(), 0);
+    }
+
+    #[test]
+    fn read_connection_counts_while_a_write_is_open() {
+        let path = temp_db("read_conn");
+        let conn = Connection::open(&path).unwrap();
+        engine(&conn).log_insert_fullrow("trips", "1", &json!({"id": 1}), "dev").unwrap();
+        let ro = Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        ro.busy_timeout(std::time::Duration::ZERO).unwrap();
+        let e = SyncEngine::new(&conn).unwrap().with_read_connection(&ro);
+
+        let writer = Connection::open(&path).unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        writer
+            .execute(
+                "INSERT INTO local_changes(table_name,row_id,op_type,hlc,origin) VALUES('trips','2','DELETE','9-0-w','w')",
+                [],
+            )
+            .unwrap();
+
+        assert_eq!(e.count_pending().unwrap(), 1, "the uncommitted write is not visible");
+        writer.execute_batch("COMMIT").unwrap();
+        assert_eq!(e.count_pending().unwrap(), 2);
+    }
+
+    fn history(conn: &Connection, row_id: &str) -> Vec<Change> {
+        let mut stmt = conn
+            .prepare(
+"SELECT change_id, table_name, row_id, op_type, columns, new_row, old_row, hlc, origin, sync_status, group_id
+FROM local_changes WHERE table_name='trips' AND row_id=?1 ORDER BY change_id",
+            )
+            .unwrap();
+        stmt.query_map(params![row_id], change_from_row).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn squashed_history_replays_to_the_same_row() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let mut ids = vec![e.log_insert_fullrow("trips", "1", &json!({"id": 1, "name": "a", "n": 0}), "dev").unwrap()];
+        ids.push(e.log_update("trips", "1", Some(&json!(["name"])), Some(&json!({"name": "b"})), None, "dev").unwrap());
+        ids.push(e.log_update("trips", "1", Some(&json!(["n"])), Some(&json!({"n": 2})), None, "dev").unwrap());
+        e.mark_ops_acked(&ids).unwrap();
+        let pending = e.log_update("trips", "1", Some(&json!(["n"])), Some(&json!({"n": 3})), None, "dev").unwrap();
+        let before = crate::merge::replay_changes(&history(&conn, "1"));
+
+        e.squash_row_history("trips", "1").unwrap();
+
+        let after = history(&conn, "1");
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].op_type, OpType::Insert);
+        assert_eq!(after[0].new_row, Some(json!({"id": 1, "name": "b", "n": 2})));
+        assert_eq!(after[1].change_id, pending);
+        assert_eq!(crate::merge::replay_changes(&after), before);
+    }
+
+    #[test]
+    fn winning_remote_op_supersedes_older_pending_change() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_supersede_local(true);
+        let local = e
+            .log_local_change("trips", "1", OpType::Update, None, Some(&json!({"id": "1"})), None, "90-0-dev", "dev")
+            .unwrap();
+        let newer = e
+            .log_local_change("trips", "2", OpType::Update, None, Some(&json!({"id": "2"})), None, "200-0-dev", "dev")
+            .unwrap();
+
+        let ops = [remote("r1", "1", OpType::Update, "100-0-server"), remote("r2", "2", OpType::Update, "100-0-server")];
+        e.apply_remote_ops_with_policy(&ops, &Recorder::default(), ConflictPolicy::LastWriterWins).unwrap();
+
+        assert_eq!(status_of(&conn, local), "acked");
+        assert_eq!(status_of(&conn, newer), "pending");
+    }
+
+    #[test]
+    fn pending_changes_are_kept_without_supersede() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let local = e
+            .log_local_change("trips", "1", OpType::Update, None, Some(&json!({"id": "1"})), None, "90-0-dev", "dev")
+            .unwrap();
+
+        e.apply_remote_ops(&[remote("r1", "1", OpType::Update, "100-0-server")], &Recorder::default()).unwrap();
+
+        assert_eq!(status_of(&conn, local), "pending");
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn table_schema_validates_logged_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.set_table_schema(
+            "trips",
+            json!({"type": "object", "required": ["name"], "properties": {"name": {"type": "string"}}}),
+        )
+        .unwrap();
+
+        assert!(e.log_insert_fullrow("trips", "1", &json!({"name": "ok"}), "dev").is_ok());
+        assert!(matches!(
+            e.log_insert_fullrow("trips", "2", &json!({"name": 5}), "dev"),
+            Err(SyncError::Validation(_))
+        ));
+        assert!(matches!(
+            e.log_update("trips", "1", None, Some(&json!({})), None, "dev"),
+            Err(SyncError::Validation(_))
+        ));
+        assert!(e.log_insert_fullrow("expenses", "1", &json!({"name": 5}), "dev").is_ok());
+        assert_eq!(e.count_pending().unwrap(), 2);
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn replacing_a_table_schema_replaces_the_cached_validator() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.set_table_schema("trips", json!({"properties": {"n": {"type": "string"}}})).unwrap();
+        assert!(e.log_insert_fullrow("trips", "1", &json!({"n": 1}), "dev").is_err());
+
+        e.set_table_schema("trips", json!({"properties": {"n": {"type": "integer"}}})).unwrap();
+        assert!(e.log_insert_fullrow("trips", "1", &json!({"n": 1}), "dev").is_ok());
+
+        // A schema written by another engine on the same database is picked up too.
+        SyncEngine::new(&conn)
+            .unwrap()
+            .set_table_schema("trips", json!({"properties": {"n": {"type": "string"}}}))
+            .unwrap();
+        assert!(e.log_insert_fullrow("trips", "2", &json!({"n": 2}), "dev").is_err());
+    }
+
+    #[test]
+    fn collect_conflicts_reports_every_conflict_of_the_batch() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        local(&e, "3", OpType::Update, "500-0-dev");
+        local(&e, "7", OpType::Update, "50-0-dev");
+        let ops: Vec<RemoteOp> = (0..10)
+            .map(|i| remote(&format!("r{}", i), &i.to_string(), OpType::Update, &format!("{}-0-server", 100 + i)))
+            .collect();
+        let recorder = Recorder::default();
+
+        let (applied, conflicts) = e.apply_remote_ops_collect_conflicts(&ops, &recorder).unwrap();
+
+        assert_eq!(applied, 9);
+        assert_eq!(recorder.0.borrow().len(), 9);
+        assert!(!recorder.0.borrow().contains(&"r3".to_string()));
+        let summary: Vec<(&str, &str, Winner)> = conflicts
+            .iter()
+            .map(|c| (c.row_id.as_str(), c.local_hlc.as_str(), c.winner))
+            .collect();
+        assert_eq!(summary, vec![("3", "500-0-dev", Winner::Local), ("7", "50-0-dev", Winner::Remote)]);
+        assert_eq!(e.applied_ops_since(0, 100).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn delete_journal_mode_creates_no_wal_file() {
+        let path = temp_db("journal_delete");
+        let conn = Connection::open(&path).unwrap();
+        let e = SyncEngine::new(&conn).unwrap();
+
+        e.init_schema_with_journal_mode(JournalMode::Delete).unwrap();
+        e.log_insert_fullrow("trips", "1", &json!({"id": 1}), "dev").unwrap();
+
+        let mode: String = conn.query_row("PRAGMA journal_mode", [], |r| r.get(0)).unwrap();
+        assert_eq!(mode, "delete");
+        assert!(!std::path::Path::new(&format!("{}-wal", path.display())).exists());
+        let res = e.checkpoint(CheckpointMode::Truncate).unwrap();
+        assert!(!res.busy);
+        assert_eq!(e.storage_report().unwrap().wal_frames, None);
+    }
+
+    #[test]
+    fn plan_apply_matches_the_actual_apply() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.apply_remote_ops(&[remote("old", "0", OpType::Insert, "10-0-server")], &Recorder::default()).unwrap();
+        local(&e, "1", OpType::Update, "500-0-dev");
+        local(&e, "2", OpType::Update, "50-0-dev");
+        let deleted = local(&e, "3", OpType::Delete, "60-0-dev");
+        e.mark_status(&[deleted], "acked").unwrap();
+        let ops = vec![
+            remote("new", "9", OpType::Insert, "100-0-server"),
+            remote("old", "0", OpType::Insert, "10-0-server"),
+            remote("lose", "1", OpType::Update, "100-0-server"),
+            remote("win", "2", OpType::Update, "100-0-server"),
+            remote("gone", "3", OpType::Update, "100-0-server"),
+            remote("new", "9", OpType::Insert, "100-0-server"),
+        ];
+
+        let plan = e.plan_apply(&ops).unwrap();
+        assert_eq!(e.applied_ops_since(0, 100).unwrap().len(), 1, "planning writes nothing");
+        let outcomes = e.apply_remote_ops_outcomes(&ops, &Recorder::default()).unwrap();
+
+        let predicted: Vec<OpOutcome> = plan
+            .entries
+            .iter()
+            .map(|(_, a)| match a {
+                PlanAction::Apply => OpOutcome::Applied,
+                PlanAction::Duplicate => OpOutcome::Duplicate,
+                PlanAction::Conflict(c) => OpOutcome::Conflict(c.winner),
+                PlanAction::UnknownTable => OpOutcome::UnknownTable,
+                PlanAction::Tombstoned => OpOutcome::Tombstoned,
+            })
+            .collect();
+        assert_eq!(predicted, outcomes);
+        assert_eq!(
+            outcomes,
+            vec![
+                OpOutcome::Applied,
+                OpOutcome::Duplicate,
+                OpOutcome::Conflict(Winner::Local),
+                OpOutcome::Conflict(Winner::Remote),
+                OpOutcome::Tombstoned,
+                OpOutcome::Duplicate,
+            ]
+        );
+        assert_eq!(plan.apply_count(), 3);
+    }
+
+    #[test]
+    fn feeds_keep_separate_dedup_and_cursors() {
+        let conn = Connection::open_in_memory().unwrap();
+        let a = engine(&conn).with_feed("a");
+        let b = SyncEngine::new(&conn).unwrap().with_feed("b");
+        let op = remote("42", "1", OpType::Insert, "100-0-server");
+        let recorder = Recorder::default();
+
+        assert_eq!(a.apply_remote_ops(std::slice::from_ref(&op), &recorder).unwrap().applied, 1);
+        assert_eq!(b.apply_remote_ops(std::slice::from_ref(&op), &recorder).unwrap().applied, 1);
+        assert_eq!(a.apply_remote_ops(std::slice::from_ref(&op), &recorder).unwrap().applied, 0);
+        assert_eq!(recorder.0.borrow().len(), 2);
+
+        a.set_remote_cursor("ca").unwrap();
+        assert_eq!(b.get_remote_cursor().unwrap(), None);
+        assert_eq!(engine(&conn).get_remote_cursor().unwrap(), None);
+    }
+
+    #[test]
+    fn egress_transform_redacts_only_the_pushed_copy() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut e = engine(&conn);
+        e.set_egress_transform(Box::new(|ch: &mut Change| {
+            if let Some(row) = ch.new_row.as_mut().and_then(|r| r.as_object_mut()) {
+                row.insert("email".to_string(), json!("<redacted>"));
+            }
+        }));
+        let id = e.log_insert_fullrow("users", "1", &json!({"id": 1, "email": "a@b.c"}), "dev").unwrap();
+
+        let pending = e.get_pending_ops(10).unwrap();
+        assert_eq!(pending[0].new_field("email"), Some(&json!("<redacted>")));
+        let stored = e.get_change(id).unwrap().unwrap();
+        assert_eq!(stored.new_field("email"), Some(&json!("a@b.c")));
+    }
+
+    #[test]
+    fn log_if_changed_skips_identical_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+
+        let first = e.log_if_changed("trips", "1", &json!({"id": 1, "n": 1}), "dev").unwrap();
+        assert!(first.is_some());
+        assert_eq!(e.get_change(first.unwrap()).unwrap().unwrap().op_type, OpType::Insert);
+        e.mark_ops_acked(&[first.unwrap()]).unwrap();
+
+        assert_eq!(e.log_if_changed("trips", "1", &json!({"n": 1.0, "id": 1}), "dev").unwrap(), None);
+        let changed = e.log_if_changed("trips", "1", &json!({"id": 1, "n": 2}), "dev").unwrap();
+        assert_eq!(e.get_change(changed.unwrap()).unwrap().unwrap().op_type, OpType::Update);
+        assert_eq!(e.count_pending().unwrap(), 1);
+    }
+
+    /// NDJSON feed of `total` remote INSERTs generated line by line as it is read.
+    struct NdjsonFeed {
+        total: usize,
+        produced: std::rc::Rc<std::cell::Cell<usize>>,
+        line: Vec<u8>,
+    }
+
+    impl std::io::Read for NdjsonFeed {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.line.is_empty() {
+                let i = self.produced.get();
+                if i == self.total {
+                    return Ok(0);
+                }
+                let op = remote(&format!("r{}", i), &i.to_string(), OpType::Insert, &format!("{}-0-server", 1000 + i));
+                self.line = serde_json::to_vec(&op).unwrap();
+                self.line.push(b'\n');
+                self.produced.set(i + 1);
+            }
+            let n = buf.len().min(self.line.len());
+            buf[..n].copy_from_slice(&self.line[..n]);
+            self.line.drain(..n);
+            Ok(n)
+        }
+    }
+
+    /// Applier checking that the stream never reads far ahead of the op being applied.
+    struct LookaheadCheck {
+        produced: std::rc::Rc<std::cell::Cell<usize>>,
+        applied: std::cell::Cell<usize>,
+        max_lookahead: std::cell::Cell<usize>,
+    }
+
+    impl ApplyDomainOp for LookaheadCheck {
+        fn apply(&self, _tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+            let idx: usize = op.row_id.parse().unwrap();
+            self.max_lookahead.set(self.max_lookahead.get().max(self.produced.get() - idx));
+            self.applied.set(self.applied.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_apply_handles_a_large_feed_with_bounded_buffering() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let produced = std::rc::Rc::new(std::cell::Cell::new(0));
+        let feed = NdjsonFeed { total: 10_000, produced: produced.clone(), line: Vec::new() };
+        let check = LookaheadCheck { produced, applied: Default::default(), max_lookahead: Default::default() };
+
+        let read = e.apply_remote_ops_stream(std::io::BufReader::new(feed), &check, 100).unwrap();
+
+        assert_eq!(read, 10_000);
+        assert_eq!(check.applied.get(), 10_000);
+        assert_eq!(e.applied_ops_since(0, 20_000).unwrap().len(), 10_000);
+        // One sub-batch plus whatever the BufReader holds; never the whole feed.
+        assert!(check.max_lookahead.get() <= 200, "read {} ops ahead", check.max_lookahead.get());
+    }
+
+    #[test]
+    fn op_type_serializes_as_sql_and_accepts_old_spellings() {
+        for (op, sql) in [(OpType::Insert, "INSERT"), (OpType::Update, "UPDATE"), (OpType::Delete, "DELETE")] {
+            assert_eq!(serde_json::to_value(op).unwrap(), json!(sql));
+            assert_eq!(serde_json::from_value::<OpType>(json!(sql)).unwrap(), op);
+            assert_eq!(op.as_str(), sql);
+        }
+        assert_eq!(serde_json::from_value::<OpType>(json!("Insert")).unwrap(), OpType::Insert);
+        assert_eq!(serde_json::from_value::<OpType>(json!("update")).unwrap(), OpType::Update);
+        assert_eq!(serde_json::from_value::<OpType>(json!("Delete")).unwrap(), OpType::Delete);
+        assert!(serde_json::from_value::<OpType>(json!("UPSERT")).is_err());
+
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.log_delete("trips", "1", "dev").unwrap();
+        let json = serde_json::to_value(e.get_pending_ops(1).unwrap()).unwrap();
+        assert_eq!(json[0]["op_type"], json!("DELETE"));
+    }
+
+    #[test]
+    fn alias_row_redirects_pending_and_future_changes() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let acked = e.log_insert_fullrow("trips", "tmp-1", &json!({"v": 0}), "dev").unwrap();
+        e.mark_ops_acked(&[acked]).unwrap();
+        e.log_update("trips", "tmp-1", None, Some(&json!({"v": 1})), None, "dev").unwrap();
+        e.log_update("trips", "other", None, Some(&json!({"v": 1})), None, "dev").unwrap();
+
+        assert_eq!(e.alias_row("trips", "tmp-1", "canon-1").unwrap(), 1);
+        e.log_update("trips", "tmp-1", None, Some(&json!({"v": 2})), None, "dev").unwrap();
+
+        let pushed: Vec<String> = e.get_pending_ops(10).unwrap().into_iter().map(|c| c.row_id).collect();
+        assert_eq!(pushed, ["canon-1", "other", "canon-1"]);
+        assert_eq!(e.get_change(acked).unwrap().unwrap().row_id, "tmp-1", "acked history is left alone");
+        assert!(e.alias_row("trips", "canon-1", "tmp-1").is_err());
+    }
+
+    #[test]
+    fn hlc_frontier_covers_local_and_applied_origins() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        local(&e, "1", OpType::Delete, "100-0-dev");
+        local(&e, "2", OpType::Delete, "100-9-dev");
+        local(&e, "3", OpType::Delete, "99-20-dev");
+        let ops = [
+            remote("r1", "1", OpType::Insert, "300-0-peer"),
+            remote("r2", "2", OpType::Insert, "2000-0-peer"),
+            remote("r3", "3", OpType::Insert, "1000-5-server"),
+        ];
+        e.apply_remote_ops(&ops, &Recorder::default()).unwrap();
+
+        let frontier = e.hlc_frontier().unwrap();
+
+        assert_eq!(frontier.len(), 3);
+        assert_eq!(frontier["dev"], "100-9-dev");
+        assert_eq!(frontier["peer"], "2000-0-peer");
+        assert_eq!(frontier["server"], "1000-5-server");
+
+        // Forgetting applied ops leaves the remote origins where they were.
+        e.prune_applied_ops(i64::MAX).unwrap();
+        assert_eq!(e.hlc_frontier().unwrap(), frontier);
+    }
+
+    #[test]
+    fn confirm_pushed_by_hlc_marks_only_matching_changes() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a = local(&e, "r1", OpType::Insert, "100-0-dev");
+        let b = local(&e, "r2", OpType::Insert, "101-0-dev");
+        let c = local(&e, "r3", OpType::Insert, "102-0-dev");
+        e.mark_ops_pushed(&[b]).unwrap();
+
+        // Same timestamp under another origin is a different key and must not match.
+        let n = e.confirm_pushed_by_hlc(&["100-0-dev", "101-0-dev", "102-0-other", "999-0-dev"]).unwrap();
+
+        assert_eq!(n, 2);
+        assert_eq!(status_of(&conn, a), "acked");
+        assert_eq!(status_of(&conn, b), "acked");
+        assert_eq!(status_of(&conn, c), "pending");
+    }
+
+    #[test]
+    fn oversized_op_is_rejected_before_insert() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_max_op_bytes(64);
+        let big = json!({"id": "r1", "notes": "x".repeat(100)});
+
+        let err = e.log_insert_fullrow("trips", "r1", &big, "dev").unwrap_err();
+
+        assert!(matches!(err, SyncError::State("op too large")));
+        assert_eq!(e.count_pending().unwrap(), 0);
+        e.log_insert_fullrow("trips", "r2", &json!({"id": "r2"}), "dev").unwrap();
+        assert_eq!(e.count_pending().unwrap(), 1);
+    }
+
+    #[test]
+    fn delete_policy_settles_delete_vs_write_both_orderings() {
+        use DeletePolicy::*;
+        use Winner::{Local, Remote};
+        // (policy, local op, local hlc, remote op, remote hlc, expected winner)
+        let cases = [
+            (LwwByHlc, OpType::Delete, "100-0-dev", OpType::Update, "110-0-server", Remote),
+            (LwwByHlc, OpType::Delete, "120-0-dev", OpType::Update, "110-0-server", Local),
+            (DeleteWins, OpType::Delete, "100-0-dev", OpType::Update, "110-0-server", Local),
+            (DeleteWins, OpType::Delete, "120-0-dev", OpType::Update, "110-0-server", Local),
+            (DeleteWins, OpType::Update, "100-0-dev", OpType::Delete, "110-0-server", Remote),
+            (DeleteWins, OpType::Update, "120-0-dev", OpType::Delete, "110-0-server", Remote),
+            (InsertWins, OpType::Delete, "100-0-dev", OpType::Insert, "110-0-server", Remote),
+            (InsertWins, OpType::Delete, "120-0-dev", OpType::Insert, "110-0-server", Remote),
+            (InsertWins, OpType::Update, "100-0-dev", OpType::Delete, "110-0-server", Local),
+            (InsertWins, OpType::Update, "120-0-dev", OpType::Delete, "110-0-server", Local),
+        ];
+        for (policy, local_op, local_hlc, remote_op, remote_hlc, expected) in cases {
+            let conn = Connection::open_in_memory().unwrap();
+            let e = engine(&conn).with_delete_policy("trips", policy);
+            local(&e, "r1", local_op, local_hlc);
+            let applier = Recorder::default();
+
+            let (_, conflicts) = e
+                .apply_remote_ops_collect_conflicts(&[remote("op-1", "r1", remote_op, remote_hlc)], &applier)
+                .unwrap();
+
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].winner, expected, "{:?} local {} vs remote {}", policy, local_hlc, remote_hlc);
+            assert_eq!(applier.0.borrow().len(), usize::from(expected == Remote));
+        }
+    }
+
+    #[test]
+    fn table_snapshot_replaces_rows_and_is_applied_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        conn.execute_batch(
+            "CREATE TABLE categories(id TEXT PRIMARY KEY, name TEXT);
+INSERT INTO categories VALUES('a', 'old a'), ('b', 'stale'), ('c', 'stale');",
+        )
+        .unwrap();
+        let rows = [json!({"id": "a", "name": "new a"}), json!({"id": "d", "name": "d"})];
+
+        assert_eq!(e.apply_table_snapshot("categories", "id", &rows, "500-0-server").unwrap(), (2, 2));
+        assert_eq!(e.apply_table_snapshot("categories", "id", &rows, "500-0-server").unwrap(), (0, 0));
+
+        let mut stmt = conn.prepare("SELECT id, name FROM categories ORDER BY id").unwrap();
+        let left: Vec<(String, String)> = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(left, [("a".to_string(), "new a".to_string()), ("d".to_string(), "d".to_string())]);
+    }
+
+    #[test]
+    fn cursor_hook_sees_each_committed_cursor() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut e = engine(&conn);
+        let seen = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let sink = seen.clone();
+        e.set_on_cursor_advance(Box::new(move |c| sink.borrow_mut().push(c.to_string())));
+
+        e.set_remote_cursor("c1").unwrap();
+        e.set_remote_cursor_for("trips", "t1").unwrap();
+        assert!(!e.compare_and_set_cursor(Some("stale"), "c9").unwrap());
+        assert!(e.compare_and_set_cursor(Some("c1"), "c2").unwrap());
+
+        assert_eq!(*seen.borrow(), ["c1", "t1", "c2"]);
+        assert_eq!(e.get_remote_cursor().unwrap().as_deref(), Some("c2"));
+    }
+
+    #[test]
+    fn change_accessors() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let update = e
+            .log_local_change(
+                "trips",
+                "r1",
+                OpType::Update,
+                Some(&json!(["name", 7, "km"])),
+                Some(&json!({"name": "new", "km": 12})),
+                Some(&json!({"name": "old"})),
+                "100-0-dev",
+                "dev",
+            )
+            .unwrap();
+        let delete = local(&e, "r1", OpType::Delete, "101-0-dev");
+
+        let c = e.get_change(update).unwrap().unwrap();
+        assert!(c.is_update() && !c.is_insert() && !c.is_delete());
+        assert_eq!(c.new_field("km"), Some(&json!(12)));
+        assert_eq!(c.new_field("missing"), None);
+        assert_eq!(c.old_field("name"), Some(&json!("old")));
+        assert_eq!(c.changed_columns(), ["name", "km"]);
+
+        let d = e.get_change(delete).unwrap().unwrap();
+        assert!(d.is_delete());
+        assert_eq!(d.new_field("id"), None);
+        assert_eq!(d.old_field("id"), None);
+        assert!(d.changed_columns().is_empty());
+    }
+
+    #[test]
+    fn init_schema_twice_is_a_no_op() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        assert!(e.is_initialized().unwrap());
+        conn.execute_batch(
+            "UPDATE sync_kv SET v='3' WHERE k='schema_version';
+INSERT OR IGNORE INTO sync_kv(k, v) VALUES('schema_version', '3');
+DROP INDEX idx_local_changes_hlc;",
+        )
+        .unwrap();
+
+        e.init_schema().unwrap();
+
+        assert_eq!(e.get_schema_version().unwrap(), 3);
+        // Nothing was re-run: the dropped index stays gone.
+        let indexes: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='index' AND name='idx_local_changes_hlc'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 0);
+    }
+
+    #[test]
+    fn ops_for_unknown_tables_are_recorded_but_not_applied() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut e = engine(&conn).with_known_tables(HashSet::from(["trips".to_string()]));
+        let skipped = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let sink = skipped.clone();
+        e.set_on_unknown_table(Box::new(move |op| sink.borrow_mut().push(op.table_name.clone())));
+        let mut newer = remote("op-2", "g1", OpType::Insert, "101-0-server");
+        newer.table_name = "gadgets".to_string();
+        let applier = Recorder::default();
+
+        e.apply_remote_ops(&[remote("op-1", "r1", OpType::Insert, "100-0-server"), newer], &applier)
+            .unwrap();
+
+        assert_eq!(*applier.0.borrow(), ["op-1"]);
+        assert_eq!(*skipped.borrow(), ["gadgets"]);
+        let recorded: i64 = conn
+            .query_row("SELECT COUNT(*) FROM applied_remote_ops WHERE remote_id='op-2'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(recorded, 1);
+    }
+
+    #[test]
+    fn row_checksum_tracks_row_content() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        conn.execute_batch(
+            "CREATE TABLE trips(id TEXT PRIMARY KEY, name TEXT, km REAL);
+CREATE TABLE mirror(km REAL, name TEXT, id TEXT PRIMARY KEY);
+INSERT INTO trips VALUES('r1', 'alps', 12.5);
+INSERT INTO mirror VALUES(12.5, 'alps', 'r1');",
+        )
+        .unwrap();
+
+        let local = e.row_checksum("trips", "id", "r1").unwrap();
+        assert!(local.is_some());
+        // Column order doesn't matter, only content.
+        assert_eq!(e.row_checksum("mirror", "id", "r1").unwrap(), local);
+        assert_eq!(e.row_checksum("trips", "id", "missing").unwrap(), None);
+
+        conn.execute("UPDATE mirror SET km=13.0", []).unwrap();
+        assert_ne!(e.row_checksum("mirror", "id", "r1").unwrap(), local);
+    }
+
+    #[test]
+    fn mark_status_moves_pending_straight_to_acked() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ids = [
+            local(&e, "r1", OpType::Insert, "100-0-dev"),
+            local(&e, "r2", OpType::Insert, "101-0-dev"),
+        ];
+        let untouched = local(&e, "r3", OpType::Insert, "102-0-dev");
+
+        assert_eq!(e.mark_status(&ids, "acked").unwrap(), 2);
+        assert!(ids.iter().all(|&id| status_of(&conn, id) == "acked"));
+        assert_eq!(status_of(&conn, untouched), "pending");
+
+        let err = e.mark_status(&[untouched], "done").unwrap_err();
+        assert!(matches!(err, SyncError::State("invalid sync_status")));
+        assert_eq!(status_of(&conn, untouched), "pending");
+    }
+
+    #[test]
+    fn invalidated_ops_apply_again_when_redelivered() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ops = [
+            remote("op-1", "r1", OpType::Insert, "100-0-server"),
+            remote("op-2", "r2", OpType::Insert, "101-0-server"),
+        ];
+        let applier = Recorder::default();
+        e.apply_remote_ops(&ops, &applier).unwrap();
+        e.apply_remote_ops(&ops, &applier).unwrap();
+        assert_eq!(applier.0.borrow().len(), 2);
+
+        assert_eq!(e.invalidate_applied(&["op-1", "unknown"]).unwrap(), 1);
+        e.apply_remote_ops(&ops, &applier).unwrap();
+        assert_eq!(*applier.0.borrow(), ["op-1", "op-2", "op-1"]);
+
+        assert_eq!(e.invalidate_applied_for_table("expenses").unwrap(), 0);
+        assert_eq!(e.invalidate_applied_for_table("trips").unwrap(), 2);
+        e.apply_remote_ops(&ops, &applier).unwrap();
+        assert_eq!(applier.0.borrow().len(), 5);
+    }
+
+    /// Applier failing on the op with remote id `.0` and recording every other one.
+    struct FailOn(&'static str, Recorder);
+
+    impl ApplyDomainOp for FailOn {
+        fn apply(&self, tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+            if op.remote_id == self.0 {
+                return Err(SyncError::Callback(7));
+            }
+            self.1.apply(tx, op)
+        }
+    }
+
+    #[test]
+    fn outcomes_line_up_with_input_positions() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn)
+            .with_known_tables(HashSet::from(["trips".to_string()]))
+            .with_max_apply_attempts(1);
+        local(&e, "lose", OpType::Update, "900-0-dev");
+        local(&e, "win", OpType::Update, "50-0-dev");
+        let deleted = local(&e, "gone", OpType::Delete, "60-0-dev");
+        e.mark_status(&[deleted], "acked").unwrap();
+        let mut unknown = remote("unknown", "g1", OpType::Insert, "100-0-server");
+        unknown.table_name = "gadgets".to_string();
+        let ops = [
+            remote("bad", "b1", OpType::Insert, "100-0-server"),
+            unknown,
+            remote("gone", "gone", OpType::Update, "100-0-server"),
+            remote("new", "n1", OpType::Insert, "100-0-server"),
+            remote("win", "win", OpType::Update, "100-0-server"),
+            remote("new", "n1", OpType::Insert, "100-0-server"),
+            remote("lose", "lose", OpType::Update, "100-0-server"),
+        ];
+        let applier = FailOn("bad", Recorder::default());
+
+        let outcomes = e.apply_remote_ops_outcomes(&ops, &applier).unwrap();
+
+        assert_eq!(
+            outcomes,
+            [
+                OpOutcome::Quarantined,
+                OpOutcome::UnknownTable,
+                OpOutcome::Tombstoned,
+                OpOutcome::Applied,
+                OpOutcome::Conflict(Winner::Remote),
+                OpOutcome::Duplicate,
+                OpOutcome::Conflict(Winner::Local),
+            ]
+        );
+        assert_eq!(*applier.1.0.borrow(), ["gone", "new", "win"]);
+    }
+
+    #[test]
+    fn rebuild_table_restores_rows_from_acked_history() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        conn.execute_batch("CREATE TABLE trips(id TEXT PRIMARY KEY, name TEXT, km INTEGER);").unwrap();
+        let ids = [
+            e.log_insert_fullrow("trips", "r1", &json!({"id": "r1", "name": "alps", "km": 10}), "dev").unwrap(),
+            e.log_update("trips", "r1", Some(&json!(["km"])), Some(&json!({"km": 12})), None, "dev").unwrap(),
+            e.log_insert_fullrow("trips", "r2", &json!({"id": "r2", "name": "gone", "km": 1}), "dev").unwrap(),
+            e.log_delete("trips", "r2", "dev").unwrap(),
+        ];
+        e.mark_ops_acked(&ids).unwrap();
+        // Never acked, so not part of the rebuilt state.
+        e.log_update("trips", "r1", Some(&json!(["name"])), Some(&json!({"name": "draft"})), None, "dev").unwrap();
+        conn.execute_batch("INSERT INTO trips VALUES('r1', 'corrupted', -1), ('r2', 'zombie', 0);").unwrap();
+
+        let rebuilt = e.with_tx(|tx| e.rebuild_table(tx, "trips", "id")).unwrap();
+
+        assert_eq!(rebuilt, 2);
+        let rows: Vec<(String, String, i64)> = conn
+            .prepare("SELECT id, name, km FROM trips")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, [("r1".to_string(), "alps".to_string(), 12)]);
+    }
+
+    #[test]
+    fn dedup_cache_never_skips_a_new_op() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_dedup_cache(2);
+        let ndjson = |ids: &[&str]| {
+            ids.iter()
+                .enumerate()
+                .map(|(i, id)| serde_json::to_string(&remote(id, id, OpType::Insert, &format!("{}-0-server", 100 + i))).unwrap())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let applier = Recorder::default();
+
+        e.apply_remote_ops_stream(ndjson(&["a", "b", "c"]).as_bytes(), &applier, 1).unwrap();
+        // "a" fell out of the cache and is caught by the database; "d" is new.
+        e.apply_remote_ops_stream(ndjson(&["a", "d", "c"]).as_bytes(), &applier, 1).unwrap();
+        assert_eq!(*applier.0.borrow(), ["a", "b", "c", "d"]);
+
+        // Pruning forgets the markers, and the cache with them.
+        e.prune_applied_ops(i64::MAX).unwrap();
+        e.apply_remote_ops_stream(ndjson(&["c"]).as_bytes(), &applier, 1).unwrap();
+        assert_eq!(*applier.0.borrow(), ["a", "b", "c", "d", "c"]);
+    }
+
+    #[test]
+    fn op_stream_read_errors_keep_their_cause() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+
+        let err = e.apply_remote_ops_stream(&b"\xff\xfe\n"[..], &Recorder::default(), 1).unwrap_err();
+
+        assert!(matches!(&err, SyncError::Io(io) if io.kind() == std::io::ErrorKind::InvalidData), "{err:?}");
+    }
+
+    #[test]
+    fn push_envelope_holds_pending_ops_and_own_frontier() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        assert!(e.build_push_envelope(10).is_err(), "no origin set yet");
+        e.set_own_origin("dev").unwrap();
+        let a = local(&e, "r1", OpType::Insert, "100-0-dev");
+        let acked = local(&e, "r2", OpType::Insert, "300-0-dev");
+        e.mark_ops_acked(&[acked]).unwrap();
+        let c = local(&e, "r3", OpType::Insert, "200-0-dev");
+        e.apply_remote_ops(&[remote("op-1", "r9", OpType::Insert, "900-0-server")], &Recorder::default()).unwrap();
+
+        let env = e.build_push_envelope(10).unwrap();
+
+        assert_eq!(env.origin, "dev");
+        assert_eq!(env.change_ids, [a, c]);
+        assert_eq!(env.ops.iter().map(|o| o.change_id).collect::<Vec<_>>(), [a, c]);
+        assert_eq!(env.client_max_hlc.as_deref(), Some("300-0-dev"));
+        let wire = serde_json::to_value(&env).unwrap();
+        assert!(wire.get("change_ids").is_none());
+        assert_eq!(wire["ops"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn contention_policy_lets_concurrent_writers_commit() {
+        let path = temp_db("contention");
+        engine(&Connection::open(&path).unwrap());
+        let policy = ContentionPolicy { busy_timeout_ms: 5_000, commit_retries: 3, retry_backoff_ms: 5 };
+        assert_eq!(policy.warning(), None);
+        assert!(ContentionPolicy::default().warning().is_some());
+        let spinning = ContentionPolicy { commit_retries: 3, ..Default::default() };
+        let conn = Connection::open_in_memory().unwrap();
+        let rejected = SyncEngine::new(&conn).unwrap().with_contention_policy(spinning);
+        assert!(matches!(rejected, Err(SyncError::Validation(_))));
+
+        // Hold the write lock so every writer starts out contended.
+        let holder = Connection::open(&path).unwrap();
+        holder.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let writers: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|origin| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let conn = Connection::open(&path).unwrap();
+                    let e = SyncEngine::new(&conn).unwrap().with_contention_policy(policy).unwrap();
+                    for i in 0..100 {
+                        e.log_insert_fullrow("trips", &format!("{}{}", origin, i), &json!({}), origin).unwrap();
+                    }
+                })
+            })
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        holder.execute_batch("COMMIT").unwrap();
+
+        for w in writers {
+            w.join().unwrap();
+        }
+        assert_eq!(engine(&holder).count_pending().unwrap(), 300);
+    }
+
+    #[test]
+    fn tracked_tables_lists_distinct_tables() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        e.log_insert_fullrow("trips", "1", &json!({}), "dev").unwrap();
+        e.log_insert_fullrow("trips", "2", &json!({}), "dev").unwrap();
+        let acked = e.log_insert_fullrow("expenses", "1", &json!({}), "dev").unwrap();
+        e.log_delete("categories", "1", "dev").unwrap();
+        e.mark_ops_acked(&[acked]).unwrap();
+
+        assert_eq!(e.tracked_tables(None).unwrap(), ["categories", "expenses", "trips"]);
+        assert_eq!(e.tracked_tables(Some("pending")).unwrap(), ["categories", "trips"]);
+        assert_eq!(e.tracked_tables(Some("acked")).unwrap(), ["expenses"]);
+        assert!(e.tracked_tables(Some("pushed")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn simulate_apply_reports_without_writing() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        conn.execute_batch("CREATE TABLE trips(id TEXT PRIMARY KEY);").unwrap();
+        local(&e, "2", OpType::Update, "900-0-dev");
+        let ops = [
+            remote("op-1", "1", OpType::Insert, "100-0-server"),
+            remote("op-2", "2", OpType::Update, "100-0-server"),
+        ];
+        let applier = crate::applier::AppendOnlyApplier::new("id");
+
+        let report = e.simulate_apply(&ops, &applier).unwrap();
+
+        assert_eq!(report.applied, 1);
+        assert_eq!(report.outcomes, [OpOutcome::Applied, OpOutcome::Conflict(Winner::Local)]);
+        assert_eq!(report.conflicts.len(), 1);
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM trips", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 0);
+        assert!(e.applied_ops_since(0, 10).unwrap().is_empty());
+        assert_eq!(e.plan_apply(&ops).unwrap().apply_count(), 1, "nothing marked applied");
+    }
+
+    #[test]
+    fn storage_report_on_file_and_memory_databases() {
+        let path = temp_db("storage");
+        let conn = Connection::open(&path).unwrap();
+        let e = engine(&conn);
+        for i in 0..50 {
+            e.log_insert_fullrow("trips", &i.to_string(), &json!({"pad": "x".repeat(200)}), "dev").unwrap();
+        }
+
+        let report = e.storage_report().unwrap();
+
+        assert!(report.page_count > 0 && report.page_size > 0);
+        assert!(report.wal_frames.unwrap() > 0);
+        assert!(report.db_bytes.is_some());
+        assert!(report.wal_bytes.unwrap() > 0);
+
+        let mem = Connection::open_in_memory().unwrap();
+        let report = engine(&mem).storage_report().unwrap();
+        assert_eq!((report.wal_frames, report.db_bytes, report.wal_bytes), (None, None, None));
+    }
+
+    #[test]
+    fn cursor_cas_with_stale_expectation_leaves_cursor_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        assert!(!e.compare_and_set_cursor(Some("c1"), "c2").unwrap(), "no cursor yet");
+        assert!(e.compare_and_set_cursor(None, "c1").unwrap());
+        // A second task that also read "no cursor" loses the race.
+        assert!(!e.compare_and_set_cursor(None, "other").unwrap());
+
+        assert!(e.compare_and_set_cursor(Some("c1"), "c2").unwrap());
+        assert!(!e.compare_and_set_cursor(Some("c1"), "c3").unwrap());
+
+        assert_eq!(e.get_remote_cursor().unwrap().as_deref(), Some("c2"));
+    }
+
+    #[test]
+    fn next_hlc_detects_clock_regression_and_caps_the_counter() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let future = Utc::now().timestamp_millis() + 10 * DEFAULT_MAX_DRIFT_MS;
+        put_hlc_state(&conn, "dev", future, 0).unwrap();
+
+        assert!(matches!(e.next_hlc("dev"), Err(SyncError::State("clock regression"))));
+        assert_eq!(e.next_hlc_with_drift("dev", i64::MAX).unwrap(), format!("{}-1-dev", future));
+
+        put_hlc_state(&conn, "dev", future, HLC_COUNTER_MAX).unwrap();
+        assert_eq!(e.next_hlc_with_drift("dev", i64::MAX).unwrap(), format!("{}-0-dev", future + 1));
+    }
+
+    #[test]
+    fn apply_stats_count_duplicates_as_skipped() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ops = [
+            remote("op-1", "r1", OpType::Insert, "100-0-server"),
+            remote("op-2", "r2", OpType::Insert, "101-0-server"),
+            remote("op-1", "r1", OpType::Insert, "100-0-server"),
+        ];
+
+        let stats = e.apply_remote_ops(&ops, &Recorder::default()).unwrap();
+
+        assert_eq!((stats.applied, stats.skipped), (2, 1));
+        let again = e.apply_remote_ops(&ops[..2], &Recorder::default()).unwrap();
+        assert_eq!((again.applied, again.skipped), (0, 2));
+    }
+
+    #[test]
+    fn preview_conflicts_names_the_winner_without_writing() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        local(&e, "r1", OpType::Update, "200-0-dev");
+        let pushed = local(&e, "r2", OpType::Update, "100-0-dev");
+        e.mark_ops_pushed(&[pushed]).unwrap();
+        let acked = local(&e, "r3", OpType::Update, "110-0-dev");
+        e.mark_ops_acked(&[acked]).unwrap();
+        let ops = [
+            remote("op-1", "r1", OpType::Update, "150-0-server"),
+            remote("op-2", "r2", OpType::Update, "150-0-server"),
+            remote("op-3", "r3", OpType::Update, "150-0-server"),
+        ];
+
+        let report = e.preview_conflicts(&ops).unwrap();
+
+        assert_eq!(report.len(), 2, "acked changes don't contend");
+        assert_eq!((report[0].row_id.as_str(), report[0].local_hlc.as_str()), ("r1", "200-0-dev"));
+        assert_eq!(report[0].winner, Winner::Local);
+        assert_eq!((report[1].row_id.as_str(), report[1].remote_hlc.as_str()), ("r2", "150-0-server"));
+        assert_eq!(report[1].winner, Winner::Remote);
+        assert!(e.applied_ops_since(0, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn each_conflict_policy_decides_what_reaches_the_applier() {
+        let cases: [(ConflictPolicy, &[&str]); 3] = [
+            (ConflictPolicy::RemoteWins, &["op-new", "op-old", "op-free"]),
+            (ConflictPolicy::LocalWins, &["op-free"]),
+            (ConflictPolicy::LastWriterWins, &["op-old", "op-free"]),
+        ];
+        for (policy, expected) in cases {
+            let conn = Connection::open_in_memory().unwrap();
+            let e = engine(&conn);
+            local(&e, "newer", OpType::Update, "200-0-dev");
+            local(&e, "older", OpType::Update, "50-0-dev");
+            let ops = [
+                remote("op-new", "newer", OpType::Update, "100-0-server"),
+                remote("op-old", "older", OpType::Update, "100-0-server"),
+                remote("op-free", "free", OpType::Update, "100-0-server"),
+            ];
+            let applier = Recorder::default();
+
+            let (applied, conflicts) = e.apply_remote_ops_with_policy(&ops, &applier, policy).unwrap();
+
+            assert_eq!(*applier.0.borrow(), expected, "{:?}", policy);
+            assert_eq!(applied, expected.len());
+            assert_eq!(conflicts.len(), 2, "{:?}", policy);
+            assert_eq!(e.applied_ops_since(0, 10).unwrap().len(), 3, "every op advances dedup");
+        }
+    }
+
+    #[test]
+    fn pending_ops_for_table_filters_and_limits() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let trips: Vec<i64> = (0..3)
+            .map(|i| e.log_insert_fullrow("trips", &i.to_string(), &json!({}), "dev").unwrap())
+            .collect();
+        e.log_insert_fullrow("photos", "p1", &json!({}), "dev").unwrap();
+        e.mark_ops_pushed(&trips[..1]).unwrap();
+
+        let ids = |ops: Vec<Change>| ops.into_iter().map(|c| c.change_id).collect::<Vec<_>>();
+        assert_eq!(ids(e.get_pending_ops_for_table("trips", 10).unwrap()), trips[1..]);
+        assert_eq!(ids(e.get_pending_ops_for_table("trips", 1).unwrap()), trips[1..2]);
+        assert_eq!(e.get_pending_ops_for_table("photos", 10).unwrap()[0].row_id, "p1");
+        assert!(e.get_pending_ops_for_table("expenses", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn marking_thousands_of_ids_in_one_statement() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let inputs: Vec<LocalChangeInput> = (0..5000)
+            .map(|i| LocalChangeInput::builder("trips", &i.to_string(), OpType::Insert).new_row(json!({})).build().unwrap())
+            .collect();
+        let ids = e.log_batch(&inputs, "dev").unwrap();
+        let count = |status: &str| e.count_by_status().unwrap().get(status).copied().unwrap_or(0);
+
+        // Around SQLite's classic 999 bound-variable limit.
+        for n in [998, 999, 1000] {
+            assert_eq!(e.mark_status(&ids[..n], "pushed").unwrap(), n);
+            assert_eq!(count("pushed"), n as i64);
+            e.mark_status(&ids[..n], "pending").unwrap();
+        }
+
+        e.mark_ops_acked(&ids).unwrap();
+        assert_eq!(count("acked"), 5000);
+        assert_eq!(count("pending"), 0);
+    }
+
+    #[test]
+    fn compact_acked_keeps_the_newest_per_row() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        // Logged out of HLC order: what survives is decided by HLC, not change_id.
+        let acked = [
+            local(&e, "r1", OpType::Update, "120-0-dev"),
+            local(&e, "r1", OpType::Update, "100-0-dev"),
+            local(&e, "r1", OpType::Update, "110-0-dev"),
+            local(&e, "r2", OpType::Insert, "105-0-dev"),
+        ];
+        e.mark_ops_acked(&acked).unwrap();
+        local(&e, "r1", OpType::Update, "130-0-dev");
+
+        assert_eq!(e.compact_acked(1).unwrap(), 2);
+
+        let hlcs = |row_id| history(&conn, row_id).into_iter().map(|c| c.hlc).collect::<Vec<_>>();
+        assert_eq!(hlcs("r1"), ["120-0-dev", "130-0-dev"]);
+        assert_eq!(hlcs("r2"), ["105-0-dev"]);
+        assert_eq!(e.compact_acked(0).unwrap(), 2);
+        assert_eq!(e.count_pending().unwrap(), 1);
+    }
+
+    #[test]
+    fn prune_applied_ops_removes_only_old_markers() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ops = [
+            remote("old", "r1", OpType::Insert, "100-0-server"),
+            remote("new", "r2", OpType::Insert, "101-0-server"),
+        ];
+        e.apply_remote_ops(&ops, &Recorder::default()).unwrap();
+        conn.execute("UPDATE applied_remote_ops SET applied_ms=1000 WHERE remote_id='old'", []).unwrap();
+        conn.execute("UPDATE applied_remote_ops SET applied_ms=5000 WHERE remote_id='new'", []).unwrap();
+
+        assert_eq!(e.prune_applied_ops(5000).unwrap(), 1);
+
+        let applier = Recorder::default();
+        e.apply_remote_ops(&ops, &applier).unwrap();
+        assert_eq!(*applier.0.borrow(), ["old"], "the pruned op applies again if redelivered");
+    }
+
+    #[test]
+    fn concurrent_connections_never_issue_the_same_hlc() {
+        let path = temp_db("hlc_threads");
+        engine(&Connection::open(&path).unwrap());
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let conn = Connection::open(&path).unwrap();
+                    let e = SyncEngine::new(&conn)
+                        .unwrap()
+                        .with_contention_policy(ContentionPolicy { busy_timeout_ms: 5_000, ..Default::default() })
+                        .unwrap();
+                    (0..1000).map(|_| e.next_hlc("dev").unwrap()).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut all: Vec<String> = workers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 2000);
+    }
+
+    fn create_tags(tx: &Transaction<'_>) -> Result<(), SyncError> {
+        tx.execute_batch("CREATE TABLE tags(id TEXT PRIMARY KEY, name TEXT)")?;
+        Ok(())
+    }
+
+    fn index_tags(tx: &Transaction<'_>) -> Result<(), SyncError> {
+        tx.execute_batch("CREATE INDEX idx_tags_name ON tags(name)")?;
+        Ok(())
+    }
+
+    fn failing_step(_tx: &Transaction<'_>) -> Result<(), SyncError> {
+        Err(SyncError::State("step failed"))
+    }
+
+    fn schema_has(conn: &Connection, kind: &str, name: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type=?1 AND name=?2",
+            params![kind, name],
+            |r| r.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    #[test]
+    fn migrations_run_in_order_and_roll_back_together() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_migrations(vec![
+            Migration { version: 3, up: index_tags, down: None },
+            Migration { version: 2, up: create_tags, down: None },
+            Migration { version: 4, up: failing_step, down: None },
+        ]);
+
+        e.run_migrations(3).unwrap();
+
+        assert!(schema_has(&conn, "table", "tags"));
+        assert!(schema_has(&conn, "index", "idx_tags_name"));
+        assert_eq!(e.get_schema_version().unwrap(), 3);
+
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_migrations(vec![
+            Migration { version: 2, up: create_tags, down: None },
+            Migration { version: 3, up: failing_step, down: None },
+        ]);
+        assert!(e.run_migrations(3).is_err());
+        assert!(!schema_has(&conn, "table", "tags"), "step 2 rolled back with step 3");
+        assert_eq!(e.get_schema_version().unwrap(), 1);
+    }
+
+    fn drop_tags(tx: &Transaction<'_>) -> Result<(), SyncError> {
+        tx.execute_batch("DROP TABLE tags")?;
+        Ok(())
+    }
+
+    fn unindex_tags(tx: &Transaction<'_>) -> Result<(), SyncError> {
+        tx.execute_batch("DROP INDEX idx_tags_name")?;
+        Ok(())
+    }
+
+    #[test]
+    fn migrations_downgrade_in_reverse_order() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_migrations(vec![
+            Migration { version: 2, up: create_tags, down: Some(drop_tags) },
+            Migration { version: 3, up: index_tags, down: Some(unindex_tags) },
+        ]);
+        e.run_migrations(3).unwrap();
+
+        e.run_migrations(1).unwrap();
+
+        assert!(!schema_has(&conn, "index", "idx_tags_name"));
+        assert!(!schema_has(&conn, "table", "tags"));
+        assert_eq!(e.get_schema_version().unwrap(), 1);
+    }
+
+    #[test]
+    fn downgrading_past_an_irreversible_step_fails_whole() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_migrations(vec![
+            Migration { version: 2, up: create_tags, down: None },
+            Migration { version: 3, up: index_tags, down: Some(unindex_tags) },
+        ]);
+        e.run_migrations(3).unwrap();
+
+        let err = e.run_migrations(1).unwrap_err();
+
+        assert!(matches!(err, SyncError::State("irreversible migration")));
+        assert!(schema_has(&conn, "index", "idx_tags_name"), "step 3's down rolled back too");
+        assert_eq!(e.get_schema_version().unwrap(), 3);
+    }
+
+    #[test]
+    fn duplicate_hlc_and_origin_is_a_conflict() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        local(&e, "r1", OpType::Insert, "100-0-dev");
+
+        let err = e
+            .log_local_change("trips", "r2", OpType::Insert, None, Some(&json!({})), None, "100-0-dev", "dev")
+            .unwrap_err();
+
+        assert!(matches!(err, SyncError::Conflict(_)), "{:?}", err);
+        assert!(!err.is_transient());
+        // Same token under another origin is a different key.
+        local(&e, "r2", OpType::Insert, "100-0-tablet");
+    }
+
+    #[test]
+    fn count_by_status_and_get_change() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a = local(&e, "r1", OpType::Insert, "100-0-dev");
+        let b = local(&e, "r1", OpType::Update, "101-0-dev");
+        local(&e, "r2", OpType::Insert, "102-0-dev");
+        local(&e, "r2", OpType::Delete, "103-0-dev");
+        e.mark_ops_pushed(&[a, b]).unwrap();
+        e.mark_ops_acked(&[a]).unwrap();
+
+        let counts = e.count_by_status().unwrap();
+
+        assert_eq!(counts.get("pending"), Some(&2));
+        assert_eq!(counts.get("pushed"), Some(&1));
+        assert_eq!(counts.get("acked"), Some(&1));
+        let change = e.get_change(a).unwrap().unwrap();
+        assert_eq!((change.row_id.as_str(), change.sync_status.as_str()), ("r1", "acked"));
+        assert!(e.get_change(999).unwrap().is_none());
+    }
+
+    #[test]
+    fn origins_keep_independent_monotonic_clocks() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let ahead = Utc::now().timestamp_millis() + 60_000;
+        put_hlc_state(&conn, "a", ahead, 0).unwrap();
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        for _ in 0..200 {
+            a.push(Hlc::parse(&e.next_hlc("a").unwrap()));
+            b.push(Hlc::parse(&e.next_hlc("b").unwrap()));
+        }
+
+        assert!(a.windows(2).all(|w| w[0] < w[1]));
+        assert!(b.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(a[0].millis, i128::from(ahead));
+        assert!(b[199].millis < i128::from(ahead), "a's clock doesn't drag b's");
+    }
+
+    #[test]
+    fn legacy_hlc_state_moves_to_the_primary_origin() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let legacy = Utc::now().timestamp_millis() + 60_000;
+        conn.execute_batch(&format!(
+            "INSERT INTO sync_kv(k, v) VALUES('hlc_last_ms', '{}'), ('hlc_last_ctr', '7')",
+            legacy
+        ))
+        .unwrap();
+
+        assert!(e.migrate_hlc_state("dev").unwrap());
+        assert!(!e.migrate_hlc_state("dev").unwrap());
+
+        assert_eq!(e.next_hlc("dev").unwrap(), format!("{}-8-dev", legacy));
+    }
+
+    #[test]
+    fn local_clock_moves_past_applied_remote_ops() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let now = Utc::now().timestamp_millis();
+        let ahead = format!("{}-5-server", now + 3_600_000);
+        let absurd = format!("{}-0-server", now + 10 * DEFAULT_MAX_DRIFT_MS);
+
+        e.apply_remote_ops(&[remote("op-1", "r1", OpType::Insert, &ahead)], &Recorder::default()).unwrap();
+        let next = e.next_hlc("dev").unwrap();
+        assert!(Hlc::parse(&next) > Hlc::parse(&ahead), "{} <= {}", next, ahead);
+
+        // Beyond the drift bound the token is not observed, so the clock isn't poisoned.
+        e.observe_hlc(&absurd).unwrap();
+        assert!(Hlc::parse(&e.next_hlc("dev").unwrap()) < Hlc::parse(&absurd));
+    }
+
+    #[test]
+    fn pending_ops_iter_streams_a_large_backlog() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let inputs: Vec<LocalChangeInput> = (0..10_000)
+            .map(|i| LocalChangeInput::builder("trips", &i.to_string(), OpType::Insert).new_row(json!({})).build().unwrap())
+            .collect();
+        let ids = e.log_batch(&inputs, "dev").unwrap();
+
+        let mut seen = Vec::new();
+        for (n, change) in e.pending_ops_iter(256).enumerate() {
+            seen.push(change.unwrap().change_id);
+            if n == 0 {
+                // Acked ahead of the cursor: skipped. Logged meanwhile: yielded at the end.
+                e.mark_ops_acked(&ids[5000..5100]).unwrap();
+                e.log_insert_fullrow("trips", "late", &json!({}), "dev").unwrap();
+            }
+        }
+
+        assert_eq!(seen.len(), 10_000 - 100 + 1);
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert!(!seen.contains(&ids[5000]));
+        assert_eq!(e.get_change(*seen.last().unwrap()).unwrap().unwrap().row_id, "late");
+    }
+
+    #[test]
+    fn content_hash_ignores_key_order() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let a: serde_json::Value = serde_json::from_str(r#"{"name":"x","meta":{"b":1,"a":[1,2]},"km":1.5}"#).unwrap();
+        let b: serde_json::Value = serde_json::from_str(r#"{"km":1.5,"meta":{"a":[1,2],"b":1},"name":"x"}"#).unwrap();
+        e.log_insert_fullrow("trips", "1", &a, "dev").unwrap();
+        e.log_insert_fullrow("trips", "2", &b, "dev").unwrap();
+        e.log_insert_fullrow("trips", "3", &json!({"name": "y"}), "dev").unwrap();
+        e.log_delete("trips", "4", "dev").unwrap();
+
+        let hashes: Vec<Option<String>> = e.get_pending_ops(10).unwrap().into_iter().map(|c| c.content_hash).collect();
+
+        assert_eq!(canonical_json(&a), canonical_json(&b));
+        // Hex SHA-256 of `{"name":"y"}`, as a server would compute it.
+        assert_eq!(hashes[2].as_deref(), Some("bcd9b688fc62b8bffa1c4fa86ab183fb1e3b0a42793d631470462e6e54f5ddd7"));
+        assert_eq!(hashes[0], hashes[1]);
+        assert_ne!(hashes[0], hashes[2]);
+        assert_eq!(hashes[3], None);
+    }
+
+    #[test]
+    fn composite_row_ids_round_trip() {
+        let cases: [&[&str]; 6] = [
+            &["a-b", "c"],
+            &["a|b", "c"],
+            &["back\\slash", "trailing\\"],
+            &["", "x", ""],
+            &["", ""],
+            &["plain"],
+        ];
+        for parts in cases {
+            let encoded = encode_row_id(parts);
+            assert_eq!(decode_row_id(&encoded), parts, "{:?} via {:?}", parts, encoded);
+        }
+        assert_eq!(encode_row_id(&["plain"]), "plain");
+        assert_ne!(encode_row_id(&["a|b"]), encode_row_id(&["a", "b"]));
+    }
+
+    #[test]
+    fn diff_columns_and_log_update_diff() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let old = json!({"id": 1, "name": "a", "km": 1, "note": "gone"});
+        let new = json!({"id": 1, "name": "b", "km": 1.0, "tag": "new"});
+
+        assert_eq!(diff_columns(&old, &new), ["name", "note", "tag"]);
+
+        let id = e.log_update_diff("trips", "1", &old, &new, "dev").unwrap().unwrap();
+        let change = e.get_change(id).unwrap().unwrap();
+        assert_eq!(change.changed_columns(), ["name", "note", "tag"]);
+        assert_eq!(change.new_row, Some(new.clone()));
+        assert_eq!(e.log_update_diff("trips", "1", &new, &new, "dev").unwrap(), None);
+    }
+
+    #[test]
+    fn partial_remote_update_touches_only_listed_columns() {
+        let current = json!({"id": "r1", "name": "a", "km": 10, "note": "keep"});
+        let mut op = remote("op-1", "r1", OpType::Update, "100-0-server");
+        op.columns = Some(json!(["km"]));
+        op.new_row = Some(json!({"id": "r1", "name": "stale", "km": 12, "note": "stale"}));
+
+        let merged = Recorder::default().merged_row(&op, &current);
+        assert_eq!(merged, json!({"id": "r1", "name": "a", "km": 12, "note": "keep"}));
+
+        op.columns = None;
+        assert_eq!(merge_remote_update(&op, &current), op.new_row.clone().unwrap());
+        op.op_type = OpType::Delete;
+        op.new_row = None;
+        assert_eq!(merge_remote_update(&op, &current), current);
+    }
+
+    #[test]
+    fn checked_cursor_rejects_regressions() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_cursor_order(CursorOrder::Numeric);
+        e.set_remote_cursor_checked("9").unwrap();
+        e.set_remote_cursor_checked("10").unwrap(); // lexically smaller, numerically larger
+        e.set_remote_cursor_checked("10").unwrap();
+
+        assert!(matches!(e.set_remote_cursor_checked("8"), Err(SyncError::State("cursor regressed"))));
+        assert!(e.set_remote_cursor_checked("abc").is_err());
+        assert_eq!(e.get_remote_cursor().unwrap().as_deref(), Some("10"));
+
+        let lexical = SyncEngine::new(&conn).unwrap().with_feed("lex").with_cursor_order(CursorOrder::Lexical);
+        lexical.set_remote_cursor_checked("b").unwrap();
+        assert!(lexical.set_remote_cursor_checked("a").is_err());
+        lexical.set_remote_cursor_checked("c").unwrap();
+    }
+
+    #[test]
+    fn log_batch_issues_increasing_hlcs_in_one_transaction() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let inputs: Vec<LocalChangeInput> = (0..1000)
+            .map(|i| LocalChangeInput::builder("trips", &i.to_string(), OpType::Insert).new_row(json!({})).build().unwrap())
+            .collect();
+
+        let ids = e.log_batch(&inputs, "dev").unwrap();
+
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        let hlcs: Vec<Hlc> = ids.iter().map(|&id| Hlc::parse(&e.get_change(id).unwrap().unwrap().hlc)).collect();
+        assert!(hlcs.windows(2).all(|w| w[0] < w[1]));
+        let rows: Vec<String> = ids.iter().map(|&id| e.get_change(id).unwrap().unwrap().row_id).collect();
+        assert_eq!(rows, (0..1000).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(e.count_pending().unwrap(), 1000);
+    }
+
+    #[test]
+    fn repeated_applies_share_no_statement_state() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let first = [remote("a", "1", OpType::Insert, "100-0-srv"), remote("b", "2", OpType::Insert, "101-0-srv")];
+        let second = [
+            remote("b", "2", OpType::Insert, "101-0-srv"),
+            remote("c", "3", OpType::Insert, "102-0-srv"),
+            remote("a", "1", OpType::Insert, "100-0-srv"),
+        ];
+
+        let rec = Recorder::default();
+        assert_eq!(e.apply_remote_ops(&first, &rec).unwrap(), ApplyStats { applied: 2, skipped: 0 });
+        // A failed batch in between must not leave a half-used cached statement behind.
+        assert!(e.apply_remote_ops(&second, &FailOn("c", Recorder::default())).is_err());
+        assert_eq!(e.apply_remote_ops(&second, &rec).unwrap(), ApplyStats { applied: 1, skipped: 2 });
+        assert_eq!(e.apply_remote_ops(&second, &rec).unwrap(), ApplyStats { applied: 0, skipped: 3 });
+
+        assert_eq!(*rec.0.borrow(), ["a", "b", "c"]);
+        let applied: i64 = conn.query_row("SELECT COUNT(*) FROM applied_remote_ops", [], |r| r.get(0)).unwrap();
+        assert_eq!(applied, 3);
+    }
+
+    #[test]
+    fn watermarks_track_the_newest_hlc_per_origin() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let rec = Recorder::default();
+        e.apply_remote_ops(
+            &[
+                remote("a1", "1", OpType::Insert, "100-0-alice"),
+                remote("b1", "2", OpType::Insert, "300-0-bob"),
+                remote("a2", "1", OpType::Update, "200-1-alice"),
+                remote("c1", "3", OpType::Insert, "150-0-carol"),
+            ],
+            &rec,
+        )
+        .unwrap();
+        // Late arrivals older than the watermark leave it where it is.
+        e.apply_remote_ops(
+            &[remote("b0", "2", OpType::Update, "250-0-bob"), remote("c2", "3", OpType::Update, "150-2-carol")],
+            &rec,
+        )
+        .unwrap();
+
+        let expected: HashMap<String, String> = [("alice", "200-1-alice"), ("bob", "300-0-bob"), ("carol", "150-2-carol")]
+            .into_iter()
+            .map(|(o, h)| (o.to_string(), h.to_string()))
+            .collect();
+        assert_eq!(e.get_watermarks().unwrap(), expected);
+    }
+
+    /// Applier keeping `trips` rows in memory, keyed by row_id.
+    #[derive(Default)]
+    struct Rows(RefCell<std::collections::BTreeMap<String, serde_json::Value>>);
+
+    impl ApplyDomainOp for Rows {
+        fn apply(&self, _tx: &Transaction<'_>, op: &RemoteOp) -> Result<(), SyncError> {
+            let mut rows = self.0.borrow_mut();
+            if op.op_type == OpType::Delete {
+                rows.remove(&op.row_id);
+            } else {
+                let current = rows.get(&op.row_id).cloned().unwrap_or_else(|| json!({}));
+                rows.insert(op.row_id.clone(), merge_remote_update(op, &current));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_local_rebuilds_the_final_state() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let first = e.log_insert_fullrow("trips", "1", &json!({"id": "1", "name": "a", "km": 1}), "dev").unwrap();
+        e.log_insert_fullrow("trips", "2", &json!({"id": "2", "name": "b"}), "dev").unwrap();
+        let km = json!({"id": "1", "name": "a", "km": 5});
+        e.log_update("trips", "1", Some(&json!(["km"])), Some(&km), None, "dev").unwrap();
+        e.log_delete("trips", "2", "dev").unwrap();
+        e.mark_ops_acked(&[first]).unwrap();
+
+        let rows = Rows::default();
+        assert_eq!(e.replay_local(&rows, 0).unwrap(), 4);
+        assert_eq!(rows.0.borrow().len(), 1);
+        assert_eq!(rows.0.borrow()["1"], km);
+
+        let tail = Rows::default();
+        assert_eq!(e.replay_local(&tail, first + 1).unwrap(), 3);
+        assert_eq!(*tail.0.borrow(), [("1".to_string(), json!({"km": 5}))].into(), "only the update of row 1");
+        let applied: i64 = conn.query_row("SELECT COUNT(*) FROM applied_remote_ops", [], |r| r.get(0)).unwrap();
+        assert_eq!(applied, 0);
+        assert_eq!(e.count_pending().unwrap(), 3);
+    }
+
+    #[test]
+    fn delete_snapshot_round_trips_as_a_tombstone() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let old = json!({"id": "1", "name": "Lisbon"});
+        let with = e.log_delete_with_snapshot("trips", "1", &old, "dev").unwrap();
+        let without = e.log_delete("trips", "2", "dev").unwrap();
+        e.log_delete("expenses", "9", "dev").unwrap();
+        e.log_insert_fullrow("trips", "3", &json!({}), "dev").unwrap();
+
+        let pending = e.get_pending_ops(10).unwrap();
+        assert_eq!(pending[0].change_id, with);
+        assert_eq!(pending[0].old_row, Some(old.clone()));
+        assert_eq!(pending[0].new_row, None);
+
+        e.mark_ops_acked(&[with]).unwrap();
+        let tombs = e.tombstones(Some("trips"), 10).unwrap();
+        assert_eq!(tombs.iter().map(|c| c.change_id).collect::<Vec<_>>(), [without, with]);
+        assert_eq!(tombs[0].old_row, None);
+        assert_eq!(tombs[1].old_row, Some(old));
+        assert_eq!(e.tombstones(None, 10).unwrap().len(), 3);
+        assert_eq!(e.tombstones(None, 1).unwrap()[0].table_name, "expenses");
+    }
+
+    #[test]
+    fn local_delete_races_remote_update_both_ways() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut e = engine(&conn);
+        let notified = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let sink = notified.clone();
+        e.set_on_conflict(Box::new(move |op, c| sink.borrow_mut().push((op.remote_id.clone(), c.winner))));
+        local(&e, "1", OpType::Delete, "200-0-dev");
+        local(&e, "2", OpType::Delete, "200-1-dev");
+        local(&e, "3", OpType::Update, "200-2-dev");
+        local(&e, "4", OpType::Update, "200-3-dev");
+
+        let rec = Recorder::default();
+        let (applied, conflicts) = e
+            .apply_remote_ops_collect_conflicts(
+                &[
+                    remote("older-update", "1", OpType::Update, "100-0-srv"),
+                    remote("newer-update", "2", OpType::Update, "300-0-srv"),
+                    remote("older-delete", "3", OpType::Delete, "100-0-srv"),
+                    remote("newer-delete", "4", OpType::Delete, "300-0-srv"),
+                ],
+                &rec,
+            )
+            .unwrap();
+
+        assert_eq!(applied, 2);
+        assert_eq!(*rec.0.borrow(), ["newer-update", "newer-delete"]);
+        let summary: Vec<_> = conflicts.iter().map(|c| (c.row_id.as_str(), c.local_op, c.winner)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("1", OpType::Delete, Winner::Local),
+                ("2", OpType::Delete, Winner::Remote),
+                ("3", OpType::Update, Winner::Local),
+                ("4", OpType::Update, Winner::Remote),
+            ]
+        );
+        assert_eq!(notified.borrow().len(), 4);
+        assert_eq!(notified.borrow()[0], ("older-update".to_string(), Winner::Local));
+    }
+
+    #[test]
+    fn grouped_changes_are_pushed_and_acked_together() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        // A database from before groups existed gets the column back on init.
+        conn.execute_batch(
+            "DROP INDEX idx_local_changes_group; ALTER TABLE local_changes DROP COLUMN group_id;
+DELETE FROM sync_kv WHERE k='engine_schema';",
+        )
+        .unwrap();
+        e.init_schema().unwrap();
+
+        let before = e.log_insert_fullrow("trips", "0", &json!({}), "dev").unwrap();
+        let inputs = [
+            LocalChangeInput::builder("trips", "1", OpType::Insert).new_row(json!({})).build().unwrap(),
+            LocalChangeInput::builder("expenses", "e1", OpType::Insert).new_row(json!({"trip": "1"})).build().unwrap(),
+        ];
+        let group = e.log_batch(&inputs, "dev").unwrap();
+        let after = e.log_insert_fullrow("trips", "2", &json!({}), "dev").unwrap();
+
+        let group_of = |id| e.get_change(id).unwrap().unwrap().group_id;
+        assert_eq!(group_of(group[0]), Some(group[0]));
+        assert_eq!(group_of(group[1]), Some(group[0]));
+        assert_eq!(group_of(before), None);
+
+        // A limit reaching into the group still returns all of it, as one entry.
+        let ids = |groups: Vec<Vec<Change>>| -> Vec<Vec<i64>> {
+            groups.iter().map(|g| g.iter().map(|c| c.change_id).collect()).collect()
+        };
+        assert_eq!(ids(e.get_pending_groups(2).unwrap()), [vec![before], group.clone()]);
+        assert_eq!(ids(e.get_pending_groups(10).unwrap()), [vec![before], group.clone(), vec![after]]);
+
+        assert_eq!(e.mark_group_acked(group[0]).unwrap(), 2);
+        assert_eq!(ids(e.get_pending_groups(10).unwrap()), [vec![before], vec![after]]);
+    }
+
+    #[test]
+    fn poison_op_is_quarantined_after_the_limit() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_max_apply_attempts(3);
+        let ops = [
+            remote("ok-1", "1", OpType::Insert, "100-0-srv"),
+            remote("poison", "2", OpType::Insert, "101-0-srv"),
+            remote("ok-2", "3", OpType::Insert, "102-0-srv"),
+        ];
+        let applier = FailOn("poison", Recorder::default());
+
+        for _ in 0..2 {
+            assert!(matches!(e.apply_remote_ops(&ops, &applier), Err(SyncError::Callback(7))));
+            assert!(e.get_quarantined_ops().unwrap().is_empty());
+        }
+        assert_eq!(e.apply_remote_ops(&ops, &applier).unwrap(), ApplyStats { applied: 2, skipped: 1 });
+
+        let quarantined = e.get_quarantined_ops().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].op.remote_id, "poison");
+        assert_eq!(quarantined[0].attempts, 3);
+        // Committed with the batch: nothing is handed to the applier again.
+        assert_eq!(e.apply_remote_ops(&ops, &applier).unwrap(), ApplyStats { applied: 0, skipped: 3 });
+        assert_eq!(applier.1.0.borrow().iter().filter(|id| *id == "ok-2").count(), 1);
+    }
+
+    #[test]
+    fn applier_error_survives_a_failed_attempt_count() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_max_apply_attempts(3);
+        conn.execute_batch(
+            "CREATE TRIGGER no_failures BEFORE INSERT ON failed_remote_ops BEGIN SELECT RAISE(ABORT, 'full'); END;",
+        )
+        .unwrap();
+        let ops = [remote("poison", "2", OpType::Insert, "101-0-srv")];
+
+        let err = e.apply_remote_ops(&ops, &FailOn("poison", Recorder::default())).unwrap_err();
+
+        assert!(matches!(err, SyncError::Callback(7)), "{err:?}");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_pending_ops_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        for i in 0..50 {
+            e.log_insert_fullrow("trips", &i.to_string(), &json!({"id": i, "name": "same name every time"}), "dev")
+                .unwrap();
+        }
+        e.log_delete_with_snapshot("trips", "0", &json!({"id": 0}), "dev").unwrap();
+
+        let compressed = e.get_pending_ops_zstd(100).unwrap();
+        let decoded: Vec<Change> = serde_json::from_slice(&zstd::decode_all(&compressed[..]).unwrap()).unwrap();
+
+        let plain = e.get_pending_ops(100).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&plain).unwrap());
+        assert!(compressed.len() < serde_json::to_vec(&plain).unwrap().len());
+    }
+
+    #[test]
+    fn bulk_precheck_skips_interleaved_seen_ids() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn);
+        let op = |i: usize| remote(&format!("op-{i}"), &i.to_string(), OpType::Insert, &format!("{}-0-srv", 100 + i));
+        let seen: Vec<RemoteOp> = (0..2000).filter(|i| i % 2 == 0).map(op).collect();
+        e.apply_remote_ops(&seen, &Recorder::default()).unwrap();
+
+        // Past SQLite's 999-variable limit, with a repeat of an unseen id inside the batch.
+        let mut batch: Vec<RemoteOp> = (0..2000).map(op).collect();
+        batch.push(op(1));
+        let rec = Recorder::default();
+        assert_eq!(e.apply_remote_ops(&batch, &rec).unwrap(), ApplyStats { applied: 1000, skipped: 1001 });
+
+        let expected: Vec<String> = (0..2000).filter(|i| i % 2 == 1).map(|i| format!("op-{i}")).collect();
+        assert_eq!(*rec.0.borrow(), expected);
+        // Applied ids are per feed.
+        let other = SyncEngine::new(&conn).unwrap().with_feed("other");
+        assert_eq!(other.apply_remote_ops(&seen[..3], &Recorder::default()).unwrap().applied, 3);
+    }
+
+    #[test]
+    fn lesser_origin_tie_break_reaches_every_row_lookup() {
+        let conn = Connection::open_in_memory().unwrap();
+        let e = engine(&conn).with_tie_break(TieBreak::LesserOrigin).with_supersede_local(true);
+        let zed = local(&e, "1", OpType::Update, "100-0-zed");
+        local(&e, "2", OpType::Update, "100-1-zed");
+        local(&e, "2", OpType::Delete, "100-1-amy");
+        let rec = Recorder::default();
+
+        let outcomes = e
+            .apply_remote_ops_outcomes(
+                &[remote("alpha", "1", OpType::Update, "100-0-alpha"), remote("late", "2", OpType::Update, "90-0-srv")],
+                &rec,
+            )
+            .unwrap();
+
+        // alpha wins the tie on row 1, so the local change it beat is retired, not pushed.
+        assert_eq!(outcomes, [OpOutcome::Conflict(Winner::Remote), OpOutcome::Conflict(Winner::Local)]);
+        assert_eq!(status_of(&conn, zed), "acked");
+        // On row 2 the newest local change is amy's DELETE.
+        let conflicts = e.preview_conflicts(&[remote("x", "2", OpType::Update, "90-0-srv")]).unwrap();
+        assert_eq!(conflicts[0].local_op, OpType::Delete);
+        assert_eq!(conflicts[0].local_hlc, "100-1-amy");
+    }
 }
@@ -1,5 +1,9 @@
-use crate::oplog::{ApplyDomainOp, Change, RemoteOp, SyncEngine, SyncError};
+#[cfg(feature = "tokio")]
+use std::sync::{Arc, Mutex};
+use crate::oplog::{ApplyDomainOp, Change, ConflictPolicy, RemoteOp, SyncEngine, SyncError};
 
+/// Changes pushed per cycle by `SyncClient::flush`.
+const FLUSH_BATCH: i64 = 500;
 
 pub struct SyncClient<'c, A> {
     engine: SyncEngine<'c>,
@@ -13,6 +17,12 @@ impl<'c, A: ApplyDomainOp> SyncClient<'c, A> {
         engine.init_schema()?;
         Ok(Self { engine, applier})
     }
+
+    /// Wrap an engine configured by the caller (feed, egress transform, ...).
+    pub fn from_engine(engine: SyncEngine<'c>, applier: A) -> Result<Self, SyncError> {
+        engine.init_schema()?;
+        Ok(Self { engine, applier })
+    }
 }
 
 impl<'c, A: ApplyDomainOp> SyncClient<'c, A> {
@@ -21,24 +31,534 @@ impl<'c, A: ApplyDomainOp> SyncClient<'c, A> {
     where
         P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>, // Push local ops -> return acked ids
         G: Fn(Option<String>) -> Result<(Vec<RemoteOp>, Option<String>), SyncError>, // pull: cursor -> (ops, new_cursor)
+    {
+        self.sync_cycle_at(None, push, pull, limit)
+    }
+
+    /// Like `sync_cycle`, pulling `table_name` from its own cursor (`get_remote_cursor_for`)
+    /// and advancing only that cursor. The push still covers every pending change.
+    pub fn sync_cycle_for<P, G>(&self, table_name: &str, push: P, pull: G, limit: i64) -> Result<(), SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+        G: Fn(Option<String>) -> Result<(Vec<RemoteOp>, Option<String>), SyncError>,
+    {
+        self.sync_cycle_at(Some(table_name), push, pull, limit)
+    }
+
+    fn sync_cycle_at<P, G>(&self, table_name: Option<&str>, push: P, pull: G, limit: i64) -> Result<(), SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+        G: Fn(Option<String>) -> Result<(Vec<RemoteOp>, Option<String>), SyncError>,
     {
         // 1. Push local changes to the server
-        let locals = self.engine.get_pending_ops(limit)?;
-        if !locals.is_empty() {
-            let acked_ids = push(&locals)?;
-            self.engine.mark_ops_acked(&acked_ids)?;
-        }
+        let (pushed_ids, acked_ids) = self.push_in_flight(push, limit)?;
 
         // 2. Pull remote changes from the server
-        let cursor = self.engine.get_remote_cursor()?;
-        let (remote_ops, new_cursor) = pull(cursor)?;
-        if !remote_ops.is_empty() {
-            self.engine.apply_remote_ops(&remote_ops, &self.applier)?;
-        }
-        if let Some(c) = new_cursor {
-            self.engine.set_remote_cursor(&c)?;
+        let pulled = (|| {
+            let cursor = match table_name {
+                Some(t) => self.engine.get_remote_cursor_for(t)?,
+                None => self.engine.get_remote_cursor()?,
+            };
+            let (remote_ops, new_cursor) = pull(cursor)?;
+            if !remote_ops.is_empty() {
+                self.engine.apply_remote_ops(&remote_ops, &self.applier)?;
+            }
+            Ok(new_cursor)
+        })();
+
+        // 3. Record acks, re-queue the unacked rest and move the cursor together; keep the
+        //    acks even if the pull failed
+        let commit = |new_cursor: Option<&str>| match table_name {
+            Some(t) => self.engine.commit_cycle_for(t, &pushed_ids, &acked_ids, new_cursor),
+            None => self.engine.commit_cycle(&pushed_ids, &acked_ids, new_cursor),
+        };
+        match pulled {
+            Ok(new_cursor) => commit(new_cursor.as_deref())?,
+            Err(e) => {
+                commit(None)?;
+                return Err(e);
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl<'c, A: ApplyDomainOp> SyncClient<'c, A> {
+    /// Push one batch of up to `limit` pending changes without pulling, marked 'pushed' while
+    /// in flight as in `sync_cycle`. Returns the number of changes the server acked.
+    pub fn push_only<P>(&self, push: P, limit: i64) -> Result<usize, SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+    {
+        let (pushed_ids, acked_ids) = self.push_in_flight(push, limit)?;
+        if pushed_ids.is_empty() {
+            return Ok(0);
+        }
+        self.engine.commit_cycle(&pushed_ids, &acked_ids, None)?;
+        Ok(acked_ids.len())
+    }
+
+    /// Hand up to `limit` pending changes to `push`, marked 'pushed' while in flight (see
+    /// `begin_push`). Returns the ids sent and the ids acked, for `commit_cycle` to settle;
+    /// if `push` fails the whole batch goes back to 'pending'.
+    fn push_in_flight<P>(&self, push: P, limit: i64) -> Result<(Vec<i64>, Vec<i64>), SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+    {
+        let (locals, pushed_ids) = begin_push(&self.engine, limit)?;
+        if locals.is_empty() {
+            return Ok((pushed_ids, Vec::new()));
+        }
+        match push(&locals) {
+            Ok(acked_ids) => Ok((pushed_ids, acked_ids)),
+            Err(e) => {
+                self.engine.mark_status(&pushed_ids, "pending")?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Run `push_only` cycles until nothing is pending or `max_cycles` is reached.
+    /// Returns the total number of acked changes. Fails if a cycle acks nothing while
+    /// changes are still pending (e.g. the server keeps rejecting one op), instead of spinning.
+    pub fn flush<P>(&self, push: P, max_cycles: usize) -> Result<usize, SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+    {
+        self.push_batches(&push, FLUSH_BATCH, max_cycles)
+    }
+
+    /// Drain the backlog in both directions: push batches of up to `batch` changes until
+    /// nothing is pending, then pull pages until the cursor stops advancing, each side capped
+    /// at `max_batches` rounds. Returns `(acked, pulled)`. Fails like `flush` when a push
+    /// round acks nothing; a server repeating the same cursor ends the pull instead of looping.
+    pub fn sync_cycle_all<P, G>(
+        &self,
+        push: P,
+        pull: G,
+        batch: i64,
+        max_batches: usize,
+    ) -> Result<(usize, usize), SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+        G: Fn(Option<String>) -> Result<(Vec<RemoteOp>, Option<String>), SyncError>,
+    {
+        if batch <= 0 {
+            return Err(SyncError::State("batch must be > 0"));
+        }
+        let acked = self.push_batches(&push, batch, max_batches)?;
+        let pulled = self.pull_only(pull, max_batches)?;
+        Ok((acked, pulled))
+    }
+
+    fn push_batches<P>(&self, push: &P, batch: i64, max_batches: usize) -> Result<usize, SyncError>
+    where
+        P: Fn(&[Change]) -> Result<Vec<i64>, SyncError>,
+    {
+        let mut total = 0;
+        for _ in 0..max_batches {
+            if self.engine.count_pending()? == 0 {
+                break;
+            }
+            let acked = self.push_only(push, batch)?;
+            if acked == 0 {
+                return Err(SyncError::State("flush made no progress"));
+            }
+            total += acked;
+        }
+        Ok(total)
+    }
+
+    /// Pull and apply up to `max_pages` pages of remote ops without pushing.
+    /// Stops early on an empty page or when the cursor stops advancing. An op contending with
+    /// a pending local change is settled by last-writer-wins (`apply_remote_ops_with_policy`),
+    /// as the FFI apply entry points do. Returns the number of remote ops pulled.
+    pub fn pull_only<G>(&self, pull: G, max_pages: usize) -> Result<usize, SyncError>
+    where
+        G: Fn(Option<String>) -> Result<(Vec<RemoteOp>, Option<String>), SyncError>,
+    {
+        let mut pulled = 0;
+        for _ in 0..max_pages {
+            let cursor = self.engine.get_remote_cursor()?;
+            let (remote_ops, new_cursor) = pull(cursor.clone())?;
+            if !remote_ops.is_empty() {
+                self.engine.apply_remote_ops_with_policy(&remote_ops, &self.applier, ConflictPolicy::LastWriterWins)?;
+            }
+            pulled += remote_ops.len();
+
+            let advanced = new_cursor.is_some() && new_cursor != cursor;
+            self.engine.commit_cycle(&[], &[], new_cursor.as_deref())?;
+            if remote_ops.is_empty() || !advanced {
+                break;
+            }
+        }
+        Ok(pulled)
+    }
+}
+
+/// Take up to `limit` pending changes for a push and mark them 'pushed', so a crash before
+/// the ack leaves them for `resweep_stuck_pushed`. Returns the changes and their ids.
+fn begin_push(engine: &SyncEngine<'_>, limit: i64) -> Result<(Vec<Change>, Vec<i64>), SyncError> {
+    let locals = engine.get_pending_ops(limit)?;
+    let ids: Vec<i64> = locals.iter().map(|c| c.change_id).collect();
+    if !ids.is_empty() {
+        engine.mark_ops_pushed(&ids)?;
+    }
+    Ok((locals, ids))
+}
+
+/// Run `f` with a default engine on `conn` on tokio's blocking pool.
+#[cfg(feature = "tokio")]
+async fn on_blocking_pool<R, F>(conn: &Arc<Mutex<rusqlite::Connection>>, f: F) -> Result<R, SyncError>
+where
+    R: Send + 'static,
+    F: FnOnce(&SyncEngine<'_>) -> Result<R, SyncError> + Send + 'static,
+{
+    let conn = Arc::clone(conn);
+    tokio::task::spawn_blocking(move || {
+        let conn = conn.lock().map_err(|_| SyncError::State("connection mutex poisoned"))?;
+        f(&SyncEngine::new(&conn)?)
+    })
+    .await
+    .map_err(|_| SyncError::State("blocking task failed"))?
+}
+
+/// `SyncClient::sync_cycle` for async push/pull, e.g. over a tokio HTTP client. The oplog work
+/// runs on `spawn_blocking` with a default `SyncEngine` over `conn` (call `init_schema` first);
+/// the network futures run on the caller's task. Statuses move exactly as in `sync_cycle`.
+#[cfg(feature = "tokio")]
+pub async fn sync_cycle_async<A, P, PF, G, GF>(
+    conn: Arc<Mutex<rusqlite::Connection>>,
+    applier: Arc<A>,
+    push: P,
+    pull: G,
+    limit: i64,
+) -> Result<(), SyncError>
+where
+    A: ApplyDomainOp + Send + Sync + 'static,
+    P: FnOnce(Vec<Change>) -> PF,
+    PF: Future<Output = Result<Vec<i64>, SyncError>>,
+    G: FnOnce(Option<String>) -> GF,
+    GF: Future<Output = Result<(Vec<RemoteOp>, Option<String>), SyncError>>,
+{
+    // 1. Push, with the batch marked 'pushed' while in flight
+    let (locals, pushed_ids) = on_blocking_pool(&conn, move |e| begin_push(e, limit)).await?;
+    let acked_ids = if locals.is_empty() {
+        Vec::new()
+    } else {
+        match push(locals).await {
+            Ok(acked) => acked,
+            Err(e) => {
+                on_blocking_pool(&conn, move |en| en.mark_status(&pushed_ids, "pending")).await?;
+                return Err(e);
+            }
+        }
+    };
+
+    // 2. Pull
+    let cursor = on_blocking_pool(&conn, |e| e.get_remote_cursor()).await?;
+    let pulled = pull(cursor).await;
+
+    // 3. Apply, then record acks and the new cursor together; keep the acks if the pull failed
+    match pulled {
+        Ok((remote_ops, new_cursor)) => {
+            on_blocking_pool(&conn, move |e| {
+                if !remote_ops.is_empty() {
+                    e.apply_remote_ops(&remote_ops, applier.as_ref())?;
+                }
+                e.commit_cycle(&pushed_ids, &acked_ids, new_cursor.as_deref())
+            })
+            .await
+        }
+        Err(err) => {
+            on_blocking_pool(&conn, move |e| e.commit_cycle(&pushed_ids, &acked_ids, None)).await?;
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oplog::OpType;
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    struct Noop;
+
+    impl ApplyDomainOp for Noop {
+        fn apply(&self, _tx: &rusqlite::Transaction<'_>, _op: &RemoteOp) -> Result<(), SyncError> {
+            Ok(())
+        }
+    }
+
+    fn remote(remote_id: &str, row_id: &str, hlc: &str) -> RemoteOp {
+        RemoteOp {
+            remote_id: remote_id.to_string(),
+            table_name: "trips".to_string(),
+            row_id: row_id.to_string(),
+            op_type: OpType::Insert,
+            columns: None,
+            new_row: Some(json!({"id": row_id})),
+            old_row: None,
+            hlc: hlc.to_string(),
+            origin: "server".to_string(),
+        }
+    }
+
+    fn log_rows(client: &SyncClient<'_, Noop>, n: usize) -> Vec<i64> {
+        (0..n)
+            .map(|i| {
+                client
+                    .engine
+                    .log_insert_fullrow("trips", &format!("l{}", i), &json!({"id": i}), "dev")
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn ack_all(changes: &[Change]) -> Result<Vec<i64>, SyncError> {
+        Ok(changes.iter().map(|c| c.change_id).collect())
+    }
+
+    #[test]
+    fn push_only_leaves_the_cursor_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        client.engine.set_remote_cursor("c1").unwrap();
+        log_rows(&client, 3);
+
+        assert_eq!(client.push_only(ack_all, 10).unwrap(), 3);
+
+        assert_eq!(client.engine.count_pending().unwrap(), 0);
+        assert_eq!(client.engine.get_remote_cursor().unwrap().as_deref(), Some("c1"));
+    }
+
+    #[test]
+    fn pull_only_leaves_local_statuses_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        let ids = log_rows(&client, 3);
+        client.engine.mark_ops_pushed(&ids[..1]).unwrap();
+        let before = client.engine.count_by_status().unwrap();
+
+        let pulled = client
+            .pull_only(
+                |cursor| match cursor.as_deref() {
+                    None => Ok((vec![remote("r1", "a", "1000-0-server")], Some("c1".to_string()))),
+                    _ => Ok((Vec::new(), cursor)),
+                },
+                5,
+            )
+            .unwrap();
+
+        assert_eq!(pulled, 1);
+        assert_eq!(client.engine.get_remote_cursor().unwrap().as_deref(), Some("c1"));
+        assert_eq!(client.engine.count_by_status().unwrap(), before);
+    }
+
+    #[test]
+    fn flush_drains_a_multi_page_backlog() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        log_rows(&client, 1200);
+        let pushes = std::cell::Cell::new(0);
+
+        let acked = client
+            .flush(
+                |changes| {
+                    pushes.set(pushes.get() + 1);
+                    ack_all(changes)
+                },
+                10,
+            )
+            .unwrap();
+
+        assert_eq!(acked, 1200);
+        assert_eq!(pushes.get(), 3);
+        assert_eq!(client.engine.count_pending().unwrap(), 0);
+    }
+
+    #[test]
+    fn flush_gives_up_on_a_poison_op() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        log_rows(&client, 3);
+        client.engine.log_insert_fullrow("trips", "poison", &json!({}), "dev").unwrap();
+        let pushes = std::cell::Cell::new(0);
+
+        let res = client.flush(
+            |changes| {
+                pushes.set(pushes.get() + 1);
+                Ok(changes.iter().filter(|c| c.row_id != "poison").map(|c| c.change_id).collect())
+            },
+            100,
+        );
+
+        assert!(matches!(res, Err(SyncError::State("flush made no progress"))));
+        assert_eq!(pushes.get(), 2);
+        assert_eq!(client.engine.count_pending().unwrap(), 1);
+    }
+
+    #[test]
+    fn sync_cycle_all_drains_both_directions_in_batches() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        log_rows(&client, 250);
+        let batches = std::cell::RefCell::new(Vec::new());
+        let pulls = std::cell::Cell::new(0);
+
+        let (acked, pulled) = client
+            .sync_cycle_all(
+                |changes| {
+                    batches.borrow_mut().push(changes.len());
+                    ack_all(changes)
+                },
+                |cursor| {
+                    pulls.set(pulls.get() + 1);
+                    match cursor.as_deref() {
+                        None => Ok((
+                            vec![remote("r1", "a", "1000-0-server"), remote("r2", "b", "1001-0-server")],
+                            Some("c1".to_string()),
+                        )),
+                        // The server keeps answering with the same cursor: stop instead of looping.
+                        _ => Ok((vec![remote("r3", "c", "1002-0-server")], Some("c1".to_string()))),
+                    }
+                },
+                100,
+                10,
+            )
+            .unwrap();
+
+        assert_eq!(*batches.borrow(), [100, 100, 50]);
+        assert_eq!((acked, pulled), (250, 3));
+        assert_eq!(pulls.get(), 2);
+        assert_eq!(client.engine.count_pending().unwrap(), 0);
+        assert_eq!(client.engine.get_remote_cursor().unwrap().as_deref(), Some("c1"));
+    }
+
+    #[test]
+    fn sync_cycle_moves_changes_through_pushed_to_acked() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        let ids = log_rows(&client, 3);
+        let status = |id: i64| client.engine.get_change(id).unwrap().unwrap().sync_status;
+        let no_pull = |cursor: Option<String>| Ok((Vec::new(), cursor));
+
+        client
+            .sync_cycle(
+                |changes| {
+                    assert!(changes.iter().all(|c| status(c.change_id) == "pushed"), "in flight");
+                    Ok(vec![ids[0], ids[1]])
+                },
+                no_pull,
+                10,
+            )
+            .unwrap();
+        assert_eq!([status(ids[0]), status(ids[1]), status(ids[2])], ["acked", "acked", "pending"]);
+
+        let failed = client.sync_cycle(|_| Err(SyncError::State("offline")), no_pull, 10);
+        assert!(failed.is_err());
+        assert_eq!(status(ids[2]), "pending", "a failed push is re-queued");
+
+        // A crash between push and ack leaves the change 'pushed' until it is swept.
+        client.engine.mark_ops_pushed(&ids[2..]).unwrap();
+        assert_eq!(client.engine.resweep_stuck_pushed(0).unwrap(), 0, "not stuck long enough");
+        assert_eq!(client.engine.resweep_stuck_pushed(i64::MAX).unwrap(), 1);
+        assert_eq!(status(ids[2]), "pending");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn sync_cycle_async_pushes_and_pulls() {
+        let conn = Connection::open_in_memory().unwrap();
+        let engine = SyncEngine::new(&conn).unwrap();
+        engine.init_schema().unwrap();
+        let ids: Vec<i64> = (0..2)
+            .map(|i| engine.log_insert_fullrow("trips", &i.to_string(), &json!({}), "dev").unwrap())
+            .collect();
+        drop(engine);
+        let conn = Arc::new(Mutex::new(conn));
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        rt.block_on(sync_cycle_async(
+            Arc::clone(&conn),
+            Arc::new(Noop),
+            |changes: Vec<Change>| async move { Ok(changes.iter().map(|c| c.change_id).collect()) },
+            |_cursor| async { Ok((vec![remote("r1", "a", "1000-0-server")], Some("c1".to_string()))) },
+            10,
+        ))
+        .unwrap();
+
+        let conn = conn.lock().unwrap();
+        let engine = SyncEngine::new(&conn).unwrap();
+        assert!(ids.iter().all(|&id| engine.get_change(id).unwrap().unwrap().sync_status == "acked"));
+        assert_eq!(engine.get_remote_cursor().unwrap().as_deref(), Some("c1"));
+        assert_eq!(engine.applied_ops_since(0, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn table_cursors_are_independent() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        client.engine.set_remote_cursor("g1").unwrap();
+        client.engine.set_remote_cursor_for("expenses", "e1").unwrap();
+        let seen = std::cell::RefCell::new(Vec::new());
+
+        for _ in 0..2 {
+            client
+                .sync_cycle_for(
+                    "trips",
+                    ack_all,
+                    |cursor| {
+                        seen.borrow_mut().push(cursor.clone());
+                        Ok((Vec::new(), Some("t1".to_string())))
+                    },
+                    10,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(*seen.borrow(), [None, Some("t1".to_string())]);
+        assert_eq!(client.engine.get_remote_cursor_for("trips").unwrap().as_deref(), Some("t1"));
+        assert_eq!(client.engine.get_remote_cursor_for("expenses").unwrap().as_deref(), Some("e1"));
+        assert_eq!(client.engine.get_remote_cursor().unwrap().as_deref(), Some("g1"));
+    }
+
+    #[test]
+    fn push_only_and_flush_mark_changes_in_flight() {
+        let conn = Connection::open_in_memory().unwrap();
+        let client = SyncClient::new(&conn, Noop).unwrap();
+        let ids = log_rows(&client, 3);
+        let status = |id: i64| client.engine.get_change(id).unwrap().unwrap().sync_status;
+
+        let failed = client.push_only(|_| Err(SyncError::State("offline")), 10);
+        assert!(failed.is_err());
+        assert!(ids.iter().all(|&id| status(id) == "pending"), "a failed push is re-queued");
+
+        let acked = client
+            .push_only(
+                |changes| {
+                    assert!(changes.iter().all(|c| status(c.change_id) == "pushed"), "in flight");
+                    Ok(vec![ids[0]])
+                },
+                10,
+            )
+            .unwrap();
+        assert_eq!(acked, 1);
+        assert_eq!([status(ids[0]), status(ids[1]), status(ids[2])], ["acked", "pending", "pending"]);
+
+        let flushed = client
+            .flush(
+                |changes| {
+                    assert!(changes.iter().all(|c| status(c.change_id) == "pushed"), "in flight");
+                    ack_all(changes)
+                },
+                5,
+            )
+            .unwrap();
+        assert_eq!(flushed, 2);
+        assert_eq!(client.engine.count_pending().unwrap(), 0);
+    }
+}